@@ -6,31 +6,74 @@ Usage: python client.py <youtube_url> [output_name] [mode]
 
 import os
 import sys
+import time
 import requests
 
 SERVER_URL = os.environ.get("YT_DLP_SERVER_URL", "http://localhost:8080")
+MAX_ATTEMPTS = 5
+
+
+def _read_etag(path: str):
+    try:
+        with open(path) as f:
+            return f.read().strip() or None
+    except OSError:
+        return None
 
 
 def download_video(url: str, output_name: str, mode: str) -> str:
     tmp = output_name + ".mp4.part"
+    etag_file = tmp + ".etag"
     final = output_name + ".mp4"
 
     s = requests.Session()
     # Don't inherit http_proxy/https_proxy from environment; clients should talk to the service directly.
     s.trust_env = False
-    with s.post(
-        f"{SERVER_URL}/download",
-        json={"url": url, "mode": mode},
-        stream=True,
-        timeout=None,
-    ) as r:
-        r.raise_for_status()
-        with open(tmp, "wb") as f:
-            for chunk in r.iter_content(chunk_size=64 * 1024):
-                if chunk:
-                    f.write(chunk)
+
+    for attempt in range(1, MAX_ATTEMPTS + 1):
+        # Resume a previous .part file if we know which server-side file it belongs to. The service keeps finished
+        # downloads for `download_grace_secs`; If-Range makes it send the whole file again if ours is stale.
+        headers = {}
+        etag = _read_etag(etag_file)
+        offset = os.path.getsize(tmp) if etag and os.path.exists(tmp) else 0
+        if offset > 0:
+            headers["Range"] = f"bytes={offset}-"
+            headers["If-Range"] = etag
+            print(f"Resuming at byte {offset}")
+
+        try:
+            with s.post(
+                f"{SERVER_URL}/download",
+                json={"url": url, "mode": mode},
+                headers=headers,
+                stream=True,
+                timeout=None,
+            ) as r:
+                if r.status_code == 416:
+                    # Our .part is already complete (or bogus); start over.
+                    os.remove(tmp)
+                    continue
+                r.raise_for_status()
+
+                if r.headers.get("ETag"):
+                    with open(etag_file, "w") as f:
+                        f.write(r.headers["ETag"])
+
+                append = r.status_code == 206
+                with open(tmp, "ab" if append else "wb") as f:
+                    for chunk in r.iter_content(chunk_size=64 * 1024):
+                        if chunk:
+                            f.write(chunk)
+            break
+        except (requests.ConnectionError, requests.exceptions.ChunkedEncodingError) as e:
+            if attempt == MAX_ATTEMPTS:
+                raise
+            print(f"Connection lost ({e}); retrying...")
+            time.sleep(2)
 
     os.replace(tmp, final)
+    if os.path.exists(etag_file):
+        os.remove(etag_file)
     return final
 
 
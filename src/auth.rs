@@ -0,0 +1,201 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+
+use crate::config::{ApiKey, AppConfig};
+use crate::state::AppState;
+
+/// Name of the API key a request was authenticated with, stored in the request extensions.
+#[derive(Debug, Clone)]
+pub struct Authenticated(pub String);
+
+/// The API key sent with a request: `X-API-Key: <key>` or `Authorization: Bearer <key>`.
+pub fn presented_key(req: &HttpRequest) -> Option<&str> {
+    if let Some(v) = req.headers().get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(v.trim());
+    }
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim())
+}
+
+// Compares every byte regardless of where the first difference is, so response timing doesn't reveal how much
+// of a guessed key was right.
+pub fn same_key(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The configured key matching what `req` presented, if any.
+pub fn find_key<'a>(cfg: &'a AppConfig, req: &HttpRequest) -> Option<&'a ApiKey> {
+    let presented = presented_key(req)?;
+    cfg.api_keys.iter().find(|k| same_key(&k.key, presented))
+}
+
+/// The path the router matches resources against: `req.path()` with percent-escapes decoded (except `%2F`, `%25`
+/// and `%2B`), so `/%61dmin/cookies` is `/admin/cookies`. Decisions about which endpoint a request is for must use
+/// this, never the raw path.
+pub fn routed_path(req: &HttpRequest) -> &str {
+    req.match_info().as_str()
+}
+
+/// Scope needed for a path; None for endpoints that stay open (the health check).
+fn required_scope(path: &str) -> Option<&'static str> {
+    match path {
+        "/" => None,
+        "/info" | "/subtitles" | "/playlist" => Some("info"),
+        "/thumbnail" => Some("thumbnail"),
+        "/stats" | "/health/cookies" => Some("admin"),
+        p if p.starts_with("/admin/") => Some("admin"),
+        // /download, /audio, /batch, /jobs/..., /download/progress/... and anything unknown.
+        _ => Some("download"),
+    }
+}
+
+/// Logger field (`%{key}xi`): the name of the API key used, or "-".
+pub fn log_key_name(req: &ServiceRequest) -> String {
+    req.app_data::<web::Data<AppState>>()
+        .and_then(|s| find_key(&s.config, req.request()).map(|k| k.name.clone()))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Middleware requiring a configured API key with the scope of the endpoint. When no keys are configured every
+/// endpoint is open except /admin/*, which is refused.
+pub async fn require_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let state = match req.app_data::<web::Data<AppState>>() {
+        Some(s) => s.clone(),
+        None => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+    let path = routed_path(req.request()).to_string();
+    if state.config.api_keys.is_empty() {
+        // Without keys everything is open, except endpoints that change the service itself.
+        if path.starts_with("/admin/") {
+            let resp = HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "Admin endpoints are disabled until api_keys are configured"
+            }));
+            return Ok(req.into_response(resp));
+        }
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+    let scope = match required_scope(&path) {
+        Some(s) => s,
+        None => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+
+    let key = match find_key(&state.config, req.request()) {
+        Some(k) => k,
+        None => {
+            let error = if presented_key(req.request()).is_some() {
+                "Invalid API key"
+            } else {
+                "Missing API key (send X-API-Key or Authorization: Bearer)"
+            };
+            eprintln!("[AUTH] Rejected {} {}: {}", req.method(), path, error);
+            let resp = HttpResponse::Unauthorized()
+                .append_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .json(serde_json::json!({ "error": error }));
+            return Ok(req.into_response(resp));
+        }
+    };
+
+    if !key.scopes.iter().any(|s| s == scope || s == "admin") {
+        eprintln!("[AUTH] Key {} lacks scope {} for {} {}", key.name, scope, req.method(), path);
+        let resp = HttpResponse::Forbidden().json(serde_json::json!({
+            "error": format!("API key {} does not have the {} scope", key.name, scope)
+        }));
+        return Ok(req.into_response(resp));
+    }
+
+    req.extensions_mut().insert(Authenticated(key.name.clone()));
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test as actix_test, App};
+
+    use super::*;
+
+    const KEYS: &str = r#"
+        [[api_keys]]
+        name = "reader"
+        key = "k-reader"
+        scopes = ["info", "download"]
+
+        [[api_keys]]
+        name = "ops"
+        key = "k-ops"
+        scopes = ["admin"]
+    "#;
+
+    /// Status of `method path` sent with `key` to the service configured by `toml`.
+    async fn status(toml: &str, method: &str, path: &str, key: Option<&str>) -> StatusCode {
+        let state = web::Data::new(AppState::new(AppConfig::from_toml(toml).unwrap(), None));
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(actix_web::middleware::from_fn(require_key))
+                .app_data(state)
+                .configure(crate::routes),
+        )
+        .await;
+        let mut req = match method {
+            "POST" => actix_test::TestRequest::post(),
+            _ => actix_test::TestRequest::get(),
+        }
+        .uri(path);
+        if let Some(key) = key {
+            req = req.insert_header(("X-API-Key", key));
+        }
+        actix_test::call_service(&app, req.to_request()).await.status()
+    }
+
+    #[test]
+    fn routed_path_decodes_escapes() {
+        let req = actix_test::TestRequest::get().uri("/%61dmin/%63ookies?x=%61").to_http_request();
+        assert_eq!(routed_path(&req), "/admin/cookies");
+        assert_eq!(req.path(), "/%61dmin/%63ookies");
+    }
+
+    #[actix_web::test]
+    async fn admin_endpoints_refused_without_keys_however_spelled() {
+        for (method, path) in [
+            ("GET", "/admin/cookies"),
+            ("GET", "/%61dmin/cookies"),
+            ("POST", "/%61dmin/cookies"),
+            ("POST", "/%61%64%6D%69%6E/%63ookies?dry_run=true"),
+        ] {
+            assert_eq!(status("", method, path, None).await, StatusCode::FORBIDDEN, "{} {}", method, path);
+        }
+        // Everything else stays open without keys.
+        assert_eq!(status("", "GET", "/%73tats", None).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn scopes_follow_the_decoded_path() {
+        let cases = [
+            // (method, path, key, expected)
+            ("POST", "/admin/cookies", Some("k-reader"), StatusCode::FORBIDDEN),
+            ("POST", "/%61dmin/cookies", Some("k-reader"), StatusCode::FORBIDDEN),
+            ("GET", "/%61dmin/%63ookies", Some("k-reader"), StatusCode::FORBIDDEN),
+            ("POST", "/%61dmin/cookies", None, StatusCode::UNAUTHORIZED),
+            ("GET", "/%73tats", Some("k-reader"), StatusCode::FORBIDDEN),
+            ("GET", "/health/%63ookies", Some("k-reader"), StatusCode::FORBIDDEN),
+            ("GET", "/%73tats", None, StatusCode::UNAUTHORIZED),
+            // The admin key reaches the handlers: /admin/cookies refuses the default browser profile with 409.
+            ("POST", "/%61dmin/cookies?dry_run=true", Some("k-ops"), StatusCode::CONFLICT),
+            ("GET", "/%73tats", Some("k-ops"), StatusCode::OK),
+            ("GET", "/health/%63ookies", Some("k-ops"), StatusCode::OK),
+            ("GET", "/", None, StatusCode::OK),
+        ];
+        for (method, path, key, expected) in cases {
+            assert_eq!(status(KEYS, method, path, key).await, expected, "{} {} key={:?}", method, path, key);
+        }
+    }
+}
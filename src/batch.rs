@@ -0,0 +1,248 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::io::AsyncWrite;
+
+use crate::cache::ContentCache;
+use crate::handlers::download_filename;
+use crate::jobs::{self, Job, JobStatus};
+use crate::limiter::Reservation;
+use crate::spec::{DownloadOptions, DownloadSpec};
+use crate::state::AppState;
+use crate::urlguard;
+use crate::util;
+use crate::ytdlp::build_ytdlp_command;
+use crate::zip::{AddError, ZipWriter};
+
+const DEFAULT_PARALLELISM: usize = 2;
+const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    pub urls: Vec<String>,
+    // Shared by every item: mode, format selection, embed and clip options; see DownloadOptions.
+    #[serde(flatten)]
+    pub options: DownloadOptions,
+    // How many items download at once (default 2, at most max_concurrent_downloads).
+    pub parallelism: Option<usize>,
+}
+
+/// Validated batch: the urls, the shared download spec and the effective parallelism.
+pub struct Batch {
+    pub urls: Vec<String>,
+    pub spec: DownloadSpec,
+    pub parallelism: usize,
+}
+
+impl BatchRequest {
+    pub fn validate(self, max_items: usize, max_parallelism: usize) -> Result<Batch, String> {
+        if self.urls.is_empty() {
+            return Err("Missing urls".to_string());
+        }
+        if self.urls.len() > max_items {
+            return Err(format!("Too many urls (max: {})", max_items));
+        }
+        let spec = self.options.validate()?;
+        let parallelism = self.parallelism.unwrap_or(DEFAULT_PARALLELISM);
+        if parallelism == 0 {
+            return Err("Invalid parallelism (expected >= 1)".to_string());
+        }
+        Ok(Batch {
+            urls: self.urls,
+            spec,
+            parallelism: parallelism.min(max_parallelism.max(1)),
+        })
+    }
+}
+
+// A finished item: its file, opened right away so neither cache eviction nor job expiry can take it away before
+// it has been archived, plus the job owning it (None for cache hits).
+struct Fetched {
+    path: PathBuf,
+    file: tokio::fs::File,
+    size: u64,
+    cached: bool,
+    _job: Option<Arc<Job>>,
+}
+
+struct Failed {
+    error: String,
+    stderr_tail: Option<String>,
+}
+
+impl Failed {
+    fn new(error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            stderr_tail: None,
+        }
+    }
+}
+
+/// Download one item the way `/download` does: from the cache, by attaching to an identical job in flight, or as a
+/// new job. New jobs wait for a slot of `AppState::limiter` for as long as it takes (regardless of the queue
+/// settings), since the batch itself already bounds how many of its items are waiting.
+async fn fetch(state: &web::Data<AppState>, url: &str, spec: &DownloadSpec) -> Result<Fetched, Failed> {
+    if url.trim().is_empty() {
+        return Err(Failed::new("Missing url"));
+    }
+    if let Err(rejection) = urlguard::check(state.config.as_ref(), url).await {
+        return Err(Failed {
+            error: rejection.message,
+            stderr_tail: None,
+        });
+    }
+    let url = util::canonical_url(url);
+    let url = url.as_str();
+    let variant = spec.variant();
+
+    let cached = match (&state.cache, ContentCache::key(url, &variant)) {
+        (Some(cache), Some(key)) => cache.get(&key),
+        _ => None,
+    };
+    // An entry evicted before it could be opened is downloaded again.
+    if let Some(path) = cached {
+        if let Ok(file) = tokio::fs::File::open(&path).await {
+            let size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+            return Ok(Fetched {
+                path,
+                file,
+                size,
+                cached: true,
+                _job: None,
+            });
+        }
+    }
+
+    let job = match state.jobs.find_active(url, &variant) {
+        Some(job) => job,
+        None => {
+            let permit = state.limiter.acquire_unbounded().await;
+            let retention = Duration::from_secs(state.config.download_grace_secs);
+            let job = Arc::new(Job::new(url.to_string(), spec, retention).map_err(|e| Failed::new(e.to_string()))?);
+            job.cancel_when_abandoned();
+            let cmd = build_ytdlp_command(state.config.as_ref(), spec, url, job.out_dir()).map_err(Failed::new)?;
+            match state.jobs.insert_unless_active(job.clone()) {
+                Some(existing) => existing,
+                None => {
+                    jobs::start(job.clone(), cmd, Reservation::Ready(permit), state);
+                    job
+                }
+            }
+        }
+    };
+
+    // Dropping this future (the client went away) cancels the job unless another request is waiting on it.
+    let _cancel = jobs::CancelOnDrop::new(state.jobs.clone(), job.clone());
+    let mut status_rx = job.subscribe_status();
+    let status = match status_rx.wait_for(|s| s.is_finished()).await {
+        Ok(s) => *s,
+        Err(_) => JobStatus::Cancelled,
+    };
+    match status {
+        JobStatus::Completed => {
+            let path = job.file().ok_or_else(|| Failed::new("Job file missing"))?;
+            let file = tokio::fs::File::open(&path)
+                .await
+                .map_err(|e| Failed::new(format!("Downloaded file missing: {}", e)))?;
+            Ok(Fetched {
+                path,
+                file,
+                size: job.file_size().unwrap_or(0),
+                cached: false,
+                _job: Some(job),
+            })
+        }
+        JobStatus::Cancelled => Err(Failed::new("Download was cancelled")),
+        _ => {
+            let snap = job.snapshot();
+            Err(Failed {
+                error: snap.error.unwrap_or_else(|| "Download failed".to_string()),
+                stderr_tail: snap.stderr_tail,
+            })
+        }
+    }
+}
+
+/// Download every item of `batch` (up to `parallelism` at a time) and write them into a ZIP on `out` in the order
+/// they finish, followed by `manifest.json` with the status of each item. Failed items (including files that
+/// can't be read back) only show up in the manifest. An error means `out` failed (usually: the client
+/// disconnected) and the archive is incomplete.
+pub async fn run<W: AsyncWrite + Unpin>(state: web::Data<AppState>, batch: Batch, out: W) -> std::io::Result<()> {
+    let Batch { urls, spec, parallelism } = batch;
+    let mut zip = ZipWriter::new(out);
+    let mut manifest: Vec<(usize, serde_json::Value)> = Vec::with_capacity(urls.len());
+
+    let mut results = futures::stream::iter(urls.clone().into_iter().enumerate())
+        .map(|(i, url)| {
+            let state = state.clone();
+            let spec = spec.clone();
+            async move {
+                let result = fetch(&state, &url, &spec).await;
+                (i, url, result)
+            }
+        })
+        .buffer_unordered(parallelism);
+
+    while let Some((i, url, result)) = results.next().await {
+        let entry = match result {
+            Ok(fetched) => {
+                let name = format!("{:03}_{}", i + 1, download_filename(&url, &spec, &fetched.path));
+                // A file that can't be read is reported like a failed download; anything else is an output error.
+                match zip.add_open_file(&name, fetched.file).await {
+                    Ok(()) => {
+                        eprintln!("[BATCH] Item {} added ({} bytes): {}", i + 1, fetched.size, url);
+                        serde_json::json!({
+                            "index": i + 1,
+                            "url": url,
+                            "status": "completed",
+                            "file": name,
+                            "size": fetched.size,
+                            "cached": fetched.cached,
+                        })
+                    }
+                    Err(AddError::Source(e)) => {
+                        eprintln!("[BATCH] Item {} could not be archived: {} ({})", i + 1, e, url);
+                        serde_json::json!({
+                            "index": i + 1,
+                            "url": url,
+                            "status": "failed",
+                            "error": format!("Failed to read downloaded file: {}", e),
+                        })
+                    }
+                    Err(AddError::Output(e)) => return Err(e),
+                }
+            }
+            Err(failed) => {
+                eprintln!("[BATCH] Item {} failed: {} ({})", i + 1, failed.error, url);
+                serde_json::json!({
+                    "index": i + 1,
+                    "url": url,
+                    "status": "failed",
+                    "error": failed.error,
+                    "stderr_tail": failed.stderr_tail,
+                })
+            }
+        };
+        manifest.push((i, entry));
+    }
+
+    manifest.sort_by_key(|(i, _)| *i);
+    let items: Vec<serde_json::Value> = manifest.into_iter().map(|(_, v)| v).collect();
+    let completed = items.iter().filter(|v| v["status"] == "completed").count();
+    let body = serde_json::to_vec_pretty(&serde_json::json!({
+        "variant": spec.variant(),
+        "completed": completed,
+        "failed": items.len() - completed,
+        "items": items,
+    }))
+    .map_err(std::io::Error::other)?;
+    zip.add_bytes(MANIFEST_NAME, &body).await?;
+    zip.finish().await?;
+    eprintln!("[BATCH] Finished: {} completed, {} failed", completed, urls.len() - completed);
+    Ok(())
+}
@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+
+use crate::spec::ChosenFormat;
+use crate::util;
+
+/// Suffix of the file next to a cached entry that records the format it was downloaded in.
+const FORMAT_SUFFIX: &str = ".format.json";
+
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    created: SystemTime,
+    last_access: SystemTime,
+}
+
+/// Persistent cache of finished downloads, keyed by video id + mode. Entries expire after `ttl` and the least
+/// recently used ones are evicted once the total size goes over `max_bytes`. Each entry may have a
+/// `<key>.format.json` next to it with the `ChosenFormat` to report on a hit.
+pub struct ContentCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+    index: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl ContentCache {
+    /// Open (or create) the cache directory and index whatever is already in it.
+    pub fn open(dir: &Path, max_bytes: u64, ttl: Duration) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create cache dir: {}", dir.to_string_lossy()))?;
+
+        let mut index = HashMap::new();
+        let mut sidecars = Vec::new();
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read cache dir: {}", dir.to_string_lossy()))?
+        {
+            let entry = entry?;
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                // Leftover partial copy from a previous run.
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            if let Some(key) = name.strip_suffix(FORMAT_SUFFIX) {
+                sidecars.push((key.to_string(), path));
+                continue;
+            }
+            let meta = match entry.metadata() {
+                Ok(m) if m.is_file() => m,
+                _ => continue,
+            };
+            let key = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(k) => k.to_string(),
+                None => continue,
+            };
+            let mtime = meta.modified().unwrap_or_else(|_| SystemTime::now());
+            index.insert(
+                key,
+                CacheEntry {
+                    path,
+                    size: meta.len(),
+                    created: mtime,
+                    last_access: mtime,
+                },
+            );
+        }
+
+        // Formats of files that are gone.
+        for (key, path) in sidecars {
+            if !index.contains_key(&key) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+
+        let cache = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            ttl,
+            index: Mutex::new(index),
+        };
+        cache.purge_expired();
+        cache.evict_to_fit(None);
+        Ok(cache)
+    }
+
+    /// Cache key for a download, or None when the URL isn't a recognizable video (we don't cache those).
+    pub fn key(url: &str, variant: &str) -> Option<String> {
+        let media = util::parse_media_url(url)?;
+        if media.extractor != "youtube" {
+            return None;
+        }
+        let id = media.id?;
+        Some(util::sanitize_filename_component(&format!("{}-{}", id, variant)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().len()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.index.lock().unwrap().values().map(|e| e.size).sum()
+    }
+
+    fn format_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", key, FORMAT_SUFFIX))
+    }
+
+    /// Remove an entry's files once it has left the index.
+    fn remove_files(&self, key: &str, path: &Path) {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(self.format_path(key));
+    }
+
+    fn is_expired(&self, e: &CacheEntry, now: SystemTime) -> bool {
+        now.duration_since(e.created).map(|age| age > self.ttl).unwrap_or(false)
+    }
+
+    /// Look up a cached file, marking it as recently used.
+    pub fn get(&self, key: &str) -> Option<PathBuf> {
+        let now = SystemTime::now();
+        let mut index = self.index.lock().unwrap();
+        let expired = self.is_expired(index.get(key)?, now);
+        if expired {
+            if let Some(e) = index.remove(key) {
+                self.remove_files(key, &e.path);
+            }
+            return None;
+        }
+        let entry = index.get_mut(key)?;
+        if !entry.path.exists() {
+            index.remove(key);
+            return None;
+        }
+        entry.last_access = now;
+        Some(entry.path.clone())
+    }
+
+    /// The format recorded for a cached entry, if it was stored with one.
+    pub fn format(&self, key: &str) -> Option<ChosenFormat> {
+        let raw = std::fs::read(self.format_path(key)).ok()?;
+        serde_json::from_slice(&raw).ok()
+    }
+
+    /// Add a finished download to the cache. The file is hard-linked when possible and copied otherwise,
+    /// so `src` stays usable by its owner. `format` is replayed by `format` on later hits.
+    pub async fn insert(&self, key: &str, src: &Path, format: Option<&ChosenFormat>) -> Result<PathBuf> {
+        let size = tokio::fs::metadata(src).await?.len();
+        if size > self.max_bytes {
+            return Err(anyhow::anyhow!(
+                "file is larger than cache_max_bytes ({} > {})",
+                size,
+                self.max_bytes
+            ));
+        }
+
+        let ext = src.extension().and_then(|e| e.to_str()).unwrap_or("bin");
+        let dest = self.dir.join(format!("{}.{}", key, ext));
+        let tmp = self.dir.join(format!(".{}.{}.tmp", key, uuid::Uuid::new_v4()));
+
+        if tokio::fs::hard_link(src, &tmp).await.is_err() {
+            if let Err(e) = tokio::fs::copy(src, &tmp).await {
+                let _ = tokio::fs::remove_file(&tmp).await;
+                return Err(e).context("Failed to copy file into cache");
+            }
+        }
+        // The format goes in first so a hit on the new file always finds it.
+        let format_path = self.format_path(key);
+        match format {
+            Some(f) => {
+                let format_tmp = self.dir.join(format!(".{}.{}.format.tmp", key, uuid::Uuid::new_v4()));
+                let written = match tokio::fs::write(&format_tmp, serde_json::to_vec(f)?).await {
+                    Ok(()) => tokio::fs::rename(&format_tmp, &format_path).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = written {
+                    let _ = tokio::fs::remove_file(&format_tmp).await;
+                    let _ = tokio::fs::remove_file(&tmp).await;
+                    return Err(e).context("Failed to store format in cache");
+                }
+            }
+            None => {
+                let _ = tokio::fs::remove_file(&format_path).await;
+            }
+        }
+        tokio::fs::rename(&tmp, &dest)
+            .await
+            .context("Failed to move file into cache")?;
+
+        let now = SystemTime::now();
+        let old = self.index.lock().unwrap().insert(
+            key.to_string(),
+            CacheEntry {
+                path: dest.clone(),
+                size,
+                created: now,
+                last_access: now,
+            },
+        );
+        if let Some(old) = old {
+            if old.path != dest {
+                let _ = tokio::fs::remove_file(&old.path).await;
+            }
+        }
+
+        self.evict_to_fit(Some(key));
+        Ok(dest)
+    }
+
+    /// Remove least recently used entries until the cache fits in `max_bytes`. `keep` is never evicted.
+    fn evict_to_fit(&self, keep: Option<&str>) {
+        let mut removed = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            let mut total: u64 = index.values().map(|e| e.size).sum();
+            while total > self.max_bytes {
+                let victim = index
+                    .iter()
+                    .filter(|(k, _)| Some(k.as_str()) != keep)
+                    .min_by_key(|(_, e)| e.last_access)
+                    .map(|(k, _)| k.clone());
+                let victim = match victim {
+                    Some(v) => v,
+                    None => break,
+                };
+                if let Some(e) = index.remove(&victim) {
+                    total -= e.size;
+                    removed.push((victim, e.path));
+                }
+            }
+        }
+        for (key, path) in &removed {
+            self.remove_files(key, path);
+        }
+        if !removed.is_empty() {
+            eprintln!("[CACHE] Evicted {} file(s) to stay under {} bytes", removed.len(), self.max_bytes);
+        }
+    }
+
+    /// Drop entries older than the TTL; returns how many were removed.
+    pub fn purge_expired(&self) -> usize {
+        let now = SystemTime::now();
+        let mut removed = Vec::new();
+        {
+            let mut index = self.index.lock().unwrap();
+            let expired: Vec<String> = index
+                .iter()
+                .filter(|(_, e)| self.is_expired(e, now))
+                .map(|(k, _)| k.clone())
+                .collect();
+            for k in expired {
+                if let Some(e) = index.remove(&k) {
+                    removed.push((k, e.path));
+                }
+            }
+        }
+        for (key, path) in &removed {
+            self.remove_files(key, path);
+        }
+        removed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::DownloadOptions;
+
+    const URL: &str = "https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+
+    fn source(dir: &Path, name: &str, size: usize) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, vec![b'x'; size]).unwrap();
+        path
+    }
+
+    // Distinct access times even on coarse clocks.
+    fn tick() {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_over_max_bytes() {
+        let src = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ContentCache::open(dir.path(), 250, Duration::from_secs(3600)).unwrap();
+
+        let a = cache.insert("a-progressive", &source(src.path(), "a.mp4", 100), None).await.unwrap();
+        tick();
+        let b = cache.insert("b-progressive", &source(src.path(), "b.mp4", 100), None).await.unwrap();
+        tick();
+        assert_eq!(cache.get("a-progressive"), Some(a.clone()));
+        tick();
+        let c = cache.insert("c-progressive", &source(src.path(), "c.webm", 100), None).await.unwrap();
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.total_bytes(), 200);
+        assert_eq!(cache.get("b-progressive"), None);
+        assert!(!b.exists() && a.exists() && c.exists());
+        assert_eq!(c.file_name().unwrap(), "c-progressive.webm");
+
+        // The entry just inserted stays even when it alone fills the cache.
+        let d = cache.insert("d-progressive", &source(src.path(), "d.mp4", 250), None).await.unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(d.exists() && !a.exists() && !c.exists());
+        assert!(cache.insert("e-progressive", &source(src.path(), "e.mp4", 251), None).await.is_err());
+        // The source files are left alone.
+        assert!(src.path().join("a.mp4").exists());
+
+        // Reopening indexes what is on disk, within the (new) size limit.
+        drop(cache);
+        std::fs::write(dir.path().join(".f.tmp"), b"partial").unwrap();
+        let cache = ContentCache::open(dir.path(), 250, Duration::from_secs(3600)).unwrap();
+        assert_eq!(cache.get("d-progressive"), Some(d.clone()));
+        assert!(!dir.path().join(".f.tmp").exists());
+        let cache = ContentCache::open(dir.path(), 100, Duration::from_secs(3600)).unwrap();
+        assert_eq!(cache.len(), 0);
+        assert!(!d.exists());
+    }
+
+    #[tokio::test]
+    async fn expires_entries_after_ttl() {
+        let src = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ContentCache::open(dir.path(), 1000, Duration::from_secs(3600)).unwrap();
+        let old = cache.insert("old-best", &source(src.path(), "old.mp4", 10), None).await.unwrap();
+        let older = cache.insert("older-best", &source(src.path(), "older.mp4", 10), None).await.unwrap();
+        let fresh = cache.insert("fresh-best", &source(src.path(), "fresh.mp4", 10), None).await.unwrap();
+        let age = |key: &str, secs: u64| {
+            let mut index = cache.index.lock().unwrap();
+            index.get_mut(key).unwrap().created = SystemTime::now() - Duration::from_secs(secs);
+        };
+        age("old-best", 3601);
+        age("older-best", 7200);
+        age("fresh-best", 3500);
+
+        assert_eq!(cache.get("old-best"), None);
+        assert!(!old.exists());
+        assert_eq!(cache.purge_expired(), 1);
+        assert!(!older.exists());
+        assert_eq!(cache.get("fresh-best"), Some(fresh.clone()));
+
+        // After a restart, a file's age is its modification time.
+        let file = std::fs::File::options().write(true).open(&fresh).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(3700)).unwrap();
+        drop(cache);
+        let cache = ContentCache::open(dir.path(), 1000, Duration::from_secs(3600)).unwrap();
+        assert_eq!(cache.len(), 0);
+        assert!(!fresh.exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_chosen_format_with_entry() {
+        let src = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ContentCache::open(dir.path(), 250, Duration::from_secs(3600)).unwrap();
+        let format = ChosenFormat {
+            format_id: "137+140".to_string(),
+            format: "137 - 1920x1080 (1080p)+140 - audio only (medium)".to_string(),
+            ext: Some("mp4".to_string()),
+            vcodec: Some("avc1.640028".to_string()),
+            acodec: Some("mp4a.40.2".to_string()),
+            height: Some(1080),
+            fps: Some(25.0),
+        };
+
+        cache.insert("a-best", &source(src.path(), "a.mp4", 100), Some(&format)).await.unwrap();
+        cache.insert("b-best", &source(src.path(), "b.mp4", 100), None).await.unwrap();
+        assert_eq!(cache.format("a-best"), Some(format.clone()));
+        assert_eq!(cache.format("b-best"), None);
+
+        // The format file isn't an entry of its own, survives a restart and goes away with its entry.
+        drop(cache);
+        let cache = ContentCache::open(dir.path(), 250, Duration::from_secs(3600)).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.format("a-best"), Some(format.clone()));
+        tick();
+        cache.get("b-best").unwrap();
+        cache.insert("c-best", &source(src.path(), "c.mp4", 100), None).await.unwrap();
+        assert_eq!(cache.get("a-best"), None);
+        assert!(!dir.path().join("a-best.format.json").exists());
+
+        // Replacing an entry without a format drops the old one; orphaned format files are removed on open.
+        cache.insert("c-best", &source(src.path(), "c.mp4", 100), Some(&format)).await.unwrap();
+        cache.insert("c-best", &source(src.path(), "c.mp4", 100), None).await.unwrap();
+        assert_eq!(cache.format("c-best"), None);
+        std::fs::write(dir.path().join("gone-best.format.json"), b"{}").unwrap();
+        drop(cache);
+        ContentCache::open(dir.path(), 250, Duration::from_secs(3600)).unwrap();
+        assert!(!dir.path().join("gone-best.format.json").exists());
+    }
+
+    #[test]
+    fn key_covers_video_and_download_options() {
+        let variant = |options: serde_json::Value| {
+            serde_json::from_value::<DownloadOptions>(options).unwrap().validate().unwrap().variant()
+        };
+        let bodies = [
+            serde_json::json!({}),
+            serde_json::json!({"mode": "best"}),
+            serde_json::json!({"mode": "best", "max_height": 720}),
+            serde_json::json!({"mode": "best", "max_height": 1080}),
+            serde_json::json!({"mode": "best", "container": "mkv"}),
+            serde_json::json!({"mode": "best", "vcodec": "av1"}),
+            serde_json::json!({"format": "bestvideo[height<=480]+bestaudio"}),
+            serde_json::json!({"format": "bestvideo[height<=360]+bestaudio"}),
+            serde_json::json!({"mode": "audio"}),
+            serde_json::json!({"mode": "audio", "audio_format": "mp3"}),
+            serde_json::json!({"mode": "audio", "audio_format": "mp3", "audio_bitrate": 128}),
+            serde_json::json!({"embed_metadata": true}),
+            serde_json::json!({"mode": "best", "embed_subs": true, "sub_langs": ["en"]}),
+            serde_json::json!({"start": 10, "end": 40}),
+            serde_json::json!({"start": 10, "end": 40, "force_keyframes": true}),
+            serde_json::json!({"split_chapters": true}),
+        ];
+        let keys: Vec<String> = bodies
+            .iter()
+            .map(|b| ContentCache::key(URL, &variant(b.clone())).unwrap())
+            .collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert!(key.starts_with("dQw4w9WgXcQ-"), "{}", key);
+            assert_eq!(keys.iter().filter(|k| *k == key).count(), 1, "{} for {}", key, bodies[i]);
+        }
+
+        // Spellings of the same video share entries; other sites aren't cached.
+        for url in ["https://youtu.be/dQw4w9WgXcQ", "https://m.youtube.com/watch?v=dQw4w9WgXcQ&t=42"] {
+            assert_eq!(ContentCache::key(url, "progressive").as_deref(), Some("dQw4w9WgXcQ-progressive"));
+        }
+        assert_eq!(ContentCache::key("https://vimeo.com/76979871", "progressive"), None);
+        assert_eq!(ContentCache::key("https://www.youtube.com/playlist?list=PL0123456789", "progressive"), None);
+    }
+}
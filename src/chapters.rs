@@ -0,0 +1,91 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+
+use crate::ytdlp::{find_output, OUTPUT_STEM};
+use crate::zip::ZipWriter;
+
+/// Subdirectory of the job dir that yt-dlp writes the per-chapter files into.
+const CHAPTERS_DIR: &str = "chapters";
+/// Chapter list (`%(chapters)j`) printed by yt-dlp, used for the manifest.
+const CHAPTERS_JSON: &str = "chapters.json";
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Arguments that make yt-dlp split the finished download into one file per chapter, next to the full file.
+pub fn apply_args(cmd: &mut Command, out_dir: &Path) {
+    let template = out_dir
+        .join(CHAPTERS_DIR)
+        .join("%(section_number)03d - %(section_title)s.%(ext)s");
+    cmd.arg("--split-chapters")
+        .arg("-o")
+        .arg(format!("chapter:{}", template.to_string_lossy()))
+        .arg("--print-to-file")
+        .arg("after_move:%(chapters)j")
+        .arg(out_dir.join(CHAPTERS_JSON));
+}
+
+/// Replace the full download in `dir` with `OUTPUT_STEM.zip`: every chapter file plus a `manifest.json` describing
+/// them. Fails when the video had no chapters (yt-dlp then leaves the chapter directory empty or absent).
+pub async fn package(dir: &Path) -> Result<(), String> {
+    let chapter_dir = dir.join(CHAPTERS_DIR);
+    let mut files: Vec<PathBuf> = match std::fs::read_dir(&chapter_dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    if files.is_empty() {
+        return Err("Video has no chapters to split".to_string());
+    }
+    // File names start with the zero-padded chapter number.
+    files.sort();
+
+    let chapters: Vec<serde_json::Value> = std::fs::read_to_string(dir.join(CHAPTERS_JSON))
+        .ok()
+        .and_then(|raw| raw.lines().find(|l| l.trim_start().starts_with('[')).map(|l| l.to_string()))
+        .and_then(|l| serde_json::from_str(&l).ok())
+        .unwrap_or_default();
+
+    let mut manifest = Vec::with_capacity(files.len());
+    for (i, path) in files.iter().enumerate() {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let size = tokio::fs::metadata(path).await.map(|m| m.len()).unwrap_or(0);
+        let chapter = chapters.get(i);
+        manifest.push(serde_json::json!({
+            "index": i + 1,
+            "title": chapter.and_then(|c| c.get("title")).cloned().unwrap_or(serde_json::Value::Null),
+            "start_time": chapter.and_then(|c| c.get("start_time")).cloned().unwrap_or(serde_json::Value::Null),
+            "end_time": chapter.and_then(|c| c.get("end_time")).cloned().unwrap_or(serde_json::Value::Null),
+            "file": name,
+            "size": size,
+        }));
+    }
+    let manifest = serde_json::to_vec_pretty(&serde_json::json!({ "chapters": manifest }))
+        .map_err(|e| format!("Failed to build manifest: {}", e))?;
+
+    let full = find_output(dir);
+    let zip_path = dir.join(format!("{}.zip", OUTPUT_STEM));
+    let file = tokio::fs::File::create(&zip_path)
+        .await
+        .map_err(|e| format!("Failed to create zip: {}", e))?;
+    let mut zip = ZipWriter::new(tokio::io::BufWriter::new(file));
+    zip.add_bytes(MANIFEST_NAME, &manifest)
+        .await
+        .map_err(|e| format!("Failed to write zip: {}", e))?;
+    for path in &files {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        zip.add_file(&name, path)
+            .await
+            .map_err(|e| format!("Failed to write zip: {}", e))?;
+    }
+    zip.finish().await.map_err(|e| format!("Failed to write zip: {}", e))?;
+
+    // Only the archive is served; drop the full video and the loose chapter files.
+    if let Some(full) = full {
+        let _ = tokio::fs::remove_file(full).await;
+    }
+    let _ = tokio::fs::remove_dir_all(&chapter_dir).await;
+    Ok(())
+}
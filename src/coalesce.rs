@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+/// Deduplicates identical concurrent operations: the first caller for a key runs the work, later callers with the
+/// same key wait for that result instead of starting their own. The work runs in a spawned task so it still
+/// completes (and is delivered to the others) if the first caller disconnects.
+pub struct InFlight<T: Clone + Send + Sync + 'static> {
+    map: Mutex<HashMap<String, watch::Receiver<Option<T>>>>,
+}
+
+impl<T: Clone + Send + Sync + 'static> Default for InFlight<T> {
+    fn default() -> Self {
+        Self {
+            map: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> InFlight<T> {
+    /// Run `make()` for `key`, or join an identical run already in flight. Returns the result and whether it was
+    /// shared with an earlier caller, or None if the work panicked.
+    pub async fn run<F, Fut>(self: &Arc<Self>, key: &str, make: F) -> Option<(T, bool)>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        let (mut rx, shared) = {
+            let mut map = self.map.lock().unwrap();
+            match map.get(key) {
+                Some(rx) => (rx.clone(), true),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    map.insert(key.to_string(), rx.clone());
+
+                    let this = self.clone();
+                    let key = key.to_string();
+                    let work = make();
+                    tokio::spawn(async move {
+                        // Unregister even if `work` panics, so the key doesn't stay stuck.
+                        let guard = Unregister { map: this, key };
+                        let out = work.await;
+                        drop(guard);
+                        let _ = tx.send(Some(out));
+                    });
+                    (rx, false)
+                }
+            }
+        };
+
+        let out = rx.wait_for(|v| v.is_some()).await.ok()?.clone()?;
+        Some((out, shared))
+    }
+}
+
+struct Unregister<T: Clone + Send + Sync + 'static> {
+    map: Arc<InFlight<T>>,
+    key: String,
+}
+
+impl<T: Clone + Send + Sync + 'static> Drop for Unregister<T> {
+    fn drop(&mut self) {
+        self.map.map.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    type Outcome = Result<String, String>;
+
+    fn counted(runs: &Arc<AtomicUsize>, out: Outcome) -> impl FnOnce() -> futures::future::BoxFuture<'static, Outcome> {
+        let runs = runs.clone();
+        move || {
+            Box::pin(async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                out
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_identical_requests_share_one_run() {
+        let inflight = Arc::new(InFlight::<Outcome>::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let callers = (0..5).map(|_| inflight.run("info:abc", counted(&runs, Ok("title".to_string()))));
+        let results = futures::future::join_all(callers).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let shared: Vec<bool> = results.iter().map(|r| r.as_ref().unwrap().1).collect();
+        assert_eq!(shared, [false, true, true, true, true]);
+        assert!(results.iter().all(|r| r.as_ref().unwrap().0 == Ok("title".to_string())));
+
+        // Other keys run on their own, and a finished run isn't reused.
+        let other = inflight.run("info:def", counted(&runs, Ok("other".to_string())));
+        let again = inflight.run("info:abc", counted(&runs, Ok("again".to_string())));
+        let (other, again) = tokio::join!(other, again);
+        assert_eq!(other, Some((Ok("other".to_string()), false)));
+        assert_eq!(again, Some((Ok("again".to_string()), false)));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_leader_releases_its_waiters() {
+        let inflight = Arc::new(InFlight::<Outcome>::default());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        // A failed run is delivered to everyone waiting on it, then forgotten.
+        let leader = inflight.run("k", counted(&runs, Err("yt-dlp failed".to_string())));
+        let waiter = inflight.run("k", counted(&runs, Ok("unused".to_string())));
+        let (leader, waiter) = tokio::join!(leader, waiter);
+        assert_eq!(leader, Some((Err("yt-dlp failed".to_string()), false)));
+        assert_eq!(waiter, Some((Err("yt-dlp failed".to_string()), true)));
+        assert!(inflight.map.lock().unwrap().is_empty());
+
+        // A panicking run wakes its waiters with None instead of leaving them hanging.
+        let leader = inflight.run("k", || async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            panic!("boom");
+        });
+        let waiter = inflight.run("k", counted(&runs, Ok("unused".to_string())));
+        let (leader, waiter) = tokio::join!(leader, waiter);
+        assert_eq!((leader, waiter), (None, None));
+        assert!(inflight.map.lock().unwrap().is_empty());
+
+        // The leader's caller going away doesn't take the run down with it.
+        let leader = tokio::spawn({
+            let inflight = inflight.clone();
+            let make = counted(&runs, Ok("done".to_string()));
+            async move { inflight.run("k", make).await }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let waiter = inflight.run("k", counted(&runs, Ok("unused".to_string())));
+        leader.abort();
+        assert_eq!(waiter.await, Some((Ok("done".to_string()), true)));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
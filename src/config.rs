@@ -4,16 +4,53 @@ use std::path::{Path, PathBuf};
 use anyhow::{anyhow, Context, Result};
 use serde::Deserialize;
 
+use crate::cookiecrypt::JarKey;
+
 #[derive(Debug, Clone)]
 pub struct AppConfig {
     pub listen_addr: String,
     pub max_concurrent_downloads: usize,
+    // Requests that find every download slot taken wait in a queue of at most this many (0 = reject with 429
+    // right away), for up to queue_max_wait_secs.
+    pub queue_max_len: usize,
+    pub queue_max_wait_secs: u64,
+    // Separate pools for metadata lookups (/info, /subtitles, /playlist) and /thumbnail, so cheap requests don't
+    // compete with downloads. Both default to max_concurrent_downloads.
+    pub max_concurrent_info: usize,
+    pub max_concurrent_thumbnails: usize,
+    // Optional cap across all pools: a running operation holds its pool's weight of it. Unset = no global cap.
+    pub global_max_weight: Option<u32>,
+    pub download_weight: u32,
+    pub info_weight: u32,
+    pub thumbnail_weight: u32,
+    // How long finished `/jobs` downloads are kept on disk before being cleaned up.
+    pub job_retention_secs: u64,
+    // How long a finished `/download` file is kept so interrupted clients can resume it with a Range request.
+    pub download_grace_secs: u64,
+    // Upper bound on how many entries /playlist extracts from one playlist or channel.
+    pub playlist_max_entries: usize,
+    // Maximum number of urls in one /batch request.
+    pub batch_max_items: usize,
+
+    // Persistent download cache; disabled unless cache_dir is set.
+    pub cache_dir: Option<PathBuf>,
+    pub cache_max_bytes: u64,
+    pub cache_ttl_secs: u64,
 
-    // "browser" (default) or "file"
-    pub cookies_source: String,
-    pub cookies_file: PathBuf,
-    pub cookies_browser: String,
+    // Cookie jars for yt-dlp, used in turn. Without [[cookie_profiles]] this is a single profile named "default"
+    // built from cookies_source ("browser" (default) or "file"), cookies_file and cookies_browser.
+    pub cookie_profiles: Vec<CookieProfile>,
+    // "round_robin" (default) or "least_recently_used".
+    pub cookie_rotation: String,
+    // How long a profile that ran into a bot check, rejected cookies or rate limiting is left out of the rotation.
+    pub cookie_bench_secs: u64,
     pub cookies_refresh_max_age_secs: u64,
+    // Video yt-dlp opens (without downloading) to export a profile's browser cookies into its file.
+    pub cookies_refresh_url: String,
+    // Cap of the exponential wait between refresh attempts after failures.
+    pub cookies_refresh_max_backoff_secs: u64,
+    // Key the cookies files are kept encrypted with, from cookies_key_env or cookies_key_file; None = plaintext.
+    pub cookies_key: Option<JarKey>,
 
     pub ytdlp_bin: PathBuf,
     pub ytdlp_path: String,
@@ -22,23 +59,125 @@ pub struct AppConfig {
     pub ytdlp_proxy: Option<String>,
     // Whether to let yt-dlp inherit http_proxy/https_proxy from the service environment.
     pub inherit_proxy_env: bool,
+
+    // URL checks before anything is passed to yt-dlp. Empty allowed_domains = any domain.
+    pub allowed_domains: Vec<String>,
+    // Passed to yt-dlp as --use-extractors; defaults to ["default", "-generic"], empty = yt-dlp's default set.
+    pub allowed_extractors: Vec<String>,
+    // Allow urls whose host is (or resolves to) a private, loopback or link-local address.
+    pub allow_private_addresses: bool,
+
+    // Per-client rate limits and daily quotas; disabled unless a [rate_limit] table is present.
+    pub rate_limit: Option<RateLimitConfig>,
+
+    // Accepted API keys. When empty, every endpoint is open (the pre-authentication behaviour).
+    pub api_keys: Vec<ApiKey>,
+}
+
+/// One cookie jar yt-dlp can run with.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CookieProfile {
+    pub name: String,
+    // "file" (a cookies.txt) or "browser" (read by yt-dlp on every run).
+    pub source: String,
+    pub file: Option<PathBuf>,
+    // For "browser": what to pass to --cookies-from-browser (e.g. "chrome:Profile 2"). For "file": the browser the
+    // file is refreshed from once it is older than cookies_refresh_max_age_secs; without one the file is never
+    // refreshed (e.g. when it is uploaded through /admin/cookies).
+    pub browser: Option<String>,
+}
+
+pub const COOKIE_ROTATIONS: [&str; 2] = ["round_robin", "least_recently_used"];
+
+/// Scopes an API key can be granted. `admin` implies all others.
+pub const API_KEY_SCOPES: [&str; 4] = ["download", "info", "thumbnail", "admin"];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    // Recorded in logs and used by [[rate_limit.clients]]; the key itself never is.
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<String>,
+}
+
+/// Limits for one client. Unset fields are unlimited.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClientLimits {
+    // Token bucket: refilled at requests_per_minute, holding at most `burst` (default: requests_per_minute).
+    pub requests_per_minute: Option<u32>,
+    pub burst: Option<u32>,
+    // Reset at 00:00 UTC.
+    pub daily_requests: Option<u64>,
+    pub daily_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitClient {
+    // Shown in logs instead of the key.
+    pub name: String,
+    // Sent as `X-API-Key: <key>` or `Authorization: Bearer <key>`. May be omitted when `name` is the name of an
+    // [[api_keys]] entry; requests authenticated with that key then get these limits.
+    pub api_key: Option<String>,
+    #[serde(flatten)]
+    pub limits: ClientLimits,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    // Limits for each client without a listed API key, tracked per IP address.
+    #[serde(flatten)]
+    pub default: ClientLimits,
+    #[serde(default)]
+    pub clients: Vec<RateLimitClient>,
 }
 
 #[derive(Debug, Deserialize)]
 struct AppConfigFile {
     listen_addr: Option<String>,
     max_concurrent_downloads: Option<usize>,
+    queue_max_len: Option<usize>,
+    queue_max_wait_secs: Option<u64>,
+    max_concurrent_info: Option<usize>,
+    max_concurrent_thumbnails: Option<usize>,
+    global_max_weight: Option<u32>,
+    download_weight: Option<u32>,
+    info_weight: Option<u32>,
+    thumbnail_weight: Option<u32>,
+    job_retention_secs: Option<u64>,
+    download_grace_secs: Option<u64>,
+    playlist_max_entries: Option<usize>,
+    batch_max_items: Option<usize>,
+
+    cache_dir: Option<String>,
+    cache_max_bytes: Option<u64>,
+    cache_ttl_secs: Option<u64>,
 
     cookies_source: Option<String>,
     cookies_file: Option<String>,
     cookies_browser: Option<String>,
     cookies_refresh_max_age_secs: Option<u64>,
+    cookies_refresh_url: Option<String>,
+    cookies_refresh_max_backoff_secs: Option<u64>,
+    cookies_key_env: Option<String>,
+    cookies_key_file: Option<String>,
+    #[serde(default)]
+    cookie_profiles: Vec<CookieProfile>,
+    cookie_rotation: Option<String>,
+    cookie_bench_secs: Option<u64>,
 
     ytdlp_bin: Option<String>,
     ytdlp_path: Option<String>,
     ffmpeg_bin: Option<String>,
     ytdlp_proxy: Option<String>,
     inherit_proxy_env: Option<bool>,
+
+    allowed_domains: Option<Vec<String>>,
+    allowed_extractors: Option<Vec<String>>,
+    allow_private_addresses: Option<bool>,
+
+    rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    api_keys: Vec<ApiKey>,
 }
 
 fn default_ytdlp_path() -> String {
@@ -58,20 +197,81 @@ impl AppConfig {
                 path.to_string_lossy().as_ref()
             )
         })?;
+        Self::from_toml(&raw)
+    }
 
-        let file: AppConfigFile = toml::from_str(&raw).context("Failed to parse config.toml")?;
+    /// Parse and validate the contents of a config file.
+    pub fn from_toml(raw: &str) -> Result<Self> {
+        let file: AppConfigFile = toml::from_str(raw).context("Failed to parse config.toml")?;
 
+        let cookies_source = file
+            .cookies_source
+            .unwrap_or_else(|| "browser".to_string())
+            .to_ascii_lowercase();
+        if cookies_source != "browser" && cookies_source != "file" {
+            return Err(anyhow!(
+                "Invalid cookies_source: {} (expected: browser|file)",
+                cookies_source
+            ));
+        }
+        let cookie_profiles = if file.cookie_profiles.is_empty() {
+            let browser = file.cookies_browser.unwrap_or_else(|| "edge".to_string());
+            vec![CookieProfile {
+                name: "default".to_string(),
+                file: (cookies_source == "file")
+                    .then(|| PathBuf::from(file.cookies_file.unwrap_or_else(|| "cookies.txt".to_string()))),
+                source: cookies_source,
+                browser: Some(browser),
+            }]
+        } else {
+            file.cookie_profiles
+        };
+
+        let max_concurrent_downloads = file.max_concurrent_downloads.unwrap_or(5);
         let cfg = Self {
             listen_addr: file.listen_addr.unwrap_or_else(|| "0.0.0.0:8080".to_string()),
-            max_concurrent_downloads: file.max_concurrent_downloads.unwrap_or(5),
-
-            cookies_source: file
-                .cookies_source
-                .unwrap_or_else(|| "browser".to_string())
-                .to_ascii_lowercase(),
-            cookies_file: PathBuf::from(file.cookies_file.unwrap_or_else(|| "cookies.txt".to_string())),
-            cookies_browser: file.cookies_browser.unwrap_or_else(|| "edge".to_string()),
+            max_concurrent_downloads,
+            queue_max_len: file.queue_max_len.unwrap_or(0),
+            queue_max_wait_secs: file.queue_max_wait_secs.unwrap_or(60),
+            max_concurrent_info: file.max_concurrent_info.unwrap_or(max_concurrent_downloads),
+            max_concurrent_thumbnails: file.max_concurrent_thumbnails.unwrap_or(max_concurrent_downloads),
+            global_max_weight: file.global_max_weight,
+            download_weight: file.download_weight.unwrap_or(1),
+            info_weight: file.info_weight.unwrap_or(1),
+            thumbnail_weight: file.thumbnail_weight.unwrap_or(1),
+            job_retention_secs: file.job_retention_secs.unwrap_or(3600),
+            download_grace_secs: file.download_grace_secs.unwrap_or(600),
+            playlist_max_entries: file.playlist_max_entries.unwrap_or(1000),
+            batch_max_items: file.batch_max_items.unwrap_or(50),
+
+            cache_dir: file.cache_dir.and_then(|s| {
+                let s = s.trim().to_string();
+                if s.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(s))
+                }
+            }),
+            cache_max_bytes: file.cache_max_bytes.unwrap_or(10 * 1024 * 1024 * 1024),
+            cache_ttl_secs: file.cache_ttl_secs.unwrap_or(24 * 3600),
+
+            cookie_profiles,
+            cookie_rotation: file.cookie_rotation.unwrap_or_else(|| "round_robin".to_string()),
+            cookie_bench_secs: file.cookie_bench_secs.unwrap_or(900),
             cookies_refresh_max_age_secs: file.cookies_refresh_max_age_secs.unwrap_or(1800),
+            cookies_refresh_url: file
+                .cookies_refresh_url
+                .map(|s| s.trim().to_string())
+                .unwrap_or_else(|| "https://www.youtube.com/watch?v=dQw4w9WgXcQ".to_string()),
+            cookies_refresh_max_backoff_secs: file.cookies_refresh_max_backoff_secs.unwrap_or(3600),
+            cookies_key: JarKey::load(
+                file.cookies_key_env.as_deref().map(str::trim).filter(|s| !s.is_empty()),
+                file.cookies_key_file
+                    .as_deref()
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(Path::new),
+            )?,
 
             ytdlp_bin: PathBuf::from(file.ytdlp_bin.unwrap_or_else(|| "yt-dlp".to_string())),
             ytdlp_path: file.ytdlp_path.unwrap_or_else(default_ytdlp_path),
@@ -90,15 +290,140 @@ impl AppConfig {
                     if s.is_empty() { None } else { Some(s) }
                 }),
             inherit_proxy_env: file.inherit_proxy_env.unwrap_or(false),
+
+            allowed_domains: file
+                .allowed_domains
+                .unwrap_or_default()
+                .into_iter()
+                .map(|d| d.trim().trim_end_matches('.').to_ascii_lowercase())
+                .filter(|d| !d.is_empty())
+                .collect(),
+            allowed_extractors: file
+                .allowed_extractors
+                .unwrap_or_else(|| vec!["default".to_string(), "-generic".to_string()]),
+            allow_private_addresses: file.allow_private_addresses.unwrap_or(false),
+
+            rate_limit: file.rate_limit,
+            api_keys: file.api_keys,
         };
 
-        if cfg.cookies_source != "browser" && cfg.cookies_source != "file" {
+        for (i, p) in cfg.cookie_profiles.iter().enumerate() {
+            if p.name.trim().is_empty() {
+                return Err(anyhow!("Invalid cookie_profiles entry #{}: name must not be empty", i + 1));
+            }
+            if cfg.cookie_profiles[..i].iter().any(|o| o.name == p.name) {
+                return Err(anyhow!("Duplicate cookie profile name: {}", p.name));
+            }
+            match p.source.as_str() {
+                "file" if p.file.is_none() => {
+                    return Err(anyhow!("Cookie profile {} has source = \"file\" but no file", p.name));
+                }
+                "browser" if p.browser.is_none() => {
+                    return Err(anyhow!("Cookie profile {} has source = \"browser\" but no browser", p.name));
+                }
+                "file" | "browser" => {}
+                other => {
+                    return Err(anyhow!(
+                        "Invalid source for cookie profile {}: {} (expected: browser|file)",
+                        p.name,
+                        other
+                    ));
+                }
+            }
+        }
+        if !COOKIE_ROTATIONS.contains(&cfg.cookie_rotation.as_str()) {
             return Err(anyhow!(
-                "Invalid cookies_source: {} (expected: browser|file)",
-                cfg.cookies_source
+                "Invalid cookie_rotation: {} (expected: {})",
+                cfg.cookie_rotation,
+                COOKIE_ROTATIONS.join("|")
             ));
         }
 
+        match url::Url::parse(&cfg.cookies_refresh_url) {
+            Ok(u) if u.scheme() == "http" || u.scheme() == "https" => {}
+            _ => {
+                return Err(anyhow!(
+                    "Invalid cookies_refresh_url: {} (expected an http(s) url)",
+                    cfg.cookies_refresh_url
+                ));
+            }
+        }
+        if cfg.cookies_refresh_max_backoff_secs == 0 {
+            return Err(anyhow!("Invalid cookies_refresh_max_backoff_secs: 0 (expected >= 1)"));
+        }
+
+        if let Some(max) = cfg.global_max_weight {
+            for (name, weight) in [
+                ("download_weight", cfg.download_weight),
+                ("info_weight", cfg.info_weight),
+                ("thumbnail_weight", cfg.thumbnail_weight),
+            ] {
+                if weight == 0 || weight > max {
+                    return Err(anyhow!(
+                        "Invalid {}: {} (expected 1..=global_max_weight, which is {})",
+                        name,
+                        weight,
+                        max
+                    ));
+                }
+            }
+        }
+
+        if let Some(rl) = &cfg.rate_limit {
+            let limits = std::iter::once(("[rate_limit]", &rl.default))
+                .chain(rl.clients.iter().map(|c| (c.name.as_str(), &c.limits)));
+            for (name, l) in limits {
+                if l.requests_per_minute == Some(0) || l.burst == Some(0) {
+                    return Err(anyhow!(
+                        "Invalid rate limit for {}: requests_per_minute and burst must be at least 1",
+                        name
+                    ));
+                }
+            }
+            for (i, c) in rl.clients.iter().enumerate() {
+                match &c.api_key {
+                    Some(k) if k.trim().is_empty() => {
+                        return Err(anyhow!("Empty api_key for rate_limit client {}", c.name));
+                    }
+                    Some(k) if rl.clients[..i].iter().any(|o| o.api_key.as_ref() == Some(k)) => {
+                        return Err(anyhow!("Duplicate api_key for rate_limit client {}", c.name));
+                    }
+                    Some(k) if !cfg.api_keys.is_empty() && !cfg.api_keys.iter().any(|a| &a.key == k) => {
+                        return Err(anyhow!(
+                            "rate_limit client {} has an api_key that is not one of the [[api_keys]] (requests \
+                             with it would be rejected)",
+                            c.name
+                        ));
+                    }
+                    Some(_) => {}
+                    None if !cfg.api_keys.iter().any(|k| k.name == c.name) => {
+                        return Err(anyhow!(
+                            "rate_limit client {} has no api_key and no [[api_keys]] entry of that name",
+                            c.name
+                        ));
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        for (i, k) in cfg.api_keys.iter().enumerate() {
+            if k.name.trim().is_empty() || k.key.trim().is_empty() {
+                return Err(anyhow!("Invalid api_keys entry #{}: name and key must not be empty", i + 1));
+            }
+            if cfg.api_keys[..i].iter().any(|o| o.name == k.name || o.key == k.key) {
+                return Err(anyhow!("Duplicate name or key in api_keys: {}", k.name));
+            }
+            if let Some(s) = k.scopes.iter().find(|s| !API_KEY_SCOPES.contains(&s.as_str())) {
+                return Err(anyhow!(
+                    "Invalid scope {} for api key {} (expected: {})",
+                    s,
+                    k.name,
+                    API_KEY_SCOPES.join("|")
+                ));
+            }
+        }
+
         Ok(cfg)
     }
 }
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+/// Start of an encrypted cookies file, followed by a 24-byte nonce and the XChaCha20-Poly1305 ciphertext.
+const MAGIC: &[u8] = b"yt-dlp-service encrypted cookies v1\n";
+const NONCE_LEN: usize = 24;
+
+/// Key the cookies files are encrypted with at rest: 32 bytes, configured as 64 hex digits.
+#[derive(Clone)]
+pub struct JarKey(Key);
+
+impl fmt::Debug for JarKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("JarKey(..)")
+    }
+}
+
+impl JarKey {
+    pub fn parse(hex: &str) -> Result<Self, String> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err("expected 64 hex digits (32 bytes, e.g. from `openssl rand -hex 32`)".to_string());
+        }
+        let mut key = Key::default();
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        Ok(Self(key))
+    }
+
+    /// The key named by `cookies_key_env` (an environment variable) or `cookies_key_file`; None when neither is
+    /// set.
+    pub fn load(env: Option<&str>, file: Option<&Path>) -> Result<Option<Self>> {
+        let (hex, from) = match (env, file) {
+            (Some(_), Some(_)) => return Err(anyhow!("Set only one of cookies_key_env and cookies_key_file")),
+            (Some(var), None) => (
+                std::env::var(var)
+                    .with_context(|| format!("cookies_key_env: environment variable {} is not set", var))?,
+                format!("environment variable {}", var),
+            ),
+            (None, Some(path)) => (
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read cookies_key_file {}", path.to_string_lossy()))?,
+                path.to_string_lossy().to_string(),
+            ),
+            (None, None) => return Ok(None),
+        };
+        Self::parse(&hex)
+            .map(Some)
+            .map_err(|e| anyhow!("Invalid cookies key in {}: {}", from, e))
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub fn encrypt(key: &JarKey, plaintext: &[u8]) -> Vec<u8> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(&key.0)
+        .encrypt(&nonce, plaintext)
+        .expect("encrypting in memory doesn't fail");
+    [MAGIC, nonce.as_slice(), &ciphertext].concat()
+}
+
+/// The text of a cookies file as read from disk: decrypted when it is encrypted, as it is otherwise. With a key,
+/// only encrypted files are accepted (`secure_jars` encrypts plaintext jars at startup).
+pub fn decode(data: Vec<u8>, key: Option<&JarKey>) -> Result<String, String> {
+    let data = match (data.strip_prefix(MAGIC), key) {
+        (None, None) => data,
+        (None, Some(_)) => {
+            return Err("the file is not encrypted but a cookies key is configured (upload it again through \
+                        /admin/cookies or restart the service to encrypt it)"
+                .to_string())
+        }
+        (Some(_), None) => return Err("the file is encrypted but no cookies key is configured".to_string()),
+        (Some(rest), Some(key)) => {
+            if rest.len() < NONCE_LEN {
+                return Err("the encrypted file is truncated".to_string());
+            }
+            let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+            XChaCha20Poly1305::new(&key.0)
+                .decrypt(XNonce::from_slice(nonce), ciphertext)
+                .map_err(|_| "the file could not be decrypted (wrong key or corrupted file)".to_string())?
+        }
+    };
+    String::from_utf8(data).map_err(|_| "the file is not valid UTF-8".to_string())
+}
+
+/// Whether anyone on the machine may read `path`.
+#[cfg(unix)]
+pub fn world_readable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|m| m.permissions().mode() & 0o004 != 0)
+}
+
+#[cfg(not(unix))]
+pub fn world_readable(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn round_trips_and_detects_tampering() {
+        let key = JarKey::parse(KEY).unwrap();
+        let text = include_str!("../testdata/cookies/youtube.txt");
+        let sealed = encrypt(&key, text.as_bytes());
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.windows(9).any(|w| w == b"g.a000sid"));
+        assert_ne!(sealed, encrypt(&key, text.as_bytes()), "fresh nonce per file");
+        assert_eq!(decode(sealed.clone(), Some(&key)).unwrap(), text);
+
+        let other = JarKey::parse(&KEY.replace("1f", "ff")).unwrap();
+        assert!(decode(sealed.clone(), Some(&other)).unwrap_err().contains("could not be decrypted"));
+        assert!(decode(sealed.clone(), None).unwrap_err().contains("no cookies key"));
+        let mut flipped = sealed.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(decode(flipped, Some(&key)).is_err());
+        assert!(decode(sealed[..MAGIC.len() + 10].to_vec(), Some(&key)).unwrap_err().contains("truncated"));
+
+        // Plaintext jars are only read without a key.
+        assert_eq!(decode(text.as_bytes().to_vec(), None).unwrap(), text);
+        assert!(decode(text.as_bytes().to_vec(), Some(&key)).unwrap_err().contains("not encrypted"));
+    }
+
+    #[test]
+    fn parses_hex_keys() {
+        assert!(JarKey::parse(&format!("  {}\n", KEY.to_uppercase())).is_ok());
+        for bad in ["", "abc", &KEY[..62], &format!("{}00", KEY), &KEY.replace('0', "g")] {
+            assert!(JarKey::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
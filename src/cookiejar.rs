@@ -0,0 +1,352 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+
+use crate::cookiecrypt::{self, JarKey};
+
+/// Cookies that keep a YouTube (Google) account signed in. If they are gone or expired, yt-dlp runs logged out.
+pub const AUTH_COOKIES: [&str; 8] = [
+    "SID",
+    "HSID",
+    "SSID",
+    "APISID",
+    "SAPISID",
+    "__Secure-1PSID",
+    "__Secure-3PSID",
+    "LOGIN_INFO",
+];
+
+/// Sign-in cookies whose expiry decides when a jar needs refreshing.
+const KEY_COOKIES: [&str; 3] = ["SID", "__Secure-1PSID", "__Secure-3PSID"];
+
+/// One line of a Netscape cookies.txt file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub domain: String,
+    pub include_subdomains: bool,
+    pub path: String,
+    pub secure: bool,
+    pub http_only: bool,
+    // Unix time; None for session cookies (expiry 0).
+    pub expires: Option<i64>,
+    pub name: String,
+    pub value: String,
+}
+
+impl Cookie {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|t| t <= now)
+    }
+
+    fn is_youtube_auth(&self) -> bool {
+        let domain = self.domain.trim_start_matches('.');
+        let google = ["youtube.com", "google.com"]
+            .iter()
+            .any(|d| domain == *d || domain.ends_with(&format!(".{}", d)));
+        google && AUTH_COOKIES.contains(&self.name.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    // 1-based line number.
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn parse_bool(field: &str, what: &str) -> Result<bool, String> {
+    match field {
+        "TRUE" => Ok(true),
+        "FALSE" => Ok(false),
+        _ => Err(format!("invalid {} flag {:?} (expected TRUE or FALSE)", what, field)),
+    }
+}
+
+fn parse_line(line: &str) -> Result<Cookie, String> {
+    let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+        Some(rest) => (rest, true),
+        None => (line, false),
+    };
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 7 {
+        return Err(format!("expected 7 tab-separated fields, found {}", fields.len()));
+    }
+    if fields[0].is_empty() {
+        return Err("empty domain".to_string());
+    }
+    if fields[5].is_empty() {
+        return Err("empty cookie name".to_string());
+    }
+    let expires: i64 = fields[4]
+        .parse()
+        .map_err(|_| format!("invalid expiry {:?}", fields[4]))?;
+    Ok(Cookie {
+        domain: fields[0].to_string(),
+        include_subdomains: parse_bool(fields[1], "subdomain")?,
+        path: fields[2].to_string(),
+        secure: parse_bool(fields[3], "secure")?,
+        http_only,
+        expires: (expires > 0).then_some(expires),
+        name: fields[5].to_string(),
+        value: fields[6].to_string(),
+    })
+}
+
+/// Parse a Netscape cookies.txt file (as written by yt-dlp, curl and browser exporters). Blank lines and comments
+/// are skipped; `#HttpOnly_` lines are cookies.
+pub fn parse(text: &str) -> Result<Vec<Cookie>, ParseError> {
+    let mut cookies = Vec::new();
+    for (i, raw) in text.lines().enumerate() {
+        let line = raw.trim_end_matches('\r');
+        if line.trim().is_empty() || (line.starts_with('#') && !line.starts_with("#HttpOnly_")) {
+            continue;
+        }
+        let cookie = parse_line(line).map_err(|message| ParseError { line: i + 1, message })?;
+        cookies.push(cookie);
+    }
+    Ok(cookies)
+}
+
+/// Read (decrypting with `key` when the file is encrypted) and parse a jar, failing on one yt-dlp couldn't use:
+/// unreadable, malformed, or without any cookie.
+pub fn load(path: &Path, key: Option<&JarKey>) -> Result<Vec<Cookie>, String> {
+    let text = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| cookiecrypt::decode(data, key))
+        .map_err(|e| format!("Failed to read {}: {}", path.to_string_lossy(), e))?;
+    let cookies = parse(&text).map_err(|e| format!("Invalid cookies file {}: {}", path.to_string_lossy(), e))?;
+    if cookies.is_empty() {
+        return Err(format!("Cookies file {} contains no cookies", path.to_string_lossy()));
+    }
+    Ok(cookies)
+}
+
+/// Earliest expiry (unix time) of the key YouTube sign-in cookies (SID, __Secure-1PSID, __Secure-3PSID). None
+/// when the jar has none of them, or only as session cookies.
+pub fn earliest_key_expiry(cookies: &[Cookie]) -> Option<i64> {
+    cookies
+        .iter()
+        .filter(|c| c.is_youtube_auth() && KEY_COOKIES.contains(&c.name.as_str()))
+        .filter_map(|c| c.expires)
+        .min()
+}
+
+fn timestamp(t: i64) -> Option<String> {
+    DateTime::<Utc>::from_timestamp(t, 0).map(|d| d.to_rfc3339())
+}
+
+/// What a jar contains, without any cookie values: per-domain counts and expiry ranges, and the state of the
+/// YouTube sign-in cookies.
+pub fn summary(cookies: &[Cookie], now: i64) -> serde_json::Value {
+    let mut domains: BTreeMap<&str, Vec<&Cookie>> = BTreeMap::new();
+    for c in cookies {
+        domains.entry(c.domain.as_str()).or_default().push(c);
+    }
+    let domains: Vec<serde_json::Value> = domains
+        .into_iter()
+        .map(|(domain, cs)| {
+            let expiries = cs.iter().filter_map(|c| c.expires);
+            serde_json::json!({
+                "domain": domain,
+                "cookies": cs.len(),
+                "session": cs.iter().filter(|c| c.expires.is_none()).count(),
+                "expired": cs.iter().filter(|c| c.is_expired(now)).count(),
+                "earliest_expiry": expiries.clone().min().and_then(timestamp),
+                "latest_expiry": expiries.max().and_then(timestamp),
+            })
+        })
+        .collect();
+
+    let auth: Vec<serde_json::Value> = cookies
+        .iter()
+        .filter(|c| c.is_youtube_auth())
+        .map(|c| {
+            serde_json::json!({
+                "name": c.name,
+                "domain": c.domain,
+                "expires": c.expires.and_then(timestamp),
+                "expired": c.is_expired(now),
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "cookies": cookies.len(),
+        "expired": cookies.iter().filter(|c| c.is_expired(now)).count(),
+        "domains": domains,
+        "auth_cookies": auth,
+    })
+}
+
+/// Problems worth telling the operator about before a jar is used: no YouTube sign-in cookies, or expired ones.
+pub fn warnings(cookies: &[Cookie], now: i64) -> Vec<String> {
+    let auth: Vec<&Cookie> = cookies.iter().filter(|c| c.is_youtube_auth()).collect();
+    if auth.is_empty() {
+        return vec!["No YouTube sign-in cookies (SID, __Secure-1PSID, ...): yt-dlp will run logged out".to_string()];
+    }
+    auth.iter()
+        .filter(|c| c.is_expired(now))
+        .map(|c| {
+            format!(
+                "{} ({}) expired at {}",
+                c.name,
+                c.domain,
+                c.expires.and_then(timestamp).unwrap_or_default()
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn sample(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/cookies").join(name)
+    }
+
+    fn names(cookies: &[Cookie]) -> Vec<&str> {
+        cookies.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_sample_jar() {
+        let jar = parse(include_str!("../testdata/cookies/youtube.txt")).unwrap();
+        assert_eq!(
+            names(&jar),
+            ["SID", "__Secure-1PSID", "__Secure-3PSID", "HSID", "YSC", "PREF", "SID", "other"]
+        );
+        assert_eq!(
+            jar[0],
+            Cookie {
+                domain: ".youtube.com".to_string(),
+                include_subdomains: true,
+                path: "/".to_string(),
+                secure: true,
+                http_only: false,
+                expires: Some(1_893_456_000),
+                name: "SID".to_string(),
+                value: "g.a000sid".to_string(),
+            }
+        );
+        assert!(jar[2].http_only, "#HttpOnly_ prefix");
+        assert_eq!(jar[2].domain, ".youtube.com");
+        assert_eq!(jar[4].expires, None, "expiry 0 is a session cookie");
+        assert!(!jar[5].include_subdomains && !jar[5].secure);
+        assert_eq!(jar[5].value, "f6=40000000&tz=UTC");
+        assert_eq!(jar[7].path, "/path");
+    }
+
+    #[test]
+    fn parses_crlf_and_empty_values() {
+        let jar = parse(include_str!("../testdata/cookies/crlf.txt")).unwrap();
+        assert_eq!(names(&jar), ["SID", "LOGIN_INFO"]);
+        assert_eq!(jar[0].value, "x");
+        assert_eq!(jar[1].value, "");
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let cases = [
+            ("malformed.txt", 3, "expected 7 tab-separated fields, found 1"),
+            ("bad_expiry.txt", 2, "invalid expiry \"tomorrow\""),
+            ("bad_flag.txt", 2, "invalid subdomain flag \"yes\""),
+        ];
+        for (file, line, message) in cases {
+            let text = std::fs::read_to_string(sample(file)).unwrap();
+            let err = parse(&text).unwrap_err();
+            assert_eq!(err.line, line, "{}", file);
+            assert!(err.message.contains(message), "{}: {}", file, err.message);
+        }
+
+        let inline = [
+            ("\tTRUE\t/\tTRUE\t0\tSID\tx", "empty domain"),
+            (".youtube.com\tTRUE\t/\tTRUE\t0\t\tx", "empty cookie name"),
+            (".youtube.com\tTRUE\t/\tTRUE\t0\tSID\tx\textra", "found 8"),
+            ("#HttpOnly_.youtube.com\tTRUE\t/", "found 3"),
+        ];
+        for (text, message) in inline {
+            let err = parse(text).unwrap_err();
+            assert!(err.message.contains(message), "{:?}: {}", text, err.message);
+        }
+    }
+
+    #[test]
+    fn load_rejects_unusable_jars() {
+        let cases = [
+            ("header_only.txt", "contains no cookies"),
+            ("malformed.txt", "line 3"),
+            ("missing.txt", "Failed to read"),
+        ];
+        for (file, message) in cases {
+            let err = load(&sample(file), None).unwrap_err();
+            assert!(err.contains(message), "{}: {}", file, err);
+        }
+        assert_eq!(load(&sample("youtube.txt"), None).unwrap().len(), 8);
+    }
+
+    #[test]
+    fn earliest_key_expiry_only_counts_sign_in_cookies() {
+        let cases = [
+            // SID on .google.com expires first; PREF (1700000000) isn't a key cookie.
+            ("youtube.txt", Some(1_830_297_600)),
+            // LOGIN_INFO is a sign-in cookie but not a key one.
+            ("crlf.txt", Some(1_893_456_000)),
+            ("logged_out.txt", None),
+        ];
+        for (file, expected) in cases {
+            assert_eq!(earliest_key_expiry(&load(&sample(file), None).unwrap()), expected, "{}", file);
+        }
+        // Key names on other sites don't count.
+        let jar = parse("example.com\tFALSE\t/\tFALSE\t1000\tSID\tx").unwrap();
+        assert_eq!(earliest_key_expiry(&jar), None);
+    }
+
+    #[test]
+    fn warns_about_missing_or_expired_sign_in_cookies() {
+        let jar = load(&sample("youtube.txt"), None).unwrap();
+        assert!(warnings(&jar, 1_800_000_000).is_empty());
+        let expired = warnings(&jar, 1_850_000_000);
+        assert_eq!(expired.len(), 1);
+        assert!(expired[0].starts_with("SID (.google.com) expired at 2028-"), "{}", expired[0]);
+        assert_eq!(warnings(&jar, 1_900_000_000).len(), 5);
+
+        let logged_out = warnings(&load(&sample("logged_out.txt"), None).unwrap(), 0);
+        assert_eq!(logged_out.len(), 1);
+        assert!(logged_out[0].starts_with("No YouTube sign-in cookies"));
+    }
+
+    #[test]
+    fn summary_never_contains_values() {
+        let jar = load(&sample("youtube.txt"), None).unwrap();
+        let summary = summary(&jar, 1_800_000_000);
+        assert_eq!(summary["cookies"], 8);
+        assert_eq!(summary["expired"], 1);
+        assert_eq!(summary["domains"].as_array().unwrap().len(), 4);
+        let youtube = summary["domains"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|d| d["domain"] == ".youtube.com")
+            .unwrap();
+        assert_eq!(youtube["cookies"], 5);
+        assert_eq!(youtube["session"], 1);
+        assert_eq!(summary["auth_cookies"].as_array().unwrap().len(), 5);
+
+        let text = summary.to_string();
+        for c in &jar {
+            if !c.value.is_empty() {
+                assert!(!text.contains(&c.value), "value of {} leaked", c.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use tokio::process::Command;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::config::{AppConfig, CookieProfile};
+use crate::cookies::{self, DecryptedJar, RefreshHealth};
+
+/// Recognize, from yt-dlp's stderr, failures that another cookie jar may not run into: YouTube's bot check,
+/// cookies it no longer accepts, and rate limiting of the account.
+pub fn cookie_failure(stderr: &str) -> Option<&'static str> {
+    let s = stderr.to_ascii_lowercase().replace('\u{2019}', "'");
+    if s.contains("confirm you're not a bot") {
+        Some("bot check")
+    } else if s.contains("cookies are no longer valid") {
+        Some("cookies rejected")
+    } else if s.contains("http error 429") || s.contains("too many requests") {
+        Some("rate limited")
+    } else {
+        None
+    }
+}
+
+#[derive(Default)]
+struct ProfileState {
+    last_used: Option<Instant>,
+    benched_until: Option<Instant>,
+    failures: u64,
+    last_failure: Option<(DateTime<Utc>, String)>,
+}
+
+impl ProfileState {
+    fn benched(&self, now: Instant) -> bool {
+        self.benched_until.is_some_and(|t| t > now)
+    }
+}
+
+/// The configured cookie profiles and which of them are currently usable. Profiles that fail with a
+/// `cookie_failure` are benched for `cookie_bench_secs` and skipped while others are available.
+pub struct CookiePool {
+    profiles: Vec<CookieProfile>,
+    least_recently_used: bool,
+    bench: Duration,
+    // Round-robin cursor.
+    next: AtomicUsize,
+    states: Mutex<Vec<ProfileState>>,
+    // Browser refreshes of each profile's cookies file.
+    refresh: Vec<RefreshHealth>,
+}
+
+impl CookiePool {
+    pub fn new(cfg: &AppConfig) -> Self {
+        Self {
+            profiles: cfg.cookie_profiles.clone(),
+            least_recently_used: cfg.cookie_rotation == "least_recently_used",
+            bench: Duration::from_secs(cfg.cookie_bench_secs),
+            next: AtomicUsize::new(0),
+            states: Mutex::new(cfg.cookie_profiles.iter().map(|_| ProfileState::default()).collect()),
+            refresh: cfg.cookie_profiles.iter().map(|_| RefreshHealth::default()).collect(),
+        }
+    }
+
+    pub fn profiles(&self) -> &[CookieProfile] {
+        &self.profiles
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CookieProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Each profile with the health of its cookie refreshes.
+    pub fn refresh_health(&self) -> impl Iterator<Item = (&CookieProfile, &RefreshHealth)> {
+        self.profiles.iter().zip(self.refresh.iter())
+    }
+
+    /// Refresh state of every profile, for `GET /health/cookies`: "ok" when every refreshable profile's last
+    /// refresh succeeded, "failing" when every profile's did not, "degraded" in between.
+    pub fn health(&self) -> (&'static str, serde_json::Value) {
+        let profiles: Vec<serde_json::Value> = self
+            .refresh_health()
+            .map(|(p, h)| {
+                let mut v = h.stats();
+                v["name"] = p.name.clone().into();
+                v["source"] = p.source.clone().into();
+                v["refreshes"] = (p.source == "file" && p.browser.is_some()).into();
+                v
+            })
+            .collect();
+        let failing = self.refresh.iter().filter(|h| !h.is_healthy()).count();
+        let status = match failing {
+            0 => "ok",
+            n if n == self.refresh.len() => "failing",
+            _ => "degraded",
+        };
+        (status, serde_json::json!(profiles))
+    }
+
+    /// Pick the next profile not in `tried`. Benched profiles are only picked for a first attempt, and only when
+    /// every profile is benched (the one coming back soonest), so requests still go out rather than all failing.
+    fn pick(&self, tried: &[usize]) -> Option<usize> {
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        let n = self.profiles.len();
+        let available: Vec<usize> = (0..n).filter(|i| !tried.contains(i) && !states[*i].benched(now)).collect();
+
+        let chosen = if available.is_empty() {
+            if !tried.is_empty() {
+                return None;
+            }
+            (0..n).min_by_key(|i| states[*i].benched_until)?
+        } else if self.least_recently_used {
+            *available.iter().min_by_key(|i| states[**i].last_used)?
+        } else {
+            let start = self.next.load(Ordering::Relaxed);
+            let chosen = *available.iter().min_by_key(|i| (**i + n - start % n) % n)?;
+            self.next.store(chosen + 1, Ordering::Relaxed);
+            chosen
+        };
+        states[chosen].last_used = Some(now);
+        Some(chosen)
+    }
+
+    fn has_unbenched(&self, tried: &[usize]) -> bool {
+        let now = Instant::now();
+        let states = self.states.lock().unwrap();
+        (0..self.profiles.len()).any(|i| !tried.contains(&i) && !states[i].benched(now))
+    }
+
+    fn bench(&self, i: usize, reason: &str) {
+        let mut states = self.states.lock().unwrap();
+        let state = &mut states[i];
+        state.benched_until = Some(Instant::now() + self.bench);
+        state.failures += 1;
+        state.last_failure = Some((Utc::now(), reason.to_string()));
+        eprintln!(
+            "[COOKIES] Benched profile {} for {}s: {}",
+            self.profiles[i].name,
+            self.bench.as_secs(),
+            reason
+        );
+    }
+
+    /// Per-profile state, for `GET /stats`.
+    pub fn stats(&self) -> serde_json::Value {
+        let now = Instant::now();
+        let states = self.states.lock().unwrap();
+        let profiles: Vec<serde_json::Value> = self
+            .profiles
+            .iter()
+            .zip(states.iter())
+            .map(|(p, s)| {
+                let benched_for = s.benched_until.and_then(|t| t.checked_duration_since(now));
+                serde_json::json!({
+                    "name": p.name,
+                    "source": p.source,
+                    "benched_for_secs": benched_for.map(|d| d.as_secs().max(1)),
+                    "failures": s.failures,
+                    "last_failure": s.last_failure.as_ref().map(|(_, reason)| reason),
+                    "last_failure_at": s.last_failure.as_ref().map(|(at, _)| at.to_rfc3339()),
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "rotation": if self.least_recently_used { "least_recently_used" } else { "round_robin" },
+            "profiles": profiles,
+        })
+    }
+}
+
+/// The cookie profiles used so far by one operation. Each attempt takes a fresh profile; after a failure that
+/// looks cookie-related the profile is benched and the operation may try again with the next one.
+///
+/// When cookies are encrypted at rest, the rotation owns the decrypted copy of the current profile's jar that
+/// yt-dlp is pointed at. Keep it until yt-dlp has exited; the copy is saved back to the jar and deleted with the
+/// rotation (or on `next`).
+pub struct Rotation {
+    pool: Arc<CookiePool>,
+    tried: Vec<usize>,
+    copy: Option<DecryptedJar>,
+}
+
+impl Rotation {
+    pub fn new(pool: Arc<CookiePool>) -> Self {
+        Self {
+            pool,
+            tried: Vec::new(),
+            copy: None,
+        }
+    }
+
+    /// Pick a profile for the next attempt and make sure its cookies are fresh. A profile whose refresh fails is
+    /// benched and the next one tried. Returns the profile's name and the last refresh error once none is left.
+    pub async fn next(&mut self, cfg: &AppConfig, cookie_lock: &Arc<AsyncMutex<()>>) -> Result<String, String> {
+        let mut last_error = None;
+        self.copy = None;
+        while let Some(i) = self.pool.pick(&self.tried) {
+            self.tried.push(i);
+            let profile = &self.pool.profiles[i];
+            let ready = match cookies::ensure_cookies(cfg, profile, &self.pool.refresh[i], cookie_lock).await {
+                // The file is only ever replaced by a rename, so it can be copied without the lock.
+                Ok(()) => cookies::decrypted_copy(cfg, profile, cookie_lock)
+                    .map_err(|e| format!("Failed to decrypt cookies: {:#}", e)),
+                Err(e) => Err(format!("Failed to refresh cookies: {}", e)),
+            };
+            match ready {
+                Ok(copy) => {
+                    self.copy = copy;
+                    return Ok(profile.name.clone());
+                }
+                Err(error) => {
+                    self.pool.bench(i, &error);
+                    last_error = Some(error);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| "No usable cookie profile".to_string()))
+    }
+
+    /// Add the cookie arguments of the current profile to `cmd`.
+    pub fn apply(&self, cmd: &mut Command) {
+        if let Some(&i) = self.tried.last() {
+            cookies::apply(&self.pool.profiles[i], self.copy.as_ref().map(|c| c.path()), cmd);
+        }
+    }
+
+    /// After a failed attempt: bench the profile if `stderr` shows a cookie-related failure. Returns true when
+    /// the operation should be retried with another profile.
+    pub fn retry_after(&mut self, stderr: &str) -> bool {
+        let (Some(&i), Some(reason)) = (self.tried.last(), cookie_failure(stderr)) else {
+            return false;
+        };
+        self.pool.bench(i, reason);
+        let more = self.pool.has_unbenched(&self.tried);
+        if more {
+            eprintln!(
+                "[COOKIES] Retrying with another profile after {} on {}",
+                reason, self.pool.profiles[i].name
+            );
+        }
+        more
+    }
+}
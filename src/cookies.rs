@@ -1,23 +1,120 @@
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use tempfile::NamedTempFile;
 use tokio::process::Command;
 use tokio::sync::Mutex as AsyncMutex;
 
-use crate::config::AppConfig;
+use crate::config::{AppConfig, CookieProfile};
+use crate::cookiecrypt::{self, JarKey};
+use crate::cookiejar::{self, Cookie};
+
+/// A jar younger than this is only refreshed when it is missing.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Wait after the first failed refresh; doubled with every further failure, up to
+/// `cookies_refresh_max_backoff_secs`.
+const REFRESH_BACKOFF_BASE: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct RefreshState {
+    last_success: Option<DateTime<Utc>>,
+    last_error: Option<(DateTime<Utc>, String)>,
+    consecutive_failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// Outcome of the refreshes of one cookies file from its browser. After a failure further attempts wait for an
+/// exponentially growing backoff, so a browser that can't be read isn't hit on every request.
+#[derive(Default)]
+pub struct RefreshHealth {
+    state: Mutex<RefreshState>,
+}
+
+fn backoff(failures: u32, max: Duration) -> Duration {
+    let factor = 1u32 << failures.saturating_sub(1).min(16);
+    (REFRESH_BACKOFF_BASE * factor).min(max)
+}
+
+impl RefreshHealth {
+    /// How long refreshes are still held off after the last failure.
+    fn backing_off(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state.retry_at.and_then(|t| t.checked_duration_since(Instant::now()))
+    }
+
+    fn record(&self, result: &Result<()>, max_backoff: Duration) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(()) => {
+                state.last_success = Some(Utc::now());
+                state.consecutive_failures = 0;
+                state.retry_at = None;
+            }
+            Err(e) => {
+                state.consecutive_failures += 1;
+                state.last_error = Some((Utc::now(), e.to_string()));
+                let wait = backoff(state.consecutive_failures, max_backoff);
+                state.retry_at = Some(Instant::now() + wait);
+                eprintln!(
+                    "[COOKIES] Refresh failed {} time(s) in a row, next attempt in {}s",
+                    state.consecutive_failures,
+                    wait.as_secs()
+                );
+            }
+        }
+    }
+
+    /// Whether the last refresh attempt (if any) succeeded.
+    pub fn is_healthy(&self) -> bool {
+        self.state.lock().unwrap().consecutive_failures == 0
+    }
+
+    /// For `GET /health/cookies`.
+    pub fn stats(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        let retry_in = state.retry_at.and_then(|t| t.checked_duration_since(Instant::now()));
+        serde_json::json!({
+            "last_success": state.last_success.map(|t| t.to_rfc3339()),
+            "last_error": state.last_error.as_ref().map(|(_, e)| e),
+            "last_error_at": state.last_error.as_ref().map(|(at, _)| at.to_rfc3339()),
+            "consecutive_failures": state.consecutive_failures,
+            "retry_in_secs": retry_in.map(|d| d.as_secs().max(1)),
+        })
+    }
+}
+
+/// Point a yt-dlp command at the jar of `profile`, or at `copy` (its decrypted copy) when the file is encrypted.
+pub fn apply(profile: &CookieProfile, copy: Option<&Path>, cmd: &mut Command) {
+    match (&profile.file, &profile.browser) {
+        (Some(file), _) if profile.source == "file" => {
+            cmd.arg("--cookies").arg(copy.unwrap_or(file).to_string_lossy().as_ref());
+        }
+        (_, Some(browser)) => {
+            cmd.arg("--cookies-from-browser").arg(browser.as_str());
+        }
+        // Ruled out by config validation.
+        _ => {}
+    }
+}
 
 /// Automatically refresh cookies (export from browser) into cookies file.
-pub async fn refresh_cookies(cfg: &AppConfig) -> Result<()> {
-    eprintln!(
-        "[COOKIES] Refreshing from browser: {}...",
-        cfg.cookies_browser
-    );
-
-    // `--cookies FILE` reads from and dumps cookie jar in that file.
-    // We hit an arbitrary video URL but skip download; goal is just to populate/update cookies file.
+pub async fn refresh_cookies(cfg: &AppConfig, browser: &str, cookie_file: &Path) -> Result<()> {
+    eprintln!("[COOKIES] Refreshing {} from browser: {}...", cookie_file.to_string_lossy(), browser);
+
+    // `--cookies FILE` reads from and dumps cookie jar in that file. It is dumped into a private temp dir and then
+    // stored (encrypted, if configured) over the cookies file, which yt-dlp would create world-readable.
+    // We hit the probe URL (any video) but skip download; goal is just to populate/update cookies file.
+    let export_dir = tempfile::Builder::new()
+        .prefix("yt-dlp-cookies-")
+        .tempdir()
+        .context("Failed to create a temp dir for the cookies")?;
+    let export = export_dir.path().join("cookies.txt");
     let ytdlp_bin = &cfg.ytdlp_bin;
-    let browser = &cfg.cookies_browser;
-    let cookie_file = &cfg.cookies_file;
 
     let mut cmd = Command::new(ytdlp_bin);
     cmd.env("PATH", &cfg.ytdlp_path);
@@ -36,15 +133,15 @@ pub async fn refresh_cookies(cfg: &AppConfig) -> Result<()> {
     }
 
     let output = cmd
-        .args(&[
+        .args([
             "--cookies-from-browser",
-            browser.as_str(),
+            browser,
             "--cookies",
-            cookie_file.to_string_lossy().as_ref(),
+            export.to_string_lossy().as_ref(),
             "--skip-download",
             "--quiet",
             "--no-warnings",
-            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            cfg.cookies_refresh_url.as_str(),
         ])
         .output()
         .await
@@ -54,37 +151,403 @@ pub async fn refresh_cookies(cfg: &AppConfig) -> Result<()> {
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Err(anyhow!("yt-dlp failed: {}", stderr));
     }
+    let contents = std::fs::read(&export).context("yt-dlp did not write any cookies")?;
+    store_jar(cfg, cookie_file, &contents)?;
 
     eprintln!("[COOKIES] Refreshed successfully");
     Ok(())
 }
 
-/// Check if cookies need refresh (missing or older than configured max age).
-pub fn needs_refresh(cfg: &AppConfig) -> bool {
-    if !PathBuf::from(&cfg.cookies_file).exists() {
+/// Check if cookies need refresh: the file is missing, older than the configured max age, or — once it is a few
+/// minutes old — unusable or holding a key sign-in cookie that expires before the next scheduled refresh. The
+/// minimum age keeps a browser that only has stale cookies from being re-exported on every request.
+pub fn needs_refresh(cfg: &AppConfig, cookie_file: &Path) -> bool {
+    if !cookie_file.exists() {
         return true;
     }
+    let age = std::fs::metadata(cookie_file)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.elapsed().ok());
+    refresh_due(
+        age,
+        || cookiejar::load(cookie_file, cfg.cookies_key.as_ref()),
+        Utc::now().timestamp(),
+        cfg.cookies_refresh_max_age_secs,
+    )
+}
+
+// The decision of `needs_refresh` for an existing file of the given age; `load` is only called when the age
+// alone doesn't decide.
+fn refresh_due(
+    age: Option<Duration>,
+    load: impl FnOnce() -> std::result::Result<Vec<Cookie>, String>,
+    now: i64,
+    max_age_secs: u64,
+) -> bool {
+    if let Some(age) = age {
+        if age.as_secs() > max_age_secs {
+            return true;
+        }
+        if age < MIN_REFRESH_INTERVAL {
+            return false;
+        }
+    }
+    match load() {
+        Ok(jar) => cookiejar::earliest_key_expiry(&jar).is_some_and(|t| t <= now + max_age_secs as i64),
+        Err(_) => true,
+    }
+}
 
-    if let Ok(metadata) = std::fs::metadata(&cfg.cookies_file) {
-        if let Ok(modified) = metadata.modified() {
-            if let Ok(elapsed) = modified.elapsed() {
-                return elapsed.as_secs() > cfg.cookies_refresh_max_age_secs;
+/// Replace the cookies file with `contents`. The new jar is written to a temp file (mode 0600) in the same
+/// directory and renamed over the old one, so a yt-dlp run never sees it half-written. Hold `cookie_lock`.
+pub fn replace_cookies_file(cookie_file: &Path, contents: &[u8]) -> Result<()> {
+    let dir = match cookie_file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut tmp = tempfile::Builder::new()
+        .prefix(".cookies-upload-")
+        .tempfile_in(&dir)
+        .with_context(|| format!("Failed to create a temp file in {}", dir.to_string_lossy()))?;
+    tmp.write_all(contents).context("Failed to write cookies")?;
+    tmp.as_file().sync_all().context("Failed to write cookies")?;
+    tmp.persist(cookie_file)
+        .with_context(|| format!("Failed to replace {}", cookie_file.to_string_lossy()))?;
+    Ok(())
+}
+
+/// Replace the cookies file with the jar `contents`, encrypted when a cookies key is configured. Hold
+/// `cookie_lock`.
+pub fn store_jar(cfg: &AppConfig, cookie_file: &Path, contents: &[u8]) -> Result<()> {
+    match &cfg.cookies_key {
+        Some(key) => replace_cookies_file(cookie_file, &cookiecrypt::encrypt(key, contents)),
+        None => replace_cookies_file(cookie_file, contents),
+    }
+}
+
+/// A private plaintext copy (mode 0600) of an encrypted cookies file for one yt-dlp run, see `decrypted_copy`.
+/// yt-dlp saves the cookies it ends up with back to the copy when it exits; dropping the copy stores them in the
+/// cookies file (encrypted, under `cookie_lock`) and deletes the copy. Don't drop it before yt-dlp has exited.
+pub struct DecryptedJar {
+    copy: Option<NamedTempFile>,
+    jar: PathBuf,
+    original: String,
+    key: JarKey,
+    cookie_lock: Arc<AsyncMutex<()>>,
+}
+
+impl DecryptedJar {
+    pub fn path(&self) -> &Path {
+        self.copy.as_ref().expect("only taken on drop").path()
+    }
+}
+
+impl Drop for DecryptedJar {
+    fn drop(&mut self) {
+        let Some(copy) = self.copy.take() else {
+            return;
+        };
+        // Outside a runtime (nothing async is running) the updates are dropped along with the copy.
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let jar = std::mem::take(&mut self.jar);
+        let original = std::mem::take(&mut self.original);
+        let key = self.key.clone();
+        let cookie_lock = self.cookie_lock.clone();
+        runtime.spawn(async move {
+            let _guard = cookie_lock.lock().await;
+            match write_back(copy.path(), &jar, &original, &key) {
+                Ok(true) => eprintln!("[COOKIES] Saved cookies updated by yt-dlp to {}", jar.to_string_lossy()),
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("[COOKIES] Failed to save cookies updated by yt-dlp to {}: {:#}", jar.to_string_lossy(), e)
+                }
             }
+            drop(copy);
+        });
+    }
+}
+
+/// Store the jar yt-dlp left in `copy` in `jar`, encrypted. Skipped (returning false) when yt-dlp changed nothing,
+/// left something that isn't a usable jar, or `jar` no longer holds `original` because a refresh or an upload
+/// replaced it meanwhile; the newer jar wins. Hold `cookie_lock`.
+fn write_back(copy: &Path, jar: &Path, original: &str, key: &JarKey) -> Result<bool> {
+    let updated = std::fs::read_to_string(copy).context("Failed to read the decrypted copy")?;
+    if updated == original || cookiejar::parse(&updated).map_or(true, |c| c.is_empty()) {
+        return Ok(false);
+    }
+    let current = std::fs::read(jar).with_context(|| format!("Failed to read {}", jar.to_string_lossy()))?;
+    if cookiecrypt::decode(current, Some(key)).ok().as_deref() != Some(original) {
+        return Ok(false);
+    }
+    replace_cookies_file(jar, &cookiecrypt::encrypt(key, updated.as_bytes()))?;
+    Ok(true)
+}
+
+/// With cookies encrypted at rest, decrypt the cookies file of `profile` into a `DecryptedJar` for one yt-dlp run.
+/// None when yt-dlp can use the profile as it is.
+pub fn decrypted_copy(
+    cfg: &AppConfig,
+    profile: &CookieProfile,
+    cookie_lock: &Arc<AsyncMutex<()>>,
+) -> Result<Option<DecryptedJar>> {
+    let (cookie_file, key) = match (&profile.file, &cfg.cookies_key) {
+        (Some(file), Some(key)) if profile.source == "file" => (file, key),
+        _ => return Ok(None),
+    };
+    let data = std::fs::read(cookie_file)
+        .with_context(|| format!("Failed to read {}", cookie_file.to_string_lossy()))?;
+    let text = cookiecrypt::decode(data, Some(key))
+        .map_err(|e| anyhow!("Failed to read {}: {}", cookie_file.to_string_lossy(), e))?;
+    let mut copy = tempfile::Builder::new()
+        .prefix("yt-dlp-cookies-")
+        .suffix(".txt")
+        .tempfile()
+        .context("Failed to create a temp file for the cookies")?;
+    copy.write_all(text.as_bytes()).context("Failed to write cookies")?;
+    copy.flush().context("Failed to write cookies")?;
+    Ok(Some(DecryptedJar {
+        copy: Some(copy),
+        jar: cookie_file.clone(),
+        original: text,
+        key: key.clone(),
+        cookie_lock: cookie_lock.clone(),
+    }))
+}
+
+/// Startup check of the cookies files: refuse a plaintext jar any user on the machine can read, or one that can't
+/// be read with the configured key, and with a key encrypt plaintext jars in place.
+pub fn secure_jars(cfg: &AppConfig) -> Result<()> {
+    for profile in &cfg.cookie_profiles {
+        let file = match &profile.file {
+            Some(file) if profile.source == "file" => file,
+            _ => continue,
+        };
+        let data = match std::fs::read(file) {
+            Ok(d) => d,
+            // Created by the first refresh or upload.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(anyhow!("Failed to read cookies file {}: {}", file.to_string_lossy(), e)),
+        };
+        let encrypted = cookiecrypt::is_encrypted(&data);
+        if !encrypted && cookiecrypt::world_readable(file) {
+            return Err(anyhow!(
+                "Cookies file {} is readable by every user; restrict it first (chmod 600 {})",
+                file.to_string_lossy(),
+                file.to_string_lossy()
+            ));
+        }
+        // A plaintext jar is checked as such here: with a key, it is encrypted right below.
+        let key = if encrypted { cfg.cookies_key.as_ref() } else { None };
+        cookiecrypt::decode(data.clone(), key)
+            .map_err(|e| anyhow!("Cookies file {} of profile {}: {}", file.to_string_lossy(), profile.name, e))?;
+        if let (false, Some(key)) = (encrypted, &cfg.cookies_key) {
+            replace_cookies_file(file, &cookiecrypt::encrypt(key, &data))?;
+            eprintln!("[COOKIES] Encrypted {} (profile {})", file.to_string_lossy(), profile.name);
         }
     }
-    false
+    Ok(())
 }
 
-pub async fn ensure_cookies(cfg: &AppConfig, cookie_lock: &AsyncMutex<()>) -> Result<()> {
-    if cfg.cookies_source != "file" {
+/// Refresh the cookies file of `profile` from its browser when `needs_refresh` says so (files without a browser
+/// are never refreshed) and `health` isn't backing off after failed attempts, then check that it is a usable jar.
+/// Browser profiles are used as they are.
+pub async fn ensure_cookies(
+    cfg: &AppConfig,
+    profile: &CookieProfile,
+    health: &RefreshHealth,
+    cookie_lock: &AsyncMutex<()>,
+) -> Result<()> {
+    let (file, browser) = match &profile.file {
+        Some(file) if profile.source == "file" => (file, profile.browser.as_deref()),
         // In browser mode, we rely on `--cookies-from-browser` at runtime.
-        return Ok(());
-    }
+        _ => return Ok(()),
+    };
 
     // Avoid multiple concurrent refreshes under load (and avoid writing cookies file concurrently).
     let _guard = cookie_lock.lock().await;
-    if needs_refresh(cfg) {
-        refresh_cookies(cfg).await?;
+    let mut held_off = None;
+    if let Some(browser) = browser {
+        if needs_refresh(cfg, file) {
+            match health.backing_off() {
+                // Keep using the current jar, if it is usable.
+                Some(wait) => held_off = Some(wait),
+                None => {
+                    let result = refresh_cookies(cfg, browser, file).await;
+                    health.record(&result, Duration::from_secs(cfg.cookies_refresh_max_backoff_secs));
+                    result?;
+                }
+            }
+        }
     }
+    // Don't hand yt-dlp a jar it would silently ignore.
+    cookiejar::load(file, cfg.cookies_key.as_ref()).map_err(|e| match held_off {
+        Some(wait) => anyhow!("{} (refresh held off for {}s after failures)", e, wait.as_secs().max(1)),
+        None => anyhow!(e),
+    })?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/cookies").join(name)
+    }
+
+    #[test]
+    fn refresh_follows_age_and_key_cookie_expiry() {
+        const MAX_AGE: u64 = 1800;
+        let mins = |m: u64| Some(Duration::from_secs(m * 60));
+        // youtube.txt: the earliest key cookie (SID on .google.com) expires at 1830297600.
+        let expiry = 1_830_297_600;
+        let cases = [
+            // (file, age, now, expected)
+            ("youtube.txt", mins(31), 0, true),
+            ("youtube.txt", mins(1), expiry, false),
+            ("youtube.txt", mins(10), expiry - 3600, false),
+            ("youtube.txt", mins(10), expiry - 1800, true),
+            ("youtube.txt", mins(10), expiry + 1, true),
+            ("youtube.txt", None, expiry - 3600, false),
+            // No key sign-in cookies: only age counts.
+            ("logged_out.txt", mins(10), 0, false),
+            ("logged_out.txt", mins(31), 0, true),
+            // Unusable jars are refreshed once they are a few minutes old.
+            ("malformed.txt", mins(1), 0, false),
+            ("malformed.txt", mins(10), 0, true),
+            ("header_only.txt", mins(10), 0, true),
+            ("missing.txt", mins(10), 0, true),
+        ];
+        for (file, age, now, expected) in cases {
+            let due = refresh_due(age, || cookiejar::load(&sample(file), None), now, MAX_AGE);
+            assert_eq!(due, expected, "{} age={:?} now={}", file, age, now);
+        }
+    }
+
+    #[test]
+    fn refresh_backoff_doubles_up_to_max() {
+        let max = Duration::from_secs(3600);
+        let waits: Vec<u64> = (1..=8).map(|n| backoff(n, max).as_secs()).collect();
+        assert_eq!(waits, [60, 120, 240, 480, 960, 1920, 3600, 3600]);
+        assert_eq!(backoff(u32::MAX, max), max);
+        assert_eq!(backoff(3, Duration::from_secs(100)).as_secs(), 100);
+    }
+
+    #[test]
+    fn refresh_health_tracks_consecutive_failures() {
+        let health = RefreshHealth::default();
+        let max = Duration::from_secs(3600);
+        assert!(health.is_healthy() && health.backing_off().is_none());
+
+        health.record(&Err(anyhow!("no keyring")), max);
+        health.record(&Err(anyhow!("still no keyring")), max);
+        assert!(!health.is_healthy());
+        assert!(health.backing_off().is_some_and(|d| d > Duration::from_secs(60)));
+        let stats = health.stats();
+        assert_eq!(stats["consecutive_failures"], 2);
+        assert_eq!(stats["last_error"], "still no keyring");
+        assert!(stats["last_success"].is_null());
+
+        health.record(&Ok(()), max);
+        assert!(health.is_healthy() && health.backing_off().is_none());
+        let stats = health.stats();
+        assert_eq!(stats["consecutive_failures"], 0);
+        assert!(stats["last_success"].is_string());
+        assert_eq!(stats["last_error"], "still no keyring");
+    }
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const NEW_COOKIE: &str = ".youtube.com\tTRUE\t/\tTRUE\t1900000000\tVISITOR_INFO1_LIVE\tfresh\n";
+
+    fn encrypted_jar(dir: &Path) -> (AppConfig, PathBuf, String) {
+        let jar = dir.join("cookies.txt");
+        let key_file = dir.join("key");
+        std::fs::write(&key_file, KEY).unwrap();
+        let cfg = AppConfig::from_toml(&format!(
+            "cookies_source = \"file\"\ncookies_file = {:?}\ncookies_key_file = {:?}\n",
+            jar, key_file
+        ))
+        .unwrap();
+        let text = std::fs::read_to_string(sample("youtube.txt")).unwrap();
+        store_jar(&cfg, &jar, text.as_bytes()).unwrap();
+        (cfg, jar, text)
+    }
+
+    fn read_jar(cfg: &AppConfig, jar: &Path) -> String {
+        let data = std::fs::read(jar).unwrap();
+        assert!(cookiecrypt::is_encrypted(&data));
+        cookiecrypt::decode(data, cfg.cookies_key.as_ref()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn decrypted_copy_saves_updates_back_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cfg, jar, text) = encrypted_jar(dir.path());
+        let lock = Arc::new(AsyncMutex::new(()));
+        let copy = decrypted_copy(&cfg, &cfg.cookie_profiles[0], &lock).unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(copy.path()).unwrap(), text);
+
+        // What yt-dlp does on exit.
+        let updated = format!("{}{}", text, NEW_COOKIE);
+        std::fs::write(copy.path(), &updated).unwrap();
+        let copy_path = copy.path().to_path_buf();
+        drop(copy);
+        for _ in 0..100 {
+            if read_jar(&cfg, &jar) == updated {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(read_jar(&cfg, &jar), updated);
+        let _guard = lock.lock().await;
+        assert!(!copy_path.exists());
+    }
+
+    #[test]
+    fn write_back_keeps_newer_or_unusable_jars_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cfg, jar, text) = encrypted_jar(dir.path());
+        let key = cfg.cookies_key.clone().unwrap();
+        let copy = dir.path().join("copy.txt");
+        let updated = format!("{}{}", text, NEW_COOKIE);
+
+        // Nothing changed, or yt-dlp left something that isn't a jar.
+        let before = std::fs::read(&jar).unwrap();
+        for left in [text.as_str(), "", "# Netscape HTTP Cookie File\n", "not a cookie line\n"] {
+            std::fs::write(&copy, left).unwrap();
+            assert!(!write_back(&copy, &jar, &text, &key).unwrap(), "{:?}", left);
+        }
+        assert_eq!(std::fs::read(&jar).unwrap(), before);
+
+        // An upload replaced the jar during the run: it wins.
+        let uploaded = std::fs::read_to_string(sample("logged_out.txt")).unwrap();
+        store_jar(&cfg, &jar, uploaded.as_bytes()).unwrap();
+        std::fs::write(&copy, &updated).unwrap();
+        assert!(!write_back(&copy, &jar, &text, &key).unwrap());
+        assert_eq!(read_jar(&cfg, &jar), uploaded);
+
+        assert!(write_back(&copy, &jar, &uploaded, &key).unwrap());
+        assert_eq!(read_jar(&cfg, &jar), updated);
+    }
+
+    #[test]
+    fn secure_jars_encrypts_plaintext_jars_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cfg, jar, text) = encrypted_jar(dir.path());
+        replace_cookies_file(&jar, text.as_bytes()).unwrap();
+        assert!(cookiejar::load(&jar, cfg.cookies_key.as_ref()).unwrap_err().contains("not encrypted"));
+        secure_jars(&cfg).unwrap();
+        assert_eq!(read_jar(&cfg, &jar), text);
+        assert!(cookiejar::load(&jar, cfg.cookies_key.as_ref()).is_ok());
+    }
+
+    #[test]
+    fn refresh_due_skips_loading_when_age_decides() {
+        for age in [Duration::from_secs(10), Duration::from_secs(3600)] {
+            refresh_due(Some(age), || panic!("loaded the jar"), 0, 1800);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use async_stream::stream;
+use futures::Stream;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Stream `len` bytes of a file starting at `offset`, in 64 KiB chunks. `guard` is held until the body is done
+/// (or dropped), which is how we keep temp dirs, permits and jobs alive for the duration of the response.
+pub fn file_stream<G: 'static>(
+    path: PathBuf,
+    offset: u64,
+    len: u64,
+    guard: G,
+) -> impl Stream<Item = Result<bytes::Bytes, std::io::Error>> {
+    stream! {
+        let _guard = guard;
+
+        let mut file = match File::open(&path).await {
+            Ok(f) => f,
+            Err(e) => {
+                yield Err(e);
+                return;
+            }
+        };
+        if offset > 0 {
+            if let Err(e) = file.seek(SeekFrom::Start(offset)).await {
+                yield Err(e);
+                return;
+            }
+        }
+
+        let mut remaining = len;
+        let mut buffer = vec![0u8; 64 * 1024];
+        while remaining > 0 {
+            let want = remaining.min(buffer.len() as u64) as usize;
+            match file.read(&mut buffer[..want]).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    remaining -= n as u64;
+                    yield Ok(bytes::Bytes::copy_from_slice(&buffer[..n]));
+                }
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Strong validator for a finished file. Files are never modified after yt-dlp is done with them,
+/// so size + mtime is enough to tell two downloads apart.
+pub fn etag(size: u64, modified: SystemTime) -> String {
+    let nanos = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", size, nanos)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RangeSpec {
+    // Inclusive byte range.
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parse a single-range `Range: bytes=...` header. Multi-range requests and anything we don't understand return
+/// None, meaning "serve the whole file", which RFC 9110 allows.
+fn parse_range(value: &str, size: u64) -> Option<RangeSpec> {
+    let spec = value.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // Suffix range: last N bytes.
+        let n: u64 = end.parse().ok()?;
+        if n == 0 || size == 0 {
+            return Some(RangeSpec::Unsatisfiable);
+        }
+        return Some(RangeSpec::Satisfiable(size.saturating_sub(n), size - 1));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    if start >= size {
+        return Some(RangeSpec::Unsatisfiable);
+    }
+    let end = if end.is_empty() {
+        size - 1
+    } else {
+        let e: u64 = end.parse().ok()?;
+        if e < start {
+            return None;
+        }
+        e.min(size - 1)
+    };
+    Some(RangeSpec::Satisfiable(start, end))
+}
+
+fn header_str(req: &HttpRequest, name: header::HeaderName) -> Option<&str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+fn etag_matches(list: &str, tag: &str) -> bool {
+    list.split(',').map(|t| t.trim()).any(|t| t == "*" || t == tag)
+}
+
+/// A finished file to send to the client.
+pub struct ServedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    pub content_type: String,
+    pub filename: String,
+}
+
+impl ServedFile {
+    pub async fn open(
+        path: PathBuf,
+        content_type: impl Into<String>,
+        filename: impl Into<String>,
+    ) -> std::io::Result<Self> {
+        let meta = tokio::fs::metadata(&path).await?;
+        Ok(Self {
+            path,
+            size: meta.len(),
+            modified: meta.modified().unwrap_or(UNIX_EPOCH),
+            content_type: content_type.into(),
+            filename: filename.into(),
+        })
+    }
+
+    pub fn etag(&self) -> String {
+        etag(self.size, self.modified)
+    }
+}
+
+/// Build the response for `file`, honouring `Range`, `If-Range` and `If-None-Match`.
+/// Callers can add their own headers to the returned response.
+pub fn serve<G: 'static>(req: &HttpRequest, file: ServedFile, guard: G) -> HttpResponse {
+    let tag = file.etag();
+
+    if let Some(inm) = header_str(req, header::IF_NONE_MATCH) {
+        if etag_matches(inm, &tag) {
+            return HttpResponse::NotModified()
+                .append_header((header::ETAG, tag))
+                .append_header((header::ACCEPT_RANGES, "bytes"))
+                .finish();
+        }
+    }
+
+    // If-Range: only honour Range when the client's copy is the same file (we only hand out ETags).
+    let range_allowed = match header_str(req, header::IF_RANGE) {
+        Some(v) => v.trim() == tag,
+        None => true,
+    };
+    let range = if range_allowed {
+        header_str(req, header::RANGE).and_then(|v| parse_range(v, file.size))
+    } else {
+        None
+    };
+
+    let disposition = format!(r#"attachment; filename="{}""#, file.filename);
+    match range {
+        Some(RangeSpec::Unsatisfiable) => HttpResponse::RangeNotSatisfiable()
+            .append_header((header::CONTENT_RANGE, format!("bytes */{}", file.size)))
+            .append_header((header::ACCEPT_RANGES, "bytes"))
+            .append_header((header::ETAG, tag))
+            .finish(),
+        Some(RangeSpec::Satisfiable(start, end)) => {
+            let len = end - start + 1;
+            let body = file_stream(file.path, start, len, guard);
+            HttpResponse::PartialContent()
+                .content_type(file.content_type)
+                .append_header((header::CONTENT_LENGTH, len.to_string()))
+                .append_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, file.size)))
+                .append_header((header::ACCEPT_RANGES, "bytes"))
+                .append_header((header::ETAG, tag))
+                .append_header((header::CONTENT_DISPOSITION, disposition))
+                .append_header((header::CACHE_CONTROL, "no-store"))
+                .streaming(body)
+        }
+        None => {
+            let body = file_stream(file.path, 0, file.size, guard);
+            HttpResponse::Ok()
+                .content_type(file.content_type)
+                .append_header((header::CONTENT_LENGTH, file.size.to_string()))
+                .append_header((header::ACCEPT_RANGES, "bytes"))
+                .append_header((header::ETAG, tag))
+                .append_header((header::CONTENT_DISPOSITION, disposition))
+                .append_header((header::CACHE_CONTROL, "no-store"))
+                .streaming(body)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test as actix_test, web, App};
+
+    use super::*;
+
+    const CONTENT: &[u8] = b"0123456789";
+
+    type Response = (StatusCode, Option<String>, Vec<u8>);
+
+    async fn get(path: &std::path::Path, headers: &[(header::HeaderName, &str)]) -> Response {
+        let path = path.to_path_buf();
+        let app = actix_test::init_service(App::new().route(
+            "/file",
+            web::get().to(move |req: HttpRequest| {
+                let path = path.clone();
+                async move { serve(&req, ServedFile::open(path, "video/mp4", "v.mp4").await.unwrap(), ()) }
+            }),
+        ))
+        .await;
+        let mut req = actix_test::TestRequest::get().uri("/file");
+        for (name, value) in headers {
+            req = req.insert_header((name.clone(), *value));
+        }
+        let resp = actix_test::call_service(&app, req.to_request()).await;
+        let status = resp.status();
+        let content_range = resp
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .map(|v| v.to_str().unwrap().to_string());
+        (status, content_range, actix_test::read_body(resp).await.to_vec())
+    }
+
+    #[actix_web::test]
+    async fn serves_byte_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("v.mp4");
+        std::fs::write(&path, CONTENT).unwrap();
+
+        let cases: [(&str, StatusCode, Option<&str>, &[u8]); 11] = [
+            ("bytes=2-5", StatusCode::PARTIAL_CONTENT, Some("bytes 2-5/10"), b"2345"),
+            ("bytes=0-0", StatusCode::PARTIAL_CONTENT, Some("bytes 0-0/10"), b"0"),
+            ("bytes=7-", StatusCode::PARTIAL_CONTENT, Some("bytes 7-9/10"), b"789"),
+            ("bytes=5-100", StatusCode::PARTIAL_CONTENT, Some("bytes 5-9/10"), b"56789"),
+            ("bytes=-3", StatusCode::PARTIAL_CONTENT, Some("bytes 7-9/10"), b"789"),
+            ("bytes=-30", StatusCode::PARTIAL_CONTENT, Some("bytes 0-9/10"), CONTENT),
+            ("bytes=10-", StatusCode::RANGE_NOT_SATISFIABLE, Some("bytes */10"), b""),
+            ("bytes=-0", StatusCode::RANGE_NOT_SATISFIABLE, Some("bytes */10"), b""),
+            // Not understood: the whole file.
+            ("bytes=5-2", StatusCode::OK, None, CONTENT),
+            ("bytes=0-1,4-5", StatusCode::OK, None, CONTENT),
+            ("items=0-1", StatusCode::OK, None, CONTENT),
+        ];
+        for (range, status, content_range, body) in cases {
+            let got = get(&path, &[(header::RANGE, range)]).await;
+            assert_eq!(got, (status, content_range.map(str::to_string), body.to_vec()), "{}", range);
+        }
+        assert_eq!(get(&path, &[]).await, (StatusCode::OK, None, CONTENT.to_vec()));
+    }
+
+    #[actix_web::test]
+    async fn honours_if_none_match_and_if_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("v.mp4");
+        std::fs::write(&path, CONTENT).unwrap();
+        let tag = ServedFile::open(path.clone(), "video/mp4", "v.mp4").await.unwrap().etag();
+        let other = "\"a-1\"";
+
+        let not_modified = (StatusCode::NOT_MODIFIED, None, Vec::new());
+        for inm in [tag.clone(), format!("{}, {}", other, tag), "*".to_string()] {
+            assert_eq!(get(&path, &[(header::IF_NONE_MATCH, &inm)]).await, not_modified, "{}", inm);
+        }
+        let got = get(&path, &[(header::IF_NONE_MATCH, other), (header::RANGE, "bytes=0-1")]).await;
+        assert_eq!(got.0, StatusCode::PARTIAL_CONTENT);
+
+        let partial = (StatusCode::PARTIAL_CONTENT, Some("bytes 8-9/10".to_string()), b"89".to_vec());
+        let whole = (StatusCode::OK, None, CONTENT.to_vec());
+        let cases = [(tag.as_str(), &partial), (other, &whole), ("Sat, 17 Oct 2026 00:00:00 GMT", &whole)];
+        for (if_range, want) in cases {
+            let got = get(&path, &[(header::IF_RANGE, if_range), (header::RANGE, "bytes=-2")]).await;
+            assert_eq!(&got, want, "{}", if_range);
+        }
+        // The file changed since the client got its tag.
+        std::fs::write(&path, b"01234567890").unwrap();
+        let got = get(&path, &[(header::IF_RANGE, &tag), (header::RANGE, "bytes=-2")]).await;
+        assert_eq!(got, (StatusCode::OK, None, b"01234567890".to_vec()));
+        assert_eq!(get(&path, &[(header::IF_NONE_MATCH, &tag)]).await.0, StatusCode::OK);
+    }
+}
@@ -1,131 +1,39 @@
 use std::collections::VecDeque;
-use actix_web::{web, HttpResponse, Responder};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use async_stream::stream;
 use serde::Deserialize;
 use tempfile::TempDir;
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
-use tokio::process::Command;
-use tokio::sync::OwnedSemaphorePermit;
-
-use crate::{cookies, state::AppState, util};
-
-async fn collect_stderr(
-    stderr: tokio::process::ChildStderr,
-    buf: std::sync::Arc<tokio::sync::Mutex<VecDeque<String>>>,
-) {
-    let mut r = BufReader::new(stderr);
-    let mut line = String::new();
-    loop {
-        line.clear();
-        match r.read_line(&mut line).await {
-            Ok(0) => break,
-            Ok(_) => {
-                let l = line.trim_end().to_string();
-                if !l.is_empty() {
-                    eprintln!("{}", l);
-                    let mut g = buf.lock().await;
-                    if g.len() >= 50 {
-                        g.pop_front();
-                    }
-                    g.push_back(l);
-                }
-            }
-            Err(_) => break,
-        }
-    }
-}
-
-async fn render_tail(buf: &tokio::sync::Mutex<VecDeque<String>>) -> String {
-    let g = buf.lock().await;
-    if g.is_empty() {
-        return "no stderr output captured".to_string();
-    }
-    g.iter().cloned().collect::<Vec<_>>().join("\n")
-}
-
-fn find_ffmpeg(cfg: &crate::config::AppConfig) -> Option<String> {
-    if let Some(p) = &cfg.ffmpeg_bin {
-        return Some(p.to_string_lossy().to_string());
-    }
-    // Common macOS/Homebrew locations.
-    for p in ["/opt/homebrew/bin/ffmpeg", "/usr/local/bin/ffmpeg", "/usr/bin/ffmpeg"] {
-        if std::path::Path::new(p).exists() {
-            return Some(p.to_string());
-        }
-    }
-    None
-}
-
-fn build_ytdlp_base_command(cfg: &crate::config::AppConfig) -> Command {
-    let mut cmd = Command::new(&cfg.ytdlp_bin);
-    cmd.env("PATH", &cfg.ytdlp_path);
-
-    if !cfg.inherit_proxy_env {
-        // Avoid being accidentally bound to a dead local proxy (common in shell env).
-        cmd.env_remove("http_proxy")
-            .env_remove("https_proxy")
-            .env_remove("HTTP_PROXY")
-            .env_remove("HTTPS_PROXY")
-            .env_remove("no_proxy")
-            .env_remove("NO_PROXY");
-    }
-
-    if let Some(p) = &cfg.ytdlp_proxy {
-        cmd.arg("--proxy").arg(p);
-    }
-
-    if cfg.cookies_source == "browser" {
-        cmd.arg("--cookies-from-browser").arg(cfg.cookies_browser.as_str());
-    } else {
-        cmd.arg("--cookies").arg(cfg.cookies_file.to_string_lossy().as_ref());
-    }
-
-    cmd.arg("--js-runtimes")
-        .arg("node")
-        .arg("--no-playlist")
-        .arg("--no-cache-dir")
-        .arg("--no-part");
-
-    cmd
-}
-
-fn build_ytdlp_command(
-    cfg: &crate::config::AppConfig,
-    mode: &str,
-    url: &str,
-    out_path: &str,
-) -> Result<Command, String> {
-    let mut cmd = build_ytdlp_base_command(cfg);
-    cmd.arg("-o").arg(out_path);
-
-    if mode == "best" {
-        let ffmpeg = find_ffmpeg(cfg).ok_or_else(|| {
-            "ffmpeg is required for mode=best. Install ffmpeg or set ffmpeg_bin in config.toml".to_string()
-        })?;
-        cmd.arg("--ffmpeg-location").arg(ffmpeg);
-        cmd.arg("-f")
-            .arg("bestvideo[ext=mp4]+bestaudio[ext=m4a]/best[ext=mp4]/best")
-            .arg("--merge-output-format")
-            .arg("mp4");
-    } else {
-        cmd.arg("-f").arg("best[ext=mp4]/best");
-    }
-
-    cmd.arg(url)
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::piped())
-        .kill_on_drop(true);
-
-    Ok(cmd)
-}
+use tokio::io::AsyncReadExt;
+
+use crate::batch::{self, BatchRequest};
+use crate::cache::ContentCache;
+use crate::cookiepool::Rotation;
+use crate::files::{self, ServedFile};
+use crate::jobs::{self, Job, JobStatus};
+use crate::limiter::Permit;
+use crate::playlist::PlaylistRequest;
+use crate::progress::{self, ProgressEvent, ProgressSink};
+use crate::spec::{self, ChosenFormat, DownloadOptions, DownloadSpec};
+use crate::subtitles::{self, SubtitleFormat, TrackKind};
+use crate::ytdlp::{self, build_ytdlp_base_command, build_ytdlp_command, collect_stderr, find_ffmpeg, render_tail};
+use crate::{cookiecrypt, cookiejar, cookies, state::AppState, urlguard, util};
 
 #[derive(Deserialize)]
 pub struct StreamRequest {
     pub url: String,
-    // "progressive" (default): best single-file mp4 if available (more likely to truly stream as it downloads)
-    // "best": bestvideo+bestaudio with merge (may only start streaming after merge)
-    pub mode: Option<String>,
+    // mode, audio_format, audio_bitrate; see DownloadOptions.
+    #[serde(flatten)]
+    pub options: DownloadOptions,
+    // Client-chosen id to follow this download on GET /download/progress/{progress_id}.
+    pub progress_id: Option<String>,
+    // When true (progressive mode only), forward yt-dlp's stdout as it downloads instead of waiting for the file.
+    // There is no Content-Length; a failure after the first byte aborts the chunked response.
+    pub passthrough: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -140,295 +48,879 @@ pub struct InfoRequest {
     pub include_formats: Option<bool>,
 }
 
+#[derive(Deserialize)]
+pub struct SubtitlesRequest {
+    pub url: String,
+    // Language code, e.g. "en" (default) or "pt-BR".
+    pub lang: Option<String>,
+    // "vtt" (default) | "srt" | "json3"
+    pub format: Option<String>,
+    // Unset: manual subtitles, falling back to auto-generated captions. true: auto only. false: manual only.
+    pub auto: Option<bool>,
+    // When true, return the available languages instead of a subtitle file.
+    pub list: Option<bool>,
+}
+
 pub async fn index() -> impl Responder {
     HttpResponse::Ok().json(serde_json::json!({
         "service": "YouTube Download Service",
         "version": "0.2.0",
         "endpoints": {
             "GET /": "Health check",
-            "POST /download": "Download video then return the final mp4 (body: {url, mode})",
+            "GET /stats": "Concurrency pool usage (download, info, thumbnail, global cap), job and cache counts, cookie profile state",
+            "GET /health/cookies": "Cookie refresh health per profile: last success, last error, consecutive failures (503 when every profile is failing)",
+            "GET /admin/cookies": "Domains, counts and expiry times of a cookies file (values redacted; ?profile=name)",
+            "POST /admin/cookies": "Check an uploaded Netscape cookies.txt (body) and install it as a profile's cookies file (?profile=name, ?dry_run=true to only check)",
+            "POST /download": "Download video then return the final file (body: {url, mode, max_height, max_fps, vcodec, acodec, container, format, audio_format, audio_bitrate, progress_id, passthrough})",
+            "POST /audio": "Same as /download with mode=audio (body: {url, audio_format, audio_bitrate, progress_id})",
+            "POST /batch": "Download several urls and stream back a ZIP with a manifest.json of per-item status (body: {urls, parallelism, plus the /download options})",
             "POST /thumbnail": "Download thumbnail then return the image (body: {url})",
-            "POST /info": "Get video info JSON (body: {url, include_formats})"
+            "POST /info": "Get video info JSON (body: {url, include_formats})",
+            "POST /playlist": "List the entries of a playlist or channel, paginated (body: {url, page, page_size, limit, date_after, date_before})",
+            "POST /subtitles": "Subtitles or auto-captions of a video, or the available languages (body: {url, lang, format, auto, list})",
+            "POST /jobs": "Start a background download and return its job id (body: same download options as /download)",
+            "GET /jobs/{id}": "Job status",
+            "GET /jobs/{id}/file": "Download the finished file of a job",
+            "DELETE /jobs/{id}": "Cancel a job and delete its file",
+            "GET /download/progress/{id}": "Server-Sent Events with download progress (job id or /download progress_id)"
         }
     }))
 }
 
-pub async fn stream_direct(req: web::Json<StreamRequest>, state: web::Data<AppState>) -> impl Responder {
-    let url = req.url.clone();
-    if url.trim().is_empty() {
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Missing url"
-        }));
-    }
+/// `GET /stats`: usage of the concurrency pools (and the global cap, if configured), jobs, the cache and the state
+/// of the cookie profiles.
+pub async fn stats(state: web::Data<AppState>) -> impl Responder {
+    let (active_jobs, total_jobs) = state.jobs.counts();
+    HttpResponse::Ok()
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .json(serde_json::json!({
+            "pools": {
+                "download": state.limiter.stats(),
+                "info": state.info_limiter.stats(),
+                "thumbnail": state.thumbnail_limiter.stats(),
+            },
+            "global": state.global_cap.as_ref().map(|g| g.stats()),
+            "jobs": {
+                "active": active_jobs,
+                "total": total_jobs,
+            },
+            "cache": state.cache.as_ref().map(|c| serde_json::json!({
+                "files": c.len(),
+                "bytes": c.total_bytes(),
+            })),
+            "cookies": state.cookie_pool.stats(),
+        }))
+}
 
-    let mode = req.mode.clone().unwrap_or_else(|| "progressive".to_string());
-    if mode != "progressive" && mode != "best" {
-        return HttpResponse::BadRequest().json(serde_json::json!({
-            "error": "Invalid mode (expected: progressive|best)"
-        }));
-    }
+/// `GET /health/cookies`: how the browser refreshes of each cookie profile are going. 503 when every profile's
+/// last refresh failed.
+pub async fn cookies_health(state: web::Data<AppState>) -> impl Responder {
+    let (status, profiles) = state.cookie_pool.health();
+    let mut resp = if status == "failing" {
+        HttpResponse::ServiceUnavailable()
+    } else {
+        HttpResponse::Ok()
+    };
+    resp.append_header((header::CACHE_CONTROL, "no-store"))
+        .json(serde_json::json!({
+            "status": status,
+            "refresh_url": state.config.cookies_refresh_url,
+            "profiles": profiles,
+        }))
+}
+
+#[derive(Deserialize)]
+pub struct CookieProfileQuery {
+    // Which cookie profile; defaults to the first one backed by a file.
+    pub profile: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CookieUploadQuery {
+    pub profile: Option<String>,
+    // Only check the jar, don't install it.
+    pub dry_run: Option<bool>,
+}
 
-    eprintln!("[STREAM] Request: mode={} url={}", mode, url);
+/// The cookie profile `/admin/cookies` works on, and its file.
+fn managed_cookie_file(
+    state: &AppState,
+    profile: Option<&str>,
+) -> Result<(String, std::path::PathBuf), Box<HttpResponse>> {
+    let pool = &state.cookie_pool;
+    let profile = match profile {
+        Some(name) => pool.get(name).ok_or_else(|| {
+            Box::new(HttpResponse::NotFound().json(serde_json::json!({
+                "error": format!("Unknown cookie profile: {}", name)
+            })))
+        })?,
+        None => pool
+            .profiles()
+            .iter()
+            .find(|p| p.source == "file")
+            .unwrap_or(&pool.profiles()[0]),
+    };
+    match &profile.file {
+        Some(file) if profile.source == "file" => Ok((profile.name.clone(), file.clone())),
+        _ => Err(Box::new(HttpResponse::Conflict().json(serde_json::json!({
+            "error": format!("Cookie profile {} reads cookies from the browser: there is no cookies file to manage", profile.name)
+        })))),
+    }
+}
 
-    let permit = match state.limiter.clone().try_acquire_owned() {
+/// `POST /admin/cookies`: check an uploaded Netscape cookies.txt (the request body) and, unless `?dry_run=true`,
+/// swap it in as the cookies file of the profile. The response describes the jar like `GET /admin/cookies`, plus warnings about
+/// missing or expired YouTube sign-in cookies.
+pub async fn upload_cookies(
+    query: web::Query<CookieUploadQuery>,
+    body: web::Bytes,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let (profile, path) = match managed_cookie_file(&state, query.profile.as_deref()) {
         Ok(p) => p,
+        Err(resp) => return *resp,
+    };
+    let text = match std::str::from_utf8(&body) {
+        Ok(t) => t,
         Err(_) => {
-            return HttpResponse::TooManyRequests().json(serde_json::json!({
-                "error": format!("Too many concurrent downloads (max: {})", state.config.max_concurrent_downloads)
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Cookies file is not valid UTF-8"
             }));
         }
     };
-
-    if let Err(e) = cookies::ensure_cookies(state.config.as_ref(), state.cookie_lock.as_ref()).await {
-        return HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": format!("Failed to refresh cookies: {}", e)
-        }));
-    }
-
-    let temp_dir = match tempfile::Builder::new().prefix("yt-dlp-stream-").tempdir() {
-        Ok(d) => d,
+    let jar = match cookiejar::parse(text) {
+        Ok(jar) if jar.is_empty() => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Cookies file contains no cookies"
+            }));
+        }
+        Ok(jar) => jar,
         Err(e) => {
-            return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": e.to_string()
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid cookies file: {}", e)
             }));
         }
     };
 
-    // New behavior: finish server-side download first, then stream the final file back (single request).
-    // We still keep cleanup on request end by capturing TempDir inside the response body stream.
-    let out_path = temp_dir.path().join("video.mp4");
-
-    let cfg = state.config.as_ref();
-    let mut cmd = match build_ytdlp_command(
-        cfg,
-        mode.as_str(),
-        url.as_str(),
-        out_path.to_string_lossy().as_ref(),
-    ) {
-        Ok(c) => c,
-        Err(msg) => {
-            return HttpResponse::BadRequest().json(serde_json::json!({
-                "error": msg
+    let now = chrono::Utc::now().timestamp();
+    let warnings = cookiejar::warnings(&jar, now);
+    let dry_run = query.dry_run.unwrap_or(false);
+    if !dry_run {
+        let _guard = state.cookie_lock.lock().await;
+        if let Err(e) = cookies::store_jar(&state.config, &path, &body) {
+            eprintln!("[COOKIES] Upload failed: {:#}", e);
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("{:#}", e)
             }));
         }
+        eprintln!(
+            "[COOKIES] Installed uploaded jar for {} ({} cookies, {} warning(s))",
+            profile,
+            jar.len(),
+            warnings.len()
+        );
+    }
+
+    let mut resp = cookiejar::summary(&jar, now);
+    resp["profile"] = serde_json::json!(profile);
+    resp["installed"] = serde_json::json!(!dry_run);
+    resp["warnings"] = serde_json::json!(warnings);
+    HttpResponse::Ok().json(resp)
+}
+
+/// `GET /admin/cookies`: the current jar's domains, cookie counts and expiry times. Cookie values are never shown.
+pub async fn cookies_summary(query: web::Query<CookieProfileQuery>, state: web::Data<AppState>) -> impl Responder {
+    let (profile, path) = match managed_cookie_file(&state, query.profile.as_deref()) {
+        Ok(p) => p,
+        Err(resp) => return *resp,
+    };
+    let (data, modified) = {
+        // A refresh rewrites the file in place.
+        let _guard = state.cookie_lock.lock().await;
+        let data = match tokio::fs::read(&path).await {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return HttpResponse::NotFound().json(serde_json::json!({
+                    "error": format!("Cookies file not found: {}", path.to_string_lossy())
+                }));
+            }
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": format!("Failed to read cookies file: {}", e)
+                }));
+            }
+        };
+        let modified = tokio::fs::metadata(&path)
+            .await
+            .and_then(|m| m.modified())
+            .ok()
+            .map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339());
+        (data, modified)
     };
 
-    let mut child = match cmd.spawn() {
-        Ok(c) => c,
+    let encrypted = cookiecrypt::is_encrypted(&data);
+    let text = match cookiecrypt::decode(data, state.config.cookies_key.as_ref()) {
+        Ok(t) => t,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to read cookies file: {}", e)
+            }));
+        }
+    };
+    let jar = match cookiejar::parse(&text) {
+        Ok(jar) => jar,
         Err(e) => {
             return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": format!("Failed to start yt-dlp: {}", e)
+                "error": format!("Current cookies file is invalid: {}", e)
             }));
         }
     };
-    if let Some(pid) = child.id() {
-        eprintln!("[STREAM] yt-dlp started (pid={})", pid);
+    let now = chrono::Utc::now().timestamp();
+    let mut resp = cookiejar::summary(&jar, now);
+    resp["profile"] = serde_json::json!(profile);
+    resp["path"] = serde_json::json!(path.to_string_lossy());
+    resp["modified"] = serde_json::json!(modified);
+    resp["encrypted"] = serde_json::json!(encrypted);
+    resp["warnings"] = serde_json::json!(cookiejar::warnings(&jar, now));
+    HttpResponse::Ok()
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .json(resp)
+}
+
+fn validate_progress_id(id: &str) -> Result<(), Box<HttpResponse>> {
+    if id.is_empty() || id.len() > 128 || util::sanitize_filename_component(id) != id {
+        return Err(Box::new(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid progress_id (expected 1-128 chars of [A-Za-z0-9._-])"
+        }))));
     }
+    Ok(())
+}
+
+fn register_progress(
+    state: &AppState,
+    id: &str,
+    rx: tokio::sync::watch::Receiver<ProgressEvent>,
+) -> Result<progress::Registration, Box<HttpResponse>> {
+    state.progress.register(id, rx).ok_or_else(|| {
+        Box::new(HttpResponse::Conflict().json(serde_json::json!({
+            "error": format!("progress_id already in use: {}", id)
+        })))
+    })
+}
 
-    // Capture stderr so we can return a useful error if yt-dlp fails.
-    let tail_buf: std::sync::Arc<tokio::sync::Mutex<VecDeque<String>>> =
-        std::sync::Arc::new(tokio::sync::Mutex::new(VecDeque::new()));
-    let stderr = match child.stderr.take() {
-        Some(s) => s,
+/// Serve the finished file of `job` (with Range/ETag support). The job is held by the body so its temp dir
+/// outlives the response even if it expires meanwhile.
+async fn serve_job_file(req: &HttpRequest, job: Arc<Job>) -> HttpResponse {
+    let path = match job.file() {
+        Some(p) => p,
         None => {
-            return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to capture yt-dlp stderr"
+            return HttpResponse::Gone().json(serde_json::json!({
+                "error": "Job file missing"
             }));
         }
     };
-    let tail_buf_clone = tail_buf.clone();
-    let stderr_task = tokio::spawn(async move { collect_stderr(stderr, tail_buf_clone).await });
-
-    // Wait for download completion. If the client disconnects during this wait, Actix will drop the handler future,
-    // which drops `child` (kill_on_drop) and `temp_dir` so we don't leak disk usage.
-    let status = match child.wait().await {
-        Ok(s) => s,
+    let content_type = util::content_type_for(&path);
+    let filename = download_filename(&job.url, &job.spec, &path);
+    let file = match ServedFile::open(path, content_type, filename).await {
+        Ok(f) => f,
         Err(e) => {
-            let tail = render_tail(&tail_buf).await;
-            return HttpResponse::BadGateway().json(serde_json::json!({
-                "error": format!("Failed waiting for yt-dlp: {}", e),
-                "stderr_tail": tail
+            return HttpResponse::Gone().json(serde_json::json!({
+                "error": format!("Job file missing: {}", e)
             }));
         }
     };
-    let _ = stderr_task.await;
 
-    if !status.success() {
-        let tail = render_tail(&tail_buf).await;
-        return HttpResponse::BadGateway().json(serde_json::json!({
-            "error": format!("yt-dlp exited with error (status={})", status),
-            "stderr_tail": tail
-        }));
+    let id = job.id.clone();
+    let format = job.format();
+    let mut resp = files::serve(req, file, job);
+    if let Some(f) = format {
+        insert_format_headers(&mut resp, f);
+    }
+    if let Ok(v) = HeaderValue::from_str(&format!("/jobs/{}/file", id)) {
+        resp.headers_mut().insert(header::CONTENT_LOCATION, v);
     }
+    if let Ok(v) = HeaderValue::from_str(&id) {
+        resp.headers_mut().insert(HeaderName::from_static("x-job-id"), v);
+    }
+    resp
+}
 
-    let meta = match tokio::fs::metadata(&out_path).await {
-        Ok(m) => m,
-        Err(e) => {
-            let tail = render_tail(&tail_buf).await;
-            return HttpResponse::BadGateway().json(serde_json::json!({
-                "error": format!("Download succeeded but output file missing: {}", e),
-                "stderr_tail": tail
-            }));
+/// Report the format yt-dlp downloaded in the `X-Format*` headers.
+fn insert_format_headers(resp: &mut HttpResponse, f: ChosenFormat) {
+    let headers = [
+        ("x-format-id", Some(f.format_id)),
+        ("x-format", Some(f.format).filter(|v| !v.is_empty())),
+        ("x-vcodec", f.vcodec),
+        ("x-acodec", f.acodec),
+    ];
+    for (name, value) in headers {
+        if let Some(v) = value.and_then(|v| HeaderValue::from_str(&v).ok()) {
+            resp.headers_mut().insert(HeaderName::from_static(name), v);
         }
-    };
-    if meta.len() == 0 {
-        let tail = render_tail(&tail_buf).await;
-        return HttpResponse::BadGateway().json(serde_json::json!({
-            "error": "Download succeeded but output file is empty",
-            "stderr_tail": tail
-        }));
     }
+}
+
+/// `Content-Disposition` filename for a download: the video id (or "video"), the clip range if any, and the
+/// extension of the file.
+pub fn download_filename(url: &str, spec: &DownloadSpec, path: &std::path::Path) -> String {
+    let mut stem = util::video_id_from_url(url).unwrap_or_else(|| "video".to_string());
+    if let Some(label) = spec.clip.label() {
+        stem = format!("{}_{}", stem, util::sanitize_filename_component(&label));
+    }
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}.{}", stem, util::sanitize_filename_component(ext)),
+        None => stem,
+    }
+}
 
-    eprintln!("[STREAM] Download completed; streaming {} bytes", meta.len());
+async fn serve_from_cache(req: &HttpRequest, state: &AppState, url: &str, spec: &DownloadSpec) -> Option<HttpResponse> {
+    let cache = state.cache.as_ref()?;
+    let key = ContentCache::key(url, &spec.variant())?;
+    let path = cache.get(&key)?;
+    let content_type = util::content_type_for(&path);
+    let filename = download_filename(url, spec, &path);
+    let file = ServedFile::open(path, content_type, filename).await.ok()?;
+
+    eprintln!("[CACHE] Hit: {}", key);
+    let mut resp = files::serve(req, file, ());
+    if let Some(f) = cache.format(&key) {
+        insert_format_headers(&mut resp, f);
+    }
+    resp.headers_mut()
+        .insert(HeaderName::from_static("x-cache"), HeaderValue::from_static("HIT"));
+    Some(resp)
+}
 
-    // Now stream the finished file back to the client. Capture TempDir so it is deleted when the response ends.
-    let body = stream! {
-        let _permit: OwnedSemaphorePermit = permit;
-        let _temp_dir: TempDir = temp_dir;
+/// Attach to a queued/running job for the same url and variant, or start a new one. Returns the job and whether it
+/// was already running. `cancel_when_abandoned` is for `/download` callers; otherwise an attached job is kept
+/// for at least `retention` and never cancelled because a `/download` waiter went away.
+fn start_or_attach(
+    state: &web::Data<AppState>,
+    url: &str,
+    spec: &DownloadSpec,
+    retention: Duration,
+    cancel_when_abandoned: bool,
+) -> Result<(Arc<Job>, bool), Box<HttpResponse>> {
+    let attach = |job: Arc<Job>| {
+        if !cancel_when_abandoned {
+            job.keep(retention);
+        }
+        Ok((job, true))
+    };
 
-        let mut file = match File::open(&out_path).await {
-            Ok(f) => f,
-            Err(e) => {
-                yield Err(e);
-                return;
-            }
-        };
+    if let Some(job) = state.jobs.find_active(url, &spec.variant()) {
+        return attach(job);
+    }
 
-        let mut buffer = vec![0u8; 64 * 1024];
-        loop {
-            match file.read(&mut buffer).await {
-                Ok(0) => break,
-                Ok(n) => yield Ok(bytes::Bytes::copy_from_slice(&buffer[..n])),
-                Err(e) => {
-                    yield Err(e);
-                    break;
-                }
-            }
+    // A free slot, or a place in the wait queue; the job then waits for its slot in the background.
+    let reservation = state
+        .limiter
+        .reserve()
+        .map_err(|rejection| Box::new(rejection.response(&state.limiter)))?;
+
+    let job = match Job::new(url.to_string(), spec, retention) {
+        Ok(j) => Arc::new(j),
+        Err(e) => {
+            return Err(Box::new(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            }))));
         }
     };
+    if cancel_when_abandoned {
+        job.cancel_when_abandoned();
+    }
 
-    let filename = util::video_id_from_url(&url).unwrap_or_else(|| "video".to_string());
-    HttpResponse::Ok()
-        .content_type("video/mp4")
-        .append_header((actix_web::http::header::CONTENT_LENGTH, meta.len().to_string()))
-        .append_header((
-            actix_web::http::header::CONTENT_DISPOSITION,
-            format!(r#"attachment; filename="{}.mp4""#, filename),
-        ))
-        .append_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
-        .streaming(body)
+    let cmd = match build_ytdlp_command(state.config.as_ref(), spec, url, job.out_dir()) {
+        Ok(c) => c,
+        Err(msg) => {
+            return Err(Box::new(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": msg
+            }))));
+        }
+    };
+
+    // Someone may have started the same download while we were setting up; join theirs and drop ours.
+    if let Some(existing) = state.jobs.insert_unless_active(job.clone()) {
+        return attach(existing);
+    }
+    jobs::start(job.clone(), cmd, reservation, state);
+    Ok((job, false))
 }
 
-pub async fn thumbnail(req: web::Json<ThumbnailRequest>, state: web::Data<AppState>) -> impl Responder {
+pub async fn stream_direct(
+    http_req: HttpRequest,
+    req: web::Json<StreamRequest>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    download(http_req, req.into_inner(), state).await
+}
+
+/// `POST /audio`: `/download` with mode=audio.
+pub async fn audio(http_req: HttpRequest, req: web::Json<StreamRequest>, state: web::Data<AppState>) -> impl Responder {
+    let mut req = req.into_inner();
+    match req.options.mode.as_deref() {
+        None | Some("audio") => req.options.mode = Some("audio".to_string()),
+        Some(_) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Invalid mode for /audio (omit it or use mode=audio)"
+            }));
+        }
+    }
+    download(http_req, req, state).await
+}
+
+async fn download(http_req: HttpRequest, req: StreamRequest, state: web::Data<AppState>) -> HttpResponse {
     let url = req.url.clone();
     if url.trim().is_empty() {
         return HttpResponse::BadRequest().json(serde_json::json!({
             "error": "Missing url"
         }));
     }
+    if let Err(rejection) = urlguard::check(state.config.as_ref(), &url).await {
+        return rejection.response();
+    }
+    // Equivalent urls (youtu.be/ID, /shorts/ID, m.youtube.com, ...) share jobs, cache entries and log lines.
+    let url = util::canonical_url(&url);
 
-    eprintln!("[THUMBNAIL] Request: url={}", url);
-
-    let permit = match state.limiter.clone().try_acquire_owned() {
-        Ok(p) => p,
-        Err(_) => {
-            return HttpResponse::TooManyRequests().json(serde_json::json!({
-                "error": format!("Too many concurrent downloads (max: {})", state.config.max_concurrent_downloads)
+    let spec = match req.options.validate() {
+        Ok(s) => s,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": msg
             }));
         }
     };
+    let variant = spec.variant();
 
-    if let Err(e) = cookies::ensure_cookies(state.config.as_ref(), state.cookie_lock.as_ref()).await {
-        return HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": format!("Failed to refresh cookies: {}", e)
+    let passthrough = req.passthrough.unwrap_or(false);
+    if passthrough && !spec.supports_passthrough() {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "passthrough is only supported with mode=progressive (without container or a merging format)"
         }));
     }
 
-    let temp_dir = match tempfile::Builder::new().prefix("yt-dlp-thumb-").tempdir() {
-        Ok(d) => d,
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": e.to_string()
-            }));
+    if let Some(id) = req.progress_id.as_deref() {
+        if let Err(resp) = validate_progress_id(id) {
+            return *resp;
         }
-    };
+    }
 
-    let out_template = temp_dir.path().join("thumbnail.%(ext)s");
+    eprintln!("[STREAM] Request: variant={} passthrough={} url={}", variant, passthrough, url);
 
-    let cfg = state.config.as_ref();
-    let mut cmd = build_ytdlp_base_command(cfg);
-    cmd.arg("--skip-download")
-        .arg("--write-thumbnail")
-        .arg("-o")
-        .arg(out_template.to_string_lossy().as_ref());
+    // Cache hits skip yt-dlp and the concurrency limit entirely.
+    if let Some(resp) = serve_from_cache(&http_req, &state, &url, &spec).await {
+        return resp;
+    }
 
-    // If ffmpeg is available, ask yt-dlp to convert to jpg for consistent output.
-    if let Some(ffmpeg) = find_ffmpeg(cfg) {
-        cmd.arg("--ffmpeg-location").arg(ffmpeg);
-        cmd.arg("--convert-thumbnails").arg("jpg");
+    // A client resuming an interrupted download (Range request) is served from the file we kept, if any.
+    if !passthrough && http_req.headers().contains_key(header::RANGE) {
+        if let Some(job) = state.jobs.find_completed(&url, &variant) {
+            eprintln!("[STREAM] Resuming from finished job {}", job.id);
+            return serve_job_file(&http_req, job).await;
+        }
     }
 
-    cmd.arg(url.as_str())
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::piped())
-        .kill_on_drop(true);
+    if passthrough {
+        // Optional progress reporting on GET /download/progress/{progress_id}; unregistered when the body ends.
+        let sink = progress::channel();
+        let registration = match req.progress_id.as_deref() {
+            Some(id) => match register_progress(&state, id, sink.subscribe()) {
+                Ok(r) => Some(r),
+                Err(resp) => return *resp,
+            },
+            None => None,
+        };
 
-    let mut child = match cmd.spawn() {
-        Ok(c) => c,
-        Err(e) => {
-            return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": format!("Failed to start yt-dlp: {}", e)
-            }));
+        // While waiting in the queue, the position is reported on the progress channel.
+        let permit = match state.limiter.acquire(Some(&sink)).await {
+            Ok(p) => p,
+            Err(rejection) => return rejection.response(&state.limiter),
+        };
+        return stream_passthrough(&state, &spec, url, permit, Some(sink), registration).await;
+    }
+
+    // Finish the download server-side first, then stream the final file back (single request).
+    // The download runs as a job so the finished file is kept for `download_grace_secs`, which lets an
+    // interrupted client resume with a Range request instead of starting over. An identical download that is
+    // already running is shared instead of started twice.
+    let job = match start_or_attach(
+        &state,
+        &url,
+        &spec,
+        Duration::from_secs(state.config.download_grace_secs),
+        true,
+    ) {
+        Ok((job, attached)) => {
+            if attached {
+                eprintln!("[STREAM] Attached to in-flight job {}", job.id);
+            }
+            job
         }
+        Err(resp) => return *resp,
+    };
+
+    let _registration = match req.progress_id.as_deref() {
+        Some(id) => match register_progress(&state, id, job.subscribe_progress()) {
+            Ok(r) => Some(r),
+            Err(resp) => return *resp,
+        },
+        None => None,
     };
-    if let Some(pid) = child.id() {
-        eprintln!("[THUMBNAIL] yt-dlp started (pid={})", pid);
+
+    // If the client disconnects during this wait, Actix drops the handler future and with it this guard. When it
+    // was the last `/download` waiting on the job, that cancels it (killing yt-dlp) and deletes its temp dir.
+    let _cancel = jobs::CancelOnDrop::new(state.jobs.clone(), job.clone());
+    let mut status_rx = job.subscribe_status();
+    let status = match status_rx.wait_for(|s| s.is_finished()).await {
+        Ok(s) => *s,
+        Err(_) => JobStatus::Cancelled,
+    };
+
+    match status {
+        JobStatus::Completed => {
+            eprintln!(
+                "[STREAM] Download completed; streaming {} bytes",
+                job.file_size().unwrap_or(0)
+            );
+            serve_job_file(&http_req, job).await
+        }
+        JobStatus::Cancelled => HttpResponse::BadGateway().json(serde_json::json!({
+            "error": "Download was cancelled"
+        })),
+        _ => {
+            let snap = job.snapshot();
+            HttpResponse::BadGateway().json(serde_json::json!({
+                "error": snap.error,
+                "stderr_tail": snap.stderr_tail
+            }))
+        }
     }
+}
 
-    let tail_buf: std::sync::Arc<tokio::sync::Mutex<VecDeque<String>>> =
-        std::sync::Arc::new(tokio::sync::Mutex::new(VecDeque::new()));
-    let stderr = match child.stderr.take() {
-        Some(s) => s,
-        None => {
+/// Forward yt-dlp's stdout to the client as it arrives. We hold back the response until the first chunk so that
+/// early failures (bad URL, auth, ...) still get a normal JSON error; after that, a yt-dlp failure aborts the
+/// chunked body before its terminating chunk, which clients see as a truncated transfer.
+async fn stream_passthrough(
+    state: &AppState,
+    spec: &DownloadSpec,
+    url: String,
+    permit: Permit,
+    progress: Option<ProgressSink>,
+    registration: Option<progress::Registration>,
+) -> HttpResponse {
+    let cfg = state.config.as_ref();
+    let mut rotation = Rotation::new(state.cookie_pool.clone());
+    if let Err(error) = rotation.next(cfg, &state.cookie_lock).await {
+        return HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": error
+        }));
+    }
+
+    // yt-dlp reports the format it picked here before writing any media (see `ChosenFormat`).
+    let format_dir = match tempfile::Builder::new().prefix("yt-dlp-stream-").tempdir() {
+        Ok(d) => d,
+        Err(e) => {
             return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to capture yt-dlp stderr"
+                "error": e.to_string()
             }));
         }
     };
-    let tail_buf_clone = tail_buf.clone();
-    let stderr_task = tokio::spawn(async move { collect_stderr(stderr, tail_buf_clone).await });
 
-    let status = match child.wait().await {
-        Ok(s) => s,
-        Err(e) => {
-            let tail = render_tail(&tail_buf).await;
-            return HttpResponse::BadGateway().json(serde_json::json!({
-                "error": format!("Failed waiting for yt-dlp: {}", e),
-                "stderr_tail": tail
-            }));
+    // Nothing has been sent before the first chunk, so a run that fails because of its cookies can still be
+    // retried with the next profile.
+    let (child, mut stdout, stderr_task, tail_buf, mut buffer, first) = loop {
+        // --print-to-file appends; a failed attempt must not leave its line behind.
+        let _ = std::fs::remove_file(format_dir.path().join(spec::FORMAT_FILE));
+        let mut cmd = match ytdlp::build_ytdlp_stream_command(cfg, spec, url.as_str(), format_dir.path()) {
+            Ok(c) => c,
+            Err(msg) => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": msg
+                }));
+            }
+        };
+        rotation.apply(&mut cmd);
+        let mut child = match cmd.spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": format!("Failed to start yt-dlp: {}", e)
+                }));
+            }
+        };
+        if let Some(pid) = child.id() {
+            eprintln!("[STREAM] yt-dlp started in passthrough mode (pid={})", pid);
         }
-    };
-    let _ = stderr_task.await;
 
-    if !status.success() {
+        let (mut stdout, stderr) = match (child.stdout.take(), child.stderr.take()) {
+            (Some(o), Some(e)) => (o, e),
+            _ => {
+                return HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "Failed to capture yt-dlp output"
+                }));
+            }
+        };
+        let tail_buf: std::sync::Arc<tokio::sync::Mutex<VecDeque<String>>> =
+            std::sync::Arc::new(tokio::sync::Mutex::new(VecDeque::new()));
+        let tail_buf_clone = tail_buf.clone();
+        let progress_clone = progress.clone();
+        let stderr_task = tokio::spawn(async move { collect_stderr(stderr, tail_buf_clone, progress_clone).await });
+
+        let mut buffer = vec![0u8; 64 * 1024];
+        let first = match stdout.read(&mut buffer).await {
+            Ok(n) => n,
+            Err(e) => {
+                return HttpResponse::BadGateway().json(serde_json::json!({
+                    "error": format!("Failed reading yt-dlp output: {}", e),
+                    "stderr_tail": render_tail(&tail_buf).await
+                }));
+            }
+        };
+        if first > 0 {
+            break (child, stdout, stderr_task, tail_buf, buffer, first);
+        }
+
+        let status = child.wait().await;
+        let _ = stderr_task.await;
         let tail = render_tail(&tail_buf).await;
+        let failed = !matches!(&status, Ok(s) if s.success());
+        if failed && rotation.retry_after(&tail) && rotation.next(cfg, &state.cookie_lock).await.is_ok() {
+            continue;
+        }
+        let error = match status {
+            Ok(s) if s.success() => "yt-dlp produced no output".to_string(),
+            Ok(s) => format!("yt-dlp exited with error (status={})", s),
+            Err(e) => format!("Failed waiting for yt-dlp: {}", e),
+        };
+        if let Some(p) = &progress {
+            p.send_replace(ProgressEvent {
+                status: "error".to_string(),
+                error: Some(error.clone()),
+                ..Default::default()
+            });
+        }
         return HttpResponse::BadGateway().json(serde_json::json!({
-            "error": format!("yt-dlp exited with error (status={})", status),
+            "error": error,
             "stderr_tail": tail
         }));
-    }
+    };
+    let chosen = ChosenFormat::read(format_dir.path());
 
-    // Find produced thumbnail file; prefer jpg/jpeg, then png, then webp.
-    let mut jpg: Option<std::path::PathBuf> = None;
-    let mut png: Option<std::path::PathBuf> = None;
-    let mut webp: Option<std::path::PathBuf> = None;
+    // The child lives inside the body stream: if the client disconnects, dropping the stream kills yt-dlp.
+    let body = stream! {
+        let _permit: Permit = permit;
+        let _registration = registration;
+        let _format_dir = format_dir;
+        // Owns the decrypted cookies copy (if any), which must outlive yt-dlp.
+        let _rotation = rotation;
+        let mut child = child;
+        let mut sent = first as u64;
+        yield Ok(bytes::Bytes::copy_from_slice(&buffer[..first]));
+
+        loop {
+            match stdout.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    sent += n as u64;
+                    yield Ok(bytes::Bytes::copy_from_slice(&buffer[..n]));
+                }
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+
+        let error = match child.wait().await {
+            Ok(s) if s.success() => None,
+            Ok(s) => Some(format!("yt-dlp exited with error (status={})", s)),
+            Err(e) => Some(format!("Failed waiting for yt-dlp: {}", e)),
+        };
+        let _ = stderr_task.await;
+
+        match error {
+            None => {
+                eprintln!("[STREAM] Passthrough completed ({} bytes)", sent);
+                if let Some(p) = &progress {
+                    p.send_replace(ProgressEvent {
+                        status: "finished".to_string(),
+                        percent: Some(100.0),
+                        downloaded_bytes: Some(sent),
+                        total_bytes: Some(sent),
+                        ..Default::default()
+                    });
+                }
+            }
+            Some(error) => {
+                eprintln!("[STREAM] Passthrough failed after {} bytes: {}\n{}", sent, error, render_tail(&tail_buf).await);
+                if let Some(p) = &progress {
+                    p.send_replace(ProgressEvent {
+                        status: "error".to_string(),
+                        error: Some(error.clone()),
+                        ..Default::default()
+                    });
+                }
+                yield Err(std::io::Error::other(error));
+            }
+        }
+    };
+
+    // Content-Type and extension follow the format yt-dlp picked; without its report we can't tell.
+    let ext = chosen.as_ref().and_then(|f| f.ext.clone()).unwrap_or_default();
+    let media = std::path::Path::new(ytdlp::OUTPUT_STEM).with_extension(&ext);
+    let filename = download_filename(&url, spec, &media);
+    let mut resp = HttpResponse::Ok()
+        .content_type(util::content_type_for_ext(&ext))
+        .append_header((
+            actix_web::http::header::CONTENT_DISPOSITION,
+            format!(r#"attachment; filename="{}""#, filename),
+        ))
+        .append_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+        .streaming(body);
+    if let Some(f) = chosen {
+        insert_format_headers(&mut resp, f);
+    }
+    resp
+}
+
+/// Error response that can be handed to every request sharing a coalesced operation.
+#[derive(Clone)]
+pub struct ErrorReply {
+    status: StatusCode,
+    body: serde_json::Value,
+}
+
+impl ErrorReply {
+    fn new(status: StatusCode, body: serde_json::Value) -> Self {
+        Self { status, body }
+    }
+
+    fn response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(&self.body)
+    }
+}
+
+/// A downloaded thumbnail. Shared by coalesced requests; the temp dir goes away with the last response.
+pub struct Thumbnail {
+    _temp_dir: TempDir,
+    path: std::path::PathBuf,
+    ext: String,
+    content_type: &'static str,
+    size: u64,
+}
+
+pub type ThumbnailResult = Result<Arc<Thumbnail>, ErrorReply>;
+pub type InfoResult = Result<Arc<serde_json::Value>, ErrorReply>;
+
+async fn fetch_thumbnail(state: web::Data<AppState>, url: String) -> ThumbnailResult {
+    let permit = match state.thumbnail_limiter.acquire(None).await {
+        Ok(p) => p,
+        Err(rejection) => {
+            return Err(ErrorReply::new(StatusCode::TOO_MANY_REQUESTS, serde_json::json!({
+                "error": rejection.message(&state.thumbnail_limiter)
+            })));
+        }
+    };
+
+    let mut rotation = Rotation::new(state.cookie_pool.clone());
+    if let Err(error) = rotation.next(state.config.as_ref(), &state.cookie_lock).await {
+        return Err(ErrorReply::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+            "error": error
+        })));
+    }
+
+    let temp_dir = match tempfile::Builder::new().prefix("yt-dlp-thumb-").tempdir() {
+        Ok(d) => d,
+        Err(e) => {
+            return Err(ErrorReply::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+                "error": e.to_string()
+            })));
+        }
+    };
+
+    let out_template = temp_dir.path().join("thumbnail.%(ext)s");
+
+    let cfg = state.config.as_ref();
+    loop {
+        let mut cmd = build_ytdlp_base_command(cfg);
+        cmd.arg("--skip-download")
+            .arg("--write-thumbnail")
+            .arg("-o")
+            .arg(out_template.to_string_lossy().as_ref());
+
+        // If ffmpeg is available, ask yt-dlp to convert to jpg for consistent output.
+        if let Some(ffmpeg) = find_ffmpeg(cfg) {
+            cmd.arg("--ffmpeg-location").arg(ffmpeg);
+            cmd.arg("--convert-thumbnails").arg("jpg");
+        }
+
+        cmd.arg(url.as_str())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true);
+        rotation.apply(&mut cmd);
+
+        let mut child = match cmd.spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(ErrorReply::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+                    "error": format!("Failed to start yt-dlp: {}", e)
+                })));
+            }
+        };
+        if let Some(pid) = child.id() {
+            eprintln!("[THUMBNAIL] yt-dlp started (pid={})", pid);
+        }
+
+        let tail_buf: std::sync::Arc<tokio::sync::Mutex<VecDeque<String>>> =
+            std::sync::Arc::new(tokio::sync::Mutex::new(VecDeque::new()));
+        let stderr = match child.stderr.take() {
+            Some(s) => s,
+            None => {
+                return Err(ErrorReply::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+                    "error": "Failed to capture yt-dlp stderr"
+                })));
+            }
+        };
+        let tail_buf_clone = tail_buf.clone();
+        let stderr_task = tokio::spawn(async move { collect_stderr(stderr, tail_buf_clone, None).await });
+
+        let status = match child.wait().await {
+            Ok(s) => s,
+            Err(e) => {
+                let tail = render_tail(&tail_buf).await;
+                return Err(ErrorReply::new(StatusCode::BAD_GATEWAY, serde_json::json!({
+                    "error": format!("Failed waiting for yt-dlp: {}", e),
+                    "stderr_tail": tail
+                })));
+            }
+        };
+        let _ = stderr_task.await;
+
+        if status.success() {
+            break;
+        }
+        let tail = render_tail(&tail_buf).await;
+        if rotation.retry_after(&tail) && rotation.next(cfg, &state.cookie_lock).await.is_ok() {
+            continue;
+        }
+        return Err(ErrorReply::new(StatusCode::BAD_GATEWAY, serde_json::json!({
+            "error": format!("yt-dlp exited with error (status={})", status),
+            "stderr_tail": tail
+        })));
+    }
+
+    // Find produced thumbnail file; prefer jpg/jpeg, then png, then webp.
+    let mut jpg: Option<std::path::PathBuf> = None;
+    let mut png: Option<std::path::PathBuf> = None;
+    let mut webp: Option<std::path::PathBuf> = None;
 
     let mut entries = match tokio::fs::read_dir(temp_dir.path()).await {
         Ok(e) => e,
         Err(e) => {
-            return HttpResponse::BadGateway().json(serde_json::json!({
+            return Err(ErrorReply::new(StatusCode::BAD_GATEWAY, serde_json::json!({
                 "error": format!("Failed to read thumbnail dir: {}", e)
-            }));
+            })));
         }
     };
     while let Ok(Some(entry)) = entries.next_entry().await {
@@ -450,18 +942,18 @@ pub async fn thumbnail(req: web::Json<ThumbnailRequest>, state: web::Data<AppSta
     let path = match path {
         Some(p) => p,
         None => {
-            return HttpResponse::BadGateway().json(serde_json::json!({
+            return Err(ErrorReply::new(StatusCode::BAD_GATEWAY, serde_json::json!({
                 "error": "No thumbnail file found"
-            }));
+            })));
         }
     };
 
     let meta = match tokio::fs::metadata(&path).await {
         Ok(m) => m,
         Err(e) => {
-            return HttpResponse::BadGateway().json(serde_json::json!({
+            return Err(ErrorReply::new(StatusCode::BAD_GATEWAY, serde_json::json!({
                 "error": format!("Thumbnail file missing: {}", e)
-            }));
+            })));
         }
     };
 
@@ -477,43 +969,130 @@ pub async fn thumbnail(req: web::Json<ThumbnailRequest>, state: web::Data<AppSta
         _ => "application/octet-stream",
     };
 
+    // The concurrency slot is released here; the file stays until the last response holding it is done.
+    drop(permit);
+    Ok(Arc::new(Thumbnail {
+        _temp_dir: temp_dir,
+        path,
+        ext,
+        content_type: ct,
+        size: meta.len(),
+    }))
+}
+
+pub async fn thumbnail(req: web::Json<ThumbnailRequest>, state: web::Data<AppState>) -> impl Responder {
+    let url = req.url.clone();
+    if url.trim().is_empty() {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Missing url"
+        }));
+    }
+    if let Err(rejection) = urlguard::check(state.config.as_ref(), &url).await {
+        return rejection.response();
+    }
+    let url = util::canonical_url(&url);
+
+    eprintln!("[THUMBNAIL] Request: url={}", url);
+
+    // Identical concurrent requests share one yt-dlp run.
+    let fetch = {
+        let state = state.clone();
+        let url = url.clone();
+        move || fetch_thumbnail(state, url)
+    };
+    let (result, shared) = match state.thumbnail_inflight.run(&url, fetch).await {
+        Some(r) => r,
+        None => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Thumbnail task failed"
+            }));
+        }
+    };
+    let thumb = match result {
+        Ok(t) => t,
+        Err(e) => return e.response(),
+    };
+
     let filename = util::video_id_from_url(&url).unwrap_or_else(|| "thumbnail".to_string());
-    let thumb_path = path.clone();
+    let content_type = thumb.content_type;
+    let size = thumb.size;
+    let disposition = format!(r#"attachment; filename="{}.{}""#, filename, thumb.ext);
+    let body = files::file_stream(thumb.path.clone(), 0, size, thumb);
+
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(content_type)
+        .append_header((actix_web::http::header::CONTENT_LENGTH, size.to_string()))
+        .append_header((actix_web::http::header::CONTENT_DISPOSITION, disposition))
+        .append_header((actix_web::http::header::CACHE_CONTROL, "no-store"));
+    if shared {
+        resp.append_header(("X-Coalesced", "1"));
+    }
+    resp.streaming(body)
+}
 
-    let body = stream! {
-        let _permit: OwnedSemaphorePermit = permit;
-        let _temp_dir: TempDir = temp_dir;
+async fn fetch_info(state: web::Data<AppState>, url: String) -> InfoResult {
+    let permit = match state.info_limiter.acquire(None).await {
+        Ok(p) => p,
+        Err(rejection) => {
+            return Err(ErrorReply::new(StatusCode::TOO_MANY_REQUESTS, serde_json::json!({
+                "error": rejection.message(&state.info_limiter)
+            })));
+        }
+    };
+
+    let mut rotation = Rotation::new(state.cookie_pool.clone());
+    if let Err(error) = rotation.next(state.config.as_ref(), &state.cookie_lock).await {
+        return Err(ErrorReply::new(StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({
+            "error": error
+        })));
+    }
+
+    // Keep the concurrency slot held while we run yt-dlp.
+    let _permit: Permit = permit;
 
-        let mut file = match File::open(&thumb_path).await {
-            Ok(f) => f,
+    let cfg = state.config.as_ref();
+    let out = loop {
+        let mut cmd = build_ytdlp_base_command(cfg);
+        cmd.arg("--no-playlist")
+            .arg("-J")
+            .arg(url.as_str())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true);
+        rotation.apply(&mut cmd);
+
+        let out = match cmd.output().await {
+            Ok(o) => o,
             Err(e) => {
-                yield Err(e);
-                return;
+                return Err(ErrorReply::new(StatusCode::BAD_GATEWAY, serde_json::json!({
+                    "error": format!("Failed to run yt-dlp: {}", e),
+                })));
             }
         };
+        if out.status.success() {
+            break out;
+        }
 
-        let mut buffer = vec![0u8; 64 * 1024];
-        loop {
-            match file.read(&mut buffer).await {
-                Ok(0) => break,
-                Ok(n) => yield Ok(bytes::Bytes::copy_from_slice(&buffer[..n])),
-                Err(e) => {
-                    yield Err(e);
-                    break;
-                }
-            }
+        let stderr_tail = String::from_utf8_lossy(&out.stderr).to_string();
+        if rotation.retry_after(&stderr_tail) && rotation.next(cfg, &state.cookie_lock).await.is_ok() {
+            continue;
         }
+        return Err(ErrorReply::new(StatusCode::BAD_GATEWAY, serde_json::json!({
+            "error": format!("yt-dlp exited with error (status={})", out.status),
+            "stderr_tail": stderr_tail
+        })));
     };
 
-    HttpResponse::Ok()
-        .content_type(ct)
-        .append_header((actix_web::http::header::CONTENT_LENGTH, meta.len().to_string()))
-        .append_header((
-            actix_web::http::header::CONTENT_DISPOSITION,
-            format!(r#"attachment; filename="{}.{}""#, filename, ext),
-        ))
-        .append_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
-        .streaming(body)
+    let v: serde_json::Value = match serde_json::from_slice(&out.stdout) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(ErrorReply::new(StatusCode::BAD_GATEWAY, serde_json::json!({
+                "error": format!("Failed to parse yt-dlp JSON: {}", e),
+            })));
+        }
+    };
+
+    Ok(Arc::new(v))
 }
 
 pub async fn info(req: web::Json<InfoRequest>, state: web::Data<AppState>) -> impl Responder {
@@ -523,73 +1102,526 @@ pub async fn info(req: web::Json<InfoRequest>, state: web::Data<AppState>) -> im
             "error": "Missing url"
         }));
     }
+    if let Err(rejection) = urlguard::check(state.config.as_ref(), &url).await {
+        return rejection.response();
+    }
+    let url = util::canonical_url(&url);
 
     let include_formats = req.include_formats.unwrap_or(false);
 
     eprintln!("[INFO] Request: url={} include_formats={}", url, include_formats);
 
-    let permit = match state.limiter.clone().try_acquire_owned() {
-        Ok(p) => p,
-        Err(_) => {
-            return HttpResponse::TooManyRequests().json(serde_json::json!({
-                "error": format!("Too many concurrent downloads (max: {})", state.config.max_concurrent_downloads)
+    // Identical concurrent requests share one yt-dlp run; include_formats is applied per request afterwards.
+    let fetch = {
+        let state = state.clone();
+        let url = url.clone();
+        move || fetch_info(state, url)
+    };
+    let (result, shared) = match state.info_inflight.run(&url, fetch).await {
+        Some(r) => r,
+        None => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Info task failed"
             }));
         }
     };
+    let mut v = match result {
+        Ok(v) => (*v).clone(),
+        Err(e) => return e.response(),
+    };
 
-    if let Err(e) = cookies::ensure_cookies(state.config.as_ref(), state.cookie_lock.as_ref()).await {
-        return HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": format!("Failed to refresh cookies: {}", e)
+    if !include_formats {
+        if let Some(obj) = v.as_object_mut() {
+            obj.remove("formats");
+            obj.remove("requested_formats");
+        }
+    }
+
+    let mut resp = HttpResponse::Ok();
+    resp.content_type("application/json")
+        .append_header((actix_web::http::header::CACHE_CONTROL, "no-store"));
+    if shared {
+        resp.append_header(("X-Coalesced", "1"));
+    }
+    resp.json(v)
+}
+
+pub async fn subtitles(req: web::Json<SubtitlesRequest>, state: web::Data<AppState>) -> impl Responder {
+    let url = req.url.clone();
+    if url.trim().is_empty() {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Missing url"
         }));
     }
+    if let Err(rejection) = urlguard::check(state.config.as_ref(), &url).await {
+        return rejection.response();
+    }
+    let url = util::canonical_url(&url);
 
-    let cfg = state.config.as_ref();
-    let mut cmd = build_ytdlp_base_command(cfg);
-    cmd.arg("--no-playlist")
-        .arg("-J")
-        .arg(url.as_str())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .kill_on_drop(true);
+    if req.list.unwrap_or(false) {
+        eprintln!("[SUBTITLES] List request: url={}", url);
 
-    // Keep the concurrency slot held while we run yt-dlp.
-    let _permit: OwnedSemaphorePermit = permit;
+        // Same lookup as /info, so a concurrent /info for this url is shared.
+        let fetch = {
+            let state = state.clone();
+            let url = url.clone();
+            move || fetch_info(state, url)
+        };
+        let result = match state.info_inflight.run(&url, fetch).await {
+            Some((r, _)) => r,
+            None => {
+                return HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "Info task failed"
+                }));
+            }
+        };
+        return match result {
+            Ok(v) => HttpResponse::Ok()
+                .append_header((header::CACHE_CONTROL, "no-store"))
+                .json(subtitles::list(&v)),
+            Err(e) => e.response(),
+        };
+    }
+
+    let lang = req.lang.clone().unwrap_or_else(|| "en".to_string());
+    if !subtitles::valid_lang(&lang) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Invalid lang (expected a language code like en or pt-BR)"
+        }));
+    }
+    let format = match req.format.as_deref() {
+        Some(f) => match SubtitleFormat::parse(f) {
+            Some(f) => f,
+            None => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "Invalid format (expected: vtt|srt|json3)"
+                }));
+            }
+        },
+        None => SubtitleFormat::Vtt,
+    };
+    let kind = match req.auto {
+        Some(true) => TrackKind::Auto,
+        Some(false) => TrackKind::Manual,
+        None => TrackKind::Any,
+    };
 
-    let out = match cmd.output().await {
-        Ok(o) => o,
+    eprintln!(
+        "[SUBTITLES] Request: url={} lang={} format={} kind={:?}",
+        url,
+        lang,
+        format.as_str(),
+        kind
+    );
+
+    let temp_dir = match tempfile::Builder::new().prefix("yt-dlp-subs-").tempdir() {
+        Ok(d) => d,
         Err(e) => {
-            return HttpResponse::BadGateway().json(serde_json::json!({
-                "error": format!("Failed to run yt-dlp: {}", e),
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+    let mut cmd = match subtitles::build_command(state.config.as_ref(), &url, &lang, format, kind, temp_dir.path()) {
+        Ok(c) => c,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": msg
             }));
         }
     };
 
-    if !out.status.success() {
+    let _permit = match state.info_limiter.acquire(None).await {
+        Ok(p) => p,
+        Err(rejection) => return rejection.response(&state.info_limiter),
+    };
+    let mut rotation = Rotation::new(state.cookie_pool.clone());
+    if let Err(error) = rotation.next(state.config.as_ref(), &state.cookie_lock).await {
+        return HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": error
+        }));
+    }
+
+    let stderr_tail = loop {
+        rotation.apply(&mut cmd);
+        let out = match cmd.output().await {
+            Ok(o) => o,
+            Err(e) => {
+                return HttpResponse::BadGateway().json(serde_json::json!({
+                    "error": format!("Failed to run yt-dlp: {}", e)
+                }));
+            }
+        };
         let stderr_tail = String::from_utf8_lossy(&out.stderr).to_string();
+        if out.status.success() {
+            break stderr_tail;
+        }
+        if rotation.retry_after(&stderr_tail) && rotation.next(state.config.as_ref(), &state.cookie_lock).await.is_ok() {
+            if let Ok(c) = subtitles::build_command(state.config.as_ref(), &url, &lang, format, kind, temp_dir.path()) {
+                cmd = c;
+                continue;
+            }
+        }
         return HttpResponse::BadGateway().json(serde_json::json!({
             "error": format!("yt-dlp exited with error (status={})", out.status),
             "stderr_tail": stderr_tail
         }));
+    };
+
+    // yt-dlp succeeds (with a warning) when the language or format isn't available.
+    let path = match subtitles::find_file(temp_dir.path(), format) {
+        Some(p) => p,
+        None => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "error": format!("No subtitles for lang={} in format={}", lang, format.as_str()),
+                "stderr_tail": stderr_tail
+            }));
+        }
+    };
+    let body = match tokio::fs::read(&path).await {
+        Ok(b) => b,
+        Err(e) => {
+            return HttpResponse::BadGateway().json(serde_json::json!({
+                "error": format!("Subtitle file missing: {}", e)
+            }));
+        }
+    };
+
+    let filename = util::video_id_from_url(&url).unwrap_or_else(|| "subtitles".to_string());
+    let disposition = format!(
+        r#"attachment; filename="{}.{}.{}""#,
+        filename,
+        util::sanitize_filename_component(&lang),
+        format.as_str()
+    );
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .append_header((header::CONTENT_DISPOSITION, disposition))
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .body(body)
+}
+
+pub async fn playlist(req: web::Json<PlaylistRequest>, state: web::Data<AppState>) -> impl Responder {
+    let url = req.url.clone();
+    if url.trim().is_empty() {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Missing url"
+        }));
+    }
+    if let Err(rejection) = urlguard::check(state.config.as_ref(), &url).await {
+        return rejection.response();
+    }
+    let query = match req.validate(state.config.as_ref()) {
+        Ok(q) => q,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": msg
+            }));
+        }
+    };
+
+    eprintln!(
+        "[PLAYLIST] Request: url={} page={} page_size={} limit={}",
+        url, query.page, query.page_size, query.limit
+    );
+
+    let mut cmd = match query.command(state.config.as_ref(), &url) {
+        Some(c) => c,
+        // Past `limit`: an empty page, no need to ask yt-dlp.
+        None => return HttpResponse::Ok().json(query.page(None)),
+    };
+
+    let _permit = match state.info_limiter.acquire(None).await {
+        Ok(p) => p,
+        Err(rejection) => return rejection.response(&state.info_limiter),
+    };
+    let mut rotation = Rotation::new(state.cookie_pool.clone());
+    if let Err(error) = rotation.next(state.config.as_ref(), &state.cookie_lock).await {
+        return HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": error
+        }));
     }
 
-    let mut v: serde_json::Value = match serde_json::from_slice(&out.stdout) {
+    let out = loop {
+        rotation.apply(&mut cmd);
+        let out = match cmd.output().await {
+            Ok(o) => o,
+            Err(e) => {
+                return HttpResponse::BadGateway().json(serde_json::json!({
+                    "error": format!("Failed to run yt-dlp: {}", e)
+                }));
+            }
+        };
+        if out.status.success() {
+            break out;
+        }
+        let stderr_tail = String::from_utf8_lossy(&out.stderr).to_string();
+        if rotation.retry_after(&stderr_tail) && rotation.next(state.config.as_ref(), &state.cookie_lock).await.is_ok() {
+            if let Some(c) = query.command(state.config.as_ref(), &url) {
+                cmd = c;
+                continue;
+            }
+        }
+        return HttpResponse::BadGateway().json(serde_json::json!({
+            "error": format!("yt-dlp exited with error (status={})", out.status),
+            "stderr_tail": stderr_tail
+        }));
+    };
+    let v: serde_json::Value = match serde_json::from_slice(&out.stdout) {
         Ok(v) => v,
         Err(e) => {
             return HttpResponse::BadGateway().json(serde_json::json!({
-                "error": format!("Failed to parse yt-dlp JSON: {}", e),
+                "error": format!("Failed to parse yt-dlp JSON: {}", e)
             }));
         }
     };
 
-    if !include_formats {
-        if let Some(obj) = v.as_object_mut() {
-            obj.remove("formats");
-            obj.remove("requested_formats");
+    HttpResponse::Ok()
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .json(query.page(Some(&v)))
+}
+
+/// `POST /batch`: download several urls with shared options and stream back a ZIP that grows as items finish.
+/// The response starts right away, so per-item failures are reported in the archive's manifest, not as a status.
+pub async fn batch(req: web::Json<BatchRequest>, state: web::Data<AppState>) -> impl Responder {
+    let batch = match req
+        .into_inner()
+        .validate(state.config.batch_max_items, state.config.max_concurrent_downloads)
+    {
+        Ok(b) => b,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": msg
+            }));
         }
+    };
+
+    eprintln!(
+        "[BATCH] Request: items={} variant={} parallelism={}",
+        batch.urls.len(),
+        batch.spec.variant(),
+        batch.parallelism
+    );
+
+    // The archive is written into one end of a pipe by a background task and streamed from the other. Dropping
+    // the body (client disconnect) aborts the task, which cancels the downloads nobody else is waiting on.
+    let (reader, writer) = tokio::io::duplex(256 * 1024);
+    let task = AbortOnDrop(tokio::spawn(batch::run(state.clone(), batch, writer)));
+    let body = stream! {
+        let mut task = task;
+        let mut reader = reader;
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            match reader.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(n) => yield Ok(bytes::Bytes::copy_from_slice(&buffer[..n])),
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+        // EOF also happens when the writer gave up; don't let a truncated archive look complete.
+        match (&mut task.0).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                eprintln!("[BATCH] Failed writing archive: {}", e);
+                yield Err(e);
+            }
+            Err(e) => yield Err(std::io::Error::other(e.to_string())),
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/zip")
+        .append_header((header::CONTENT_DISPOSITION, r#"attachment; filename="batch.zip""#))
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .streaming(body)
+}
+
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub async fn create_job(req: web::Json<StreamRequest>, state: web::Data<AppState>) -> impl Responder {
+    let url = req.url.clone();
+    if url.trim().is_empty() {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Missing url"
+        }));
+    }
+    if let Err(rejection) = urlguard::check(state.config.as_ref(), &url).await {
+        return rejection.response();
+    }
+    let url = util::canonical_url(&url);
+
+    let spec = match req.options.validate() {
+        Ok(s) => s,
+        Err(msg) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": msg
+            }));
+        }
+    };
+    let variant = spec.variant();
+
+    let retention = Duration::from_secs(state.config.job_retention_secs);
+
+    // Cache hits complete without yt-dlp or a concurrency permit.
+    let cached = match (&state.cache, ContentCache::key(&url, &variant)) {
+        (Some(cache), Some(key)) => cache.get(&key),
+        _ => None,
+    };
+    if let Some(path) = cached {
+        let job = match Job::new(url.clone(), &spec, retention) {
+            Ok(j) => Arc::new(j),
+            Err(e) => {
+                return HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": e.to_string()
+                }));
+            }
+        };
+        eprintln!("[JOB] {} created from cache: variant={} url={}", job.id, variant, url);
+        state.jobs.insert(job.clone());
+        jobs::start_from_cache(job.clone(), path);
+        return job_accepted(&job);
+    }
+
+    let job = match start_or_attach(&state, &url, &spec, retention, false) {
+        Ok((job, attached)) => {
+            if attached {
+                eprintln!("[JOB] {} shared with new request: variant={} url={}", job.id, variant, url);
+            } else {
+                eprintln!("[JOB] {} created: variant={} url={}", job.id, variant, url);
+            }
+            job
+        }
+        Err(resp) => return *resp,
+    };
+
+    job_accepted(&job)
+}
+
+fn job_accepted(job: &Job) -> HttpResponse {
+    let queue_position = job.queue_position();
+    let mut resp = HttpResponse::Accepted();
+    resp.append_header((header::LOCATION, format!("/jobs/{}", job.id)));
+    if let Some(p) = queue_position {
+        resp.append_header(("X-Queue-Position", p.to_string()));
+    }
+    resp.json(serde_json::json!({
+        "id": job.id,
+        "status": job.status(),
+        "queue_position": queue_position,
+        "status_url": format!("/jobs/{}", job.id),
+        "file_url": format!("/jobs/{}/file", job.id)
+    }))
+}
+
+pub async fn job_status(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    match state.jobs.get(&id) {
+        Some(job) => HttpResponse::Ok()
+            .append_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(job.snapshot()),
+        None => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Job not found: {}", id)
+        })),
+    }
+}
+
+pub async fn cancel_job(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    match state.jobs.remove(&id) {
+        Some(job) => {
+            eprintln!("[JOB] {} removed", id);
+            HttpResponse::Ok().json(job.snapshot())
+        }
+        None => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Job not found: {}", id)
+        })),
+    }
+}
+
+pub async fn job_file(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let job = match state.jobs.get(&id) {
+        Some(j) => j,
+        None => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "error": format!("Job not found: {}", id)
+            }));
+        }
+    };
+
+    if job.file_size().is_none() {
+        let status = job.status();
+        let body = serde_json::json!({
+            "error": format!("Job has no file (status={})", status.as_str())
+        });
+        return if status == JobStatus::Failed || status == JobStatus::Cancelled {
+            HttpResponse::Gone().json(body)
+        } else {
+            HttpResponse::Conflict().json(body)
+        };
     }
 
+    serve_job_file(&http_req, job).await
+}
+
+pub async fn download_progress(path: web::Path<String>, state: web::Data<AppState>) -> impl Responder {
+    let id = path.into_inner();
+    let mut rx = match state.jobs.get(&id) {
+        Some(job) => job.subscribe_progress(),
+        None => match state.progress.subscribe(&id) {
+            Some(rx) => rx,
+            None => {
+                return HttpResponse::NotFound().json(serde_json::json!({
+                    "error": format!("No download in progress with id: {}", id)
+                }));
+            }
+        },
+    };
+
+    let body = stream! {
+        let mut keepalive = tokio::time::interval(std::time::Duration::from_secs(15));
+        keepalive.tick().await;
+        loop {
+            let ev = rx.borrow_and_update().clone();
+            let data = serde_json::to_string(&ev).unwrap_or_else(|_| "{}".to_string());
+            yield Ok::<_, std::io::Error>(bytes::Bytes::from(format!("event: progress\ndata: {}\n\n", data)));
+            if ev.is_terminal() {
+                break;
+            }
+
+            // Wait for the next update, sending an SSE comment now and then so proxies keep the connection open.
+            loop {
+                tokio::select! {
+                    changed = rx.changed() => {
+                        if changed.is_err() {
+                            // Sender gone without a terminal event (e.g. the /download request was dropped).
+                            return;
+                        }
+                        break;
+                    }
+                    _ = keepalive.tick() => {
+                        yield Ok(bytes::Bytes::from_static(b": keepalive\n\n"));
+                    }
+                }
+            }
+        }
+    };
+
     HttpResponse::Ok()
-        .content_type("application/json")
-        .append_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
-        .json(v)
+        .content_type("text/event-stream")
+        .append_header((actix_web::http::header::CACHE_CONTROL, "no-cache"))
+        .streaming(body)
 }
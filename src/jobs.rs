@@ -0,0 +1,506 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tempfile::TempDir;
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::cache::ContentCache;
+use crate::cookiepool::Rotation;
+use crate::limiter::Reservation;
+use crate::progress::{self, ProgressEvent, ProgressSink};
+use crate::spec::{ChosenFormat, DownloadSpec};
+use crate::state::AppState;
+use crate::ytdlp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct JobInner {
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    size: Option<u64>,
+    file: Option<PathBuf>,
+    format: Option<ChosenFormat>,
+    error: Option<String>,
+    stderr_tail: Option<String>,
+}
+
+/// Serializable view of a job, returned by the `/jobs` endpoints.
+#[derive(Debug, Serialize)]
+pub struct JobSnapshot {
+    pub id: String,
+    pub url: String,
+    pub mode: String,
+    pub variant: String,
+    pub status: JobStatus,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub size: Option<u64>,
+    pub format: Option<ChosenFormat>,
+    // Position in the wait queue while the job is queued behind max_concurrent_downloads.
+    pub queue_position: Option<usize>,
+    pub error: Option<String>,
+    pub stderr_tail: Option<String>,
+}
+
+/// A background download. The job owns its temp dir (and, through the runner task, the yt-dlp child),
+/// so the work survives the HTTP request that created it and is cleaned up when the job is removed.
+pub struct Job {
+    pub id: String,
+    pub url: String,
+    pub mode: String,
+    pub spec: DownloadSpec,
+    // `DownloadSpec::variant()`: jobs with the same url and variant produce the same file.
+    pub variant: String,
+    pub created_at: DateTime<Utc>,
+    // How long (in seconds) the finished file is kept around (for GET /jobs/{id}/file and resumed downloads).
+    retention_secs: AtomicU64,
+    // Set for `/download` jobs: once every waiting request has gone away, there is nobody left to deliver to.
+    cancel_when_abandoned: AtomicBool,
+    waiters: AtomicUsize,
+    // Kept alive for as long as the job is registered; dropping it deletes the downloaded file.
+    temp_dir: TempDir,
+    inner: Mutex<JobInner>,
+    status: watch::Sender<JobStatus>,
+    progress: ProgressSink,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Job {
+    pub fn new(url: String, spec: &DownloadSpec, retention: Duration) -> std::io::Result<Self> {
+        let temp_dir = tempfile::Builder::new().prefix("yt-dlp-job-").tempdir()?;
+        let (status, _) = watch::channel(JobStatus::Queued);
+        Ok(Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            url,
+            mode: spec.mode.clone(),
+            variant: spec.variant(),
+            spec: spec.clone(),
+            created_at: Utc::now(),
+            retention_secs: AtomicU64::new(retention.as_secs()),
+            cancel_when_abandoned: AtomicBool::new(false),
+            waiters: AtomicUsize::new(0),
+            temp_dir,
+            inner: Mutex::new(JobInner::default()),
+            status,
+            progress: progress::channel(),
+            task: Mutex::new(None),
+        })
+    }
+
+    /// Directory yt-dlp writes the download into.
+    pub fn out_dir(&self) -> &Path {
+        self.temp_dir.path()
+    }
+
+    pub fn status(&self) -> JobStatus {
+        *self.status.borrow()
+    }
+
+    /// Cancel this job when the last `/download` request waiting on it disconnects.
+    pub fn cancel_when_abandoned(&self) {
+        self.cancel_when_abandoned.store(true, Ordering::SeqCst);
+    }
+
+    /// Someone (e.g. `POST /jobs`) wants this job to outlive its waiters: never cancel it on abandonment and keep
+    /// the file for at least `retention`.
+    pub fn keep(&self, retention: Duration) {
+        self.cancel_when_abandoned.store(false, Ordering::SeqCst);
+        self.retention_secs.fetch_max(retention.as_secs(), Ordering::SeqCst);
+    }
+
+    pub fn subscribe_status(&self) -> watch::Receiver<JobStatus> {
+        self.status.subscribe()
+    }
+
+    pub fn subscribe_progress(&self) -> watch::Receiver<ProgressEvent> {
+        self.progress.subscribe()
+    }
+
+    /// Size of the finished file, if the job completed.
+    pub fn file_size(&self) -> Option<u64> {
+        if self.status() != JobStatus::Completed {
+            return None;
+        }
+        self.inner.lock().unwrap().size
+    }
+
+    /// Path of the finished file, if the job completed.
+    pub fn file(&self) -> Option<PathBuf> {
+        if self.status() != JobStatus::Completed {
+            return None;
+        }
+        self.inner.lock().unwrap().file.clone()
+    }
+
+    /// Format yt-dlp picked, once the job completed (not known for cache hits).
+    pub fn format(&self) -> Option<ChosenFormat> {
+        self.inner.lock().unwrap().format.clone()
+    }
+
+    /// Current position in the wait queue, while the job waits for a download slot.
+    pub fn queue_position(&self) -> Option<usize> {
+        if self.status() != JobStatus::Queued {
+            return None;
+        }
+        self.progress.borrow().queue_position
+    }
+
+    pub fn snapshot(&self) -> JobSnapshot {
+        let queue_position = self.queue_position();
+        let inner = self.inner.lock().unwrap();
+        JobSnapshot {
+            id: self.id.clone(),
+            url: self.url.clone(),
+            mode: self.mode.clone(),
+            variant: self.variant.clone(),
+            status: self.status(),
+            created_at: self.created_at,
+            started_at: inner.started_at,
+            finished_at: inner.finished_at,
+            size: inner.size,
+            format: inner.format.clone(),
+            queue_position,
+            error: inner.error.clone(),
+            stderr_tail: inner.stderr_tail.clone(),
+        }
+    }
+
+    /// Stop the runner (killing yt-dlp via kill_on_drop) if it is still going.
+    pub fn cancel(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        if !self.status().is_finished() {
+            self.inner.lock().unwrap().finished_at = Some(Utc::now());
+            self.status.send_replace(JobStatus::Cancelled);
+            self.progress.send_replace(ProgressEvent::status("cancelled"));
+        }
+    }
+
+    fn mark_running(&self) {
+        self.inner.lock().unwrap().started_at = Some(Utc::now());
+        self.status.send_replace(JobStatus::Running);
+        self.progress.send_replace(ProgressEvent::status("downloading"));
+    }
+
+    fn mark_completed(&self, file: PathBuf, size: u64, format: Option<ChosenFormat>) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.finished_at = Some(Utc::now());
+            inner.size = Some(size);
+            inner.file = Some(file);
+            inner.format = format;
+        }
+        self.status.send_replace(JobStatus::Completed);
+        self.progress.send_replace(ProgressEvent {
+            status: "finished".to_string(),
+            percent: Some(100.0),
+            downloaded_bytes: Some(size),
+            total_bytes: Some(size),
+            ..Default::default()
+        });
+    }
+
+    fn mark_failed(&self, failure: ytdlp::Failure) {
+        let error = failure.error.clone();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.finished_at = Some(Utc::now());
+            inner.error = Some(failure.error);
+            inner.stderr_tail = failure.stderr_tail;
+        }
+        self.status.send_replace(JobStatus::Failed);
+        self.progress.send_replace(ProgressEvent {
+            status: "error".to_string(),
+            error: Some(error),
+            ..Default::default()
+        });
+    }
+
+    fn expired(&self) -> bool {
+        if !self.status().is_finished() {
+            return false;
+        }
+        let finished_at = match self.inner.lock().unwrap().finished_at {
+            Some(t) => t,
+            None => return false,
+        };
+        let age = Utc::now().signed_duration_since(finished_at);
+        let retention = Duration::from_secs(self.retention_secs.load(Ordering::SeqCst));
+        age.to_std().map(|a| a > retention).unwrap_or(false)
+    }
+}
+
+/// Run the prepared yt-dlp command for `job` in the background, once `reservation` turns into a concurrency slot
+/// (the job stays queued until then, reporting its queue position as progress). The slot is held until yt-dlp
+/// exits. Successful downloads are added to the content cache when one is configured. A run that fails because of
+/// its cookies is retried with the next cookie profile.
+pub fn start(job: Arc<Job>, cmd: Command, reservation: Reservation, state: &AppState) {
+    let runner = job.clone();
+    let cfg = state.config.clone();
+    let cookie_lock = state.cookie_lock.clone();
+    let cookie_pool = state.cookie_pool.clone();
+    let cache = state.cache.clone();
+    let limiter = state.limiter.clone();
+    if let Some(position) = reservation.position() {
+        eprintln!("[JOB] {} queued at position {}", job.id, position);
+        job.progress.send_replace(ProgressEvent {
+            queue_position: Some(position),
+            ..ProgressEvent::status("queued")
+        });
+    }
+    let handle = tokio::spawn(async move {
+        let _permit = match reservation.acquire(Some(&runner.progress)).await {
+            Ok(p) => p,
+            Err(rejection) => {
+                eprintln!("[JOB] {} gave up waiting for a download slot", runner.id);
+                runner.mark_failed(ytdlp::Failure {
+                    error: rejection.message(&limiter),
+                    stderr_tail: None,
+                });
+                return;
+            }
+        };
+
+        let mut rotation = Rotation::new(cookie_pool);
+        if let Err(error) = rotation.next(cfg.as_ref(), &cookie_lock).await {
+            runner.mark_failed(ytdlp::Failure {
+                error,
+                stderr_tail: None,
+            });
+            return;
+        }
+
+        runner.mark_running();
+        eprintln!("[JOB] {} started: variant={} url={}", runner.id, runner.variant, runner.url);
+
+        let mut cmd = cmd;
+        loop {
+            rotation.apply(&mut cmd);
+            let failure = match ytdlp::run_to_file(
+                cfg.as_ref(),
+                &runner.spec,
+                cmd,
+                runner.out_dir(),
+                Some(runner.progress.clone()),
+            )
+            .await
+            {
+                Ok((file, size)) => {
+                    let format = ChosenFormat::read(runner.out_dir());
+                    eprintln!(
+                        "[JOB] {} completed ({} bytes, format={})",
+                        runner.id,
+                        size,
+                        format.as_ref().map(|f| f.format_id.as_str()).unwrap_or("?")
+                    );
+                    runner.mark_completed(file.clone(), size, format.clone());
+                    if let (Some(cache), Some(key)) = (cache, ContentCache::key(&runner.url, &runner.variant)) {
+                        if let Err(e) = cache.insert(&key, &file, format.as_ref()).await {
+                            eprintln!("[CACHE] Failed to store {}: {:#}", key, e);
+                        }
+                    }
+                    return;
+                }
+                Err(failure) => failure,
+            };
+
+            // A bot check or rejected cookies: run again from scratch with the next cookie profile.
+            let retry = rotation.retry_after(failure.stderr_tail.as_deref().unwrap_or_default())
+                && rotation.next(cfg.as_ref(), &cookie_lock).await.is_ok();
+            let rebuilt = if retry {
+                clear_dir(runner.out_dir()).await;
+                ytdlp::build_ytdlp_command(cfg.as_ref(), &runner.spec, &runner.url, runner.out_dir()).ok()
+            } else {
+                None
+            };
+            match rebuilt {
+                Some(c) => cmd = c,
+                None => {
+                    eprintln!("[JOB] {} failed: {}", runner.id, failure.error);
+                    runner.mark_failed(failure);
+                    return;
+                }
+            }
+        }
+    });
+    *job.task.lock().unwrap() = Some(handle);
+}
+
+// Remove what a failed run left in a job's directory, so the next run starts clean.
+async fn clear_dir(dir: &Path) {
+    if let Ok(mut entries) = tokio::fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let removed = match entry.file_type().await {
+                Ok(t) if t.is_dir() => tokio::fs::remove_dir_all(&path).await,
+                _ => tokio::fs::remove_file(&path).await,
+            };
+            if let Err(e) = removed {
+                eprintln!("[JOB] Failed to remove {}: {}", path.to_string_lossy(), e);
+            }
+        }
+    }
+}
+
+/// Complete `job` from a cached file without running yt-dlp (and without a concurrency permit).
+pub fn start_from_cache(job: Arc<Job>, cached: PathBuf) {
+    let runner = job.clone();
+    let handle = tokio::spawn(async move {
+        runner.mark_running();
+        let ext = cached.extension().and_then(|e| e.to_str()).unwrap_or("bin");
+        let file = runner.out_dir().join(format!("{}.{}", ytdlp::OUTPUT_STEM, ext));
+        let linked = match tokio::fs::hard_link(&cached, &file).await {
+            Ok(()) => Ok(()),
+            Err(_) => tokio::fs::copy(&cached, &file).await.map(|_| ()),
+        };
+        let result = match linked {
+            Ok(()) => tokio::fs::metadata(&file).await.map(|m| m.len()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(size) => {
+                eprintln!("[JOB] {} served from cache ({} bytes)", runner.id, size);
+                runner.mark_completed(file, size, None);
+            }
+            Err(e) => runner.mark_failed(ytdlp::Failure {
+                error: format!("Failed to copy cached file: {}", e),
+                stderr_tail: None,
+            }),
+        }
+    });
+    *job.task.lock().unwrap() = Some(handle);
+}
+
+#[derive(Default)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+}
+
+impl JobRegistry {
+    pub fn insert(&self, job: Arc<Job>) {
+        self.jobs.lock().unwrap().insert(job.id.clone(), job);
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Remove a job, cancelling it if it is still running.
+    pub fn remove(&self, id: &str) -> Option<Arc<Job>> {
+        let job = self.jobs.lock().unwrap().remove(id)?;
+        job.cancel();
+        Some(job)
+    }
+
+    /// Queued or running job for the same url and variant, which a new request can attach to.
+    pub fn find_active(&self, url: &str, variant: &str) -> Option<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .find(|j| j.url == url && j.variant == variant && !j.status().is_finished())
+            .cloned()
+    }
+
+    /// Insert `job` unless an identical job is already in flight; in that case the existing job is returned
+    /// (and `job` is not registered). Checking and inserting under one lock avoids racing duplicates.
+    pub fn insert_unless_active(&self, job: Arc<Job>) -> Option<Arc<Job>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(existing) = jobs
+            .values()
+            .find(|j| j.url == job.url && j.variant == job.variant && !j.status().is_finished())
+        {
+            return Some(existing.clone());
+        }
+        jobs.insert(job.id.clone(), job);
+        None
+    }
+
+    /// Most recent completed job for the same url and variant, if its file is still around.
+    pub fn find_completed(&self, url: &str, variant: &str) -> Option<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|j| j.url == url && j.variant == variant && j.status() == JobStatus::Completed)
+            .max_by_key(|j| j.created_at)
+            .cloned()
+    }
+
+    /// (queued or running, total) registered jobs.
+    pub fn counts(&self) -> (usize, usize) {
+        let jobs = self.jobs.lock().unwrap();
+        let active = jobs.values().filter(|j| !j.status().is_finished()).count();
+        (active, jobs.len())
+    }
+
+    /// Drop finished jobs whose retention period has passed; returns how many were removed.
+    pub fn purge_expired(&self) -> usize {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        jobs.retain(|_, job| !job.expired());
+        before - jobs.len()
+    }
+}
+
+/// Marks a request as waiting on a job. When the last waiter of a `cancel_when_abandoned` job is dropped before
+/// the job finished, the job is removed (and thereby cancelled). Used by `/download`, where disconnected clients
+/// should still stop the download unless another request is sharing it.
+pub struct CancelOnDrop {
+    registry: Arc<JobRegistry>,
+    job: Arc<Job>,
+}
+
+impl CancelOnDrop {
+    pub fn new(registry: Arc<JobRegistry>, job: Arc<Job>) -> Self {
+        job.waiters.fetch_add(1, Ordering::SeqCst);
+        Self { registry, job }
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        let remaining = self.job.waiters.fetch_sub(1, Ordering::SeqCst) - 1;
+        if remaining == 0
+            && self.job.cancel_when_abandoned.load(Ordering::SeqCst)
+            && !self.job.status().is_finished()
+        {
+            eprintln!("[JOB] {} abandoned by client; cancelling", self.job.id);
+            self.registry.remove(&self.job.id);
+        }
+    }
+}
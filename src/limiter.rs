@@ -0,0 +1,497 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_web::HttpResponse;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::sync::{AcquireError, Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::progress::{ProgressEvent, ProgressSink};
+
+/// Optional cap shared by all pools: every running operation also holds its pool's `weight` units of it, so
+/// e.g. a few downloads can be made to count as much as many metadata lookups.
+pub struct GlobalCap {
+    semaphore: Arc<Semaphore>,
+    max: u32,
+}
+
+impl GlobalCap {
+    pub fn new(max: u32) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max as usize)),
+            max,
+        }
+    }
+
+    pub fn stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "max_weight": self.max,
+            "in_use": self.max as usize - self.semaphore.available_permits(),
+        })
+    }
+}
+
+/// Concurrency limit for one kind of yt-dlp run (downloads, info lookups, thumbnails), with an optional bounded
+/// wait queue in front of it. With `queue_max = 0` a request that finds every slot taken is rejected right away;
+/// otherwise up to `queue_max` requests wait (in arrival order, for at most `queue_wait`) and can follow their
+/// position in the queue.
+pub struct Limiter {
+    // What is being limited, for error messages ("downloads", "info requests", ...).
+    what: &'static str,
+    semaphore: Arc<Semaphore>,
+    max: usize,
+    global: Option<Arc<GlobalCap>>,
+    weight: u32,
+    queue_max: usize,
+    queue_wait: Duration,
+    // Tickets of the requests waiting for a slot, oldest first. Each ticket is registered with the semaphore
+    // (which is FIFO) while this lock is held, so slots are handed out in the same order.
+    queue: Mutex<VecDeque<u64>>,
+    next_ticket: AtomicU64,
+    // Woken whenever someone leaves the queue, so the others can report their new position.
+    moved: Notify,
+}
+
+/// A running operation's slot in its pool (and its share of the global cap, if any). Released on drop.
+pub struct Permit {
+    _pool: OwnedSemaphorePermit,
+    _global: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    // Every slot of the pool is taken and queueing is disabled.
+    Busy,
+    // The pool has room but the global cap doesn't, and queueing is disabled.
+    GlobalBusy,
+    QueueFull,
+    TimedOut,
+}
+
+impl Rejection {
+    pub fn message(self, limiter: &Limiter) -> String {
+        match self {
+            Rejection::Busy => format!("Too many concurrent {} (max: {})", limiter.what, limiter.max),
+            Rejection::GlobalBusy => format!(
+                "Too many concurrent operations (global cap: {})",
+                limiter.global.as_ref().map(|g| g.max).unwrap_or(0)
+            ),
+            Rejection::QueueFull => format!(
+                "Too many concurrent {} (max: {}) and the wait queue is full (max: {})",
+                limiter.what, limiter.max, limiter.queue_max
+            ),
+            Rejection::TimedOut => format!(
+                "Timed out after {}s waiting for a slot (max concurrent {}: {})",
+                limiter.queue_wait.as_secs(),
+                limiter.what,
+                limiter.max
+            ),
+        }
+    }
+
+    /// The 429 response for this rejection.
+    pub fn response(self, limiter: &Limiter) -> HttpResponse {
+        HttpResponse::TooManyRequests().json(serde_json::json!({
+            "error": self.message(limiter)
+        }))
+    }
+}
+
+/// A place in line: either a slot that was free right away, or a position in the wait queue.
+pub enum Reservation {
+    Ready(Permit),
+    Queued(Ticket),
+}
+
+/// A queued request. Dropping it gives up the place in the queue.
+pub struct Ticket {
+    limiter: Arc<Limiter>,
+    id: u64,
+    // The wait for a pool slot, already registered with the semaphore; taken by whoever waits on the ticket.
+    pool: Option<BoxFuture<'static, Result<OwnedSemaphorePermit, AcquireError>>>,
+}
+
+impl Limiter {
+    pub fn new(
+        what: &'static str,
+        max: usize,
+        global: Option<(Arc<GlobalCap>, u32)>,
+        queue_max: usize,
+        queue_wait: Duration,
+    ) -> Self {
+        let (global, weight) = match global {
+            Some((g, w)) => (Some(g), w),
+            None => (None, 0),
+        };
+        Self {
+            what,
+            semaphore: Arc::new(Semaphore::new(max)),
+            max,
+            global,
+            weight,
+            queue_max,
+            queue_wait,
+            queue: Mutex::new(VecDeque::new()),
+            next_ticket: AtomicU64::new(0),
+            moved: Notify::new(),
+        }
+    }
+
+    fn try_take(&self) -> Result<Permit, Rejection> {
+        let pool = self.semaphore.clone().try_acquire_owned().map_err(|_| Rejection::Busy)?;
+        let global = match &self.global {
+            Some(g) => Some(
+                g.semaphore
+                    .clone()
+                    .try_acquire_many_owned(self.weight)
+                    .map_err(|_| Rejection::GlobalBusy)?,
+            ),
+            None => None,
+        };
+        Ok(Permit {
+            _pool: pool,
+            _global: global,
+        })
+    }
+
+    /// Add a ticket at the end of the queue. A tokio semaphore queues a waiter when its acquire future is first
+    /// polled, so the future is polled here, under the queue lock, rather than when someone starts waiting on it.
+    fn enqueue(self: &Arc<Self>, queue: &mut VecDeque<u64>) -> Ticket {
+        let id = self.next_ticket.fetch_add(1, Ordering::SeqCst);
+        queue.push_back(id);
+        let mut pool = self.semaphore.clone().acquire_owned().boxed();
+        if let Poll::Ready(result) = pool.poll_unpin(&mut Context::from_waker(futures::task::noop_waker_ref())) {
+            pool = futures::future::ready(result).boxed();
+        }
+        Ticket {
+            limiter: self.clone(),
+            id,
+            pool: Some(pool),
+        }
+    }
+
+    // Add the share of the global cap (if any) to a pool slot.
+    async fn with_global(&self, pool: OwnedSemaphorePermit) -> Permit {
+        let global = match &self.global {
+            Some(g) => Some(
+                g.semaphore
+                    .clone()
+                    .acquire_many_owned(self.weight)
+                    .await
+                    .expect("limiter semaphore is never closed"),
+            ),
+            None => None,
+        };
+        Permit {
+            _pool: pool,
+            _global: global,
+        }
+    }
+
+    /// Take a free slot or a place in the queue, without waiting. Fails when neither is available.
+    pub fn reserve(self: &Arc<Self>) -> Result<Reservation, Rejection> {
+        let mut queue = self.queue.lock().unwrap();
+        // Nobody may skip ahead of requests already waiting.
+        let busy = if queue.is_empty() {
+            match self.try_take() {
+                Ok(permit) => return Ok(Reservation::Ready(permit)),
+                Err(rejection) => rejection,
+            }
+        } else {
+            Rejection::Busy
+        };
+        if self.queue_max == 0 {
+            return Err(busy);
+        }
+        if queue.len() >= self.queue_max {
+            return Err(Rejection::QueueFull);
+        }
+        Ok(Reservation::Queued(self.enqueue(&mut queue)))
+    }
+
+    /// `reserve` followed by `Reservation::acquire`.
+    pub async fn acquire(self: &Arc<Self>, progress: Option<&ProgressSink>) -> Result<Permit, Rejection> {
+        self.reserve()?.acquire(progress).await
+    }
+
+    /// Wait for a slot for as long as it takes, bypassing the queue limits. For work that is already bounded
+    /// elsewhere (the items of a `/batch` request). The wait still takes its place in the queue.
+    pub async fn acquire_unbounded(self: &Arc<Self>) -> Permit {
+        let mut ticket = {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.is_empty() {
+                if let Ok(permit) = self.try_take() {
+                    return permit;
+                }
+            }
+            self.enqueue(&mut queue)
+        };
+        ticket.take().await
+    }
+
+    /// Current usage, for `GET /stats`.
+    pub fn stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "max": self.max,
+            "active": self.max.saturating_sub(self.semaphore.available_permits()),
+            "queued": self.queue.lock().unwrap().len(),
+            "queue_max": self.queue_max,
+            "weight": self.global.as_ref().map(|_| self.weight),
+        })
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        self.queue.lock().unwrap().iter().position(|t| *t == id).map(|p| p + 1)
+    }
+}
+
+impl Reservation {
+    /// 1-based position in the wait queue at the time of the call (None when a slot was free).
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            Reservation::Ready(_) => None,
+            Reservation::Queued(ticket) => ticket.limiter.position(ticket.id),
+        }
+    }
+
+    /// Wait for the slot. While queued, the current position is published on `progress` as a "queued" event.
+    pub async fn acquire(self, progress: Option<&ProgressSink>) -> Result<Permit, Rejection> {
+        let mut ticket = match self {
+            Reservation::Ready(permit) => return Ok(permit),
+            Reservation::Queued(ticket) => ticket,
+        };
+        let (limiter, id) = (ticket.limiter.clone(), ticket.id);
+
+        let acquire = ticket.take();
+        tokio::pin!(acquire);
+        let deadline = tokio::time::sleep(limiter.queue_wait);
+        tokio::pin!(deadline);
+
+        loop {
+            // Register for the wakeup before reading the position, so a move in between isn't missed.
+            let moved = limiter.moved.notified();
+            tokio::pin!(moved);
+            moved.as_mut().enable();
+            if let (Some(p), Some(position)) = (progress, limiter.position(id)) {
+                p.send_replace(ProgressEvent {
+                    queue_position: Some(position),
+                    ..ProgressEvent::status("queued")
+                });
+            }
+
+            tokio::select! {
+                permit = &mut acquire => return Ok(permit),
+                _ = &mut deadline => return Err(Rejection::TimedOut),
+                _ = &mut moved => {}
+            }
+        }
+    }
+}
+
+impl Ticket {
+    /// Wait for the pool slot and the global cap. The ticket keeps its place in the queue until it is dropped.
+    fn take(&mut self) -> impl std::future::Future<Output = Permit> + 'static {
+        let pool = self.pool.take().expect("ticket is waited on once");
+        let limiter = self.limiter.clone();
+        async move {
+            let pool = pool.await.expect("limiter semaphore is never closed");
+            limiter.with_global(pool).await
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut queue = self.limiter.queue.lock().unwrap();
+        if let Some(i) = queue.iter().position(|t| *t == self.id) {
+            queue.remove(i);
+        }
+        drop(queue);
+        self.limiter.moved.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max: usize, global: Option<(Arc<GlobalCap>, u32)>, queue_max: usize, wait_secs: u64) -> Arc<Limiter> {
+        Arc::new(Limiter::new("downloads", max, global, queue_max, Duration::from_secs(wait_secs)))
+    }
+
+    fn ready(reservation: Result<Reservation, Rejection>) -> Permit {
+        match reservation {
+            Ok(Reservation::Ready(permit)) => permit,
+            Ok(Reservation::Queued(_)) => panic!("queued"),
+            Err(rejection) => panic!("rejected: {:?}", rejection),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queued_requests_are_admitted_in_order() {
+        let limiter = limiter(1, None, 10, 600);
+        let first = ready(limiter.reserve());
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for i in 1..=3 {
+            let reservation = limiter.reserve().unwrap();
+            assert_eq!(reservation.position(), Some(i));
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _permit = reservation.acquire(None).await.unwrap();
+                tx.send(i).unwrap();
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            });
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(limiter.stats()["queued"], 3);
+
+        drop(first);
+        let mut order = Vec::new();
+        for _ in 0..3 {
+            order.push(rx.recv().await.unwrap());
+        }
+        assert_eq!(order, [1, 2, 3]);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(limiter.stats()["queued"], 0);
+        assert_eq!(limiter.stats()["active"], 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slots_follow_ticket_order_not_waiting_order() {
+        let limiter = limiter(1, None, 10, 600);
+        let first = ready(limiter.reserve());
+        let reservations: Vec<_> = (1..=3).map(|_| limiter.reserve().unwrap()).collect();
+        // A batch item waiting without queue limits still takes its place in line.
+        let unbounded = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire_unbounded().await })
+        };
+        tokio::task::yield_now().await;
+        assert_eq!(limiter.stats()["queued"], 4);
+        assert_eq!(limiter.reserve().unwrap().position(), Some(5));
+
+        // Start waiting in reverse: the slots still go out by ticket.
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for (i, reservation) in reservations.into_iter().enumerate().rev() {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _permit = reservation.acquire(None).await.unwrap();
+                tx.send(i + 1).unwrap();
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            });
+            tokio::task::yield_now().await;
+        }
+
+        drop(first);
+        let mut order = Vec::new();
+        for _ in 0..3 {
+            order.push(rx.recv().await.unwrap());
+        }
+        assert_eq!(order, [1, 2, 3]);
+        let permit = unbounded.await.unwrap();
+        assert_eq!(limiter.stats()["queued"], 0);
+        drop(permit);
+        assert_eq!(limiter.stats()["active"], 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn newcomers_queue_behind_waiting_requests() {
+        let limiter = limiter(1, None, 10, 600);
+        let first = ready(limiter.reserve());
+        let waiting = limiter.reserve().unwrap();
+        drop(first);
+        // The slot is free, but the queued request hasn't taken it yet.
+        let late = limiter.reserve().unwrap();
+        assert_eq!(late.position(), Some(2));
+        let permit = waiting.acquire(None).await.unwrap();
+        assert_eq!(late.position(), Some(1));
+        drop(permit);
+        late.acquire(None).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn without_a_queue_busy_pools_reject_right_away() {
+        let limiter = limiter(1, None, 0, 600);
+        let _held = ready(limiter.reserve());
+        let rejection = match limiter.reserve() {
+            Err(rejection) => rejection,
+            Ok(_) => panic!("reserved a busy pool"),
+        };
+        assert_eq!(rejection, Rejection::Busy);
+        assert_eq!(rejection.message(&limiter), "Too many concurrent downloads (max: 1)");
+        assert_eq!(rejection.response(&limiter).status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limiter.acquire(None).await.err(), Some(Rejection::Busy));
+        assert_eq!(limiter.stats()["queued"], 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_queues_reject_and_waits_time_out() {
+        let limiter = limiter(1, None, 2, 30);
+        let _held = ready(limiter.reserve());
+        let a = limiter.reserve().unwrap();
+        let _b = limiter.reserve().unwrap();
+        assert_eq!(limiter.reserve().err(), Some(Rejection::QueueFull));
+
+        let progress: ProgressSink = Arc::new(tokio::sync::watch::channel(ProgressEvent::default()).0);
+        let started = tokio::time::Instant::now();
+        assert_eq!(a.acquire(Some(&progress)).await.err(), Some(Rejection::TimedOut));
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+        assert_eq!(progress.borrow().status, "queued");
+        assert_eq!(progress.borrow().queue_position, Some(1));
+        // The timed-out request left the queue, making room for another.
+        assert_eq!(limiter.stats()["queued"], 1);
+        assert!(matches!(limiter.reserve(), Ok(Reservation::Queued(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn global_cap_blocks_across_pools() {
+        let global = Arc::new(GlobalCap::new(4));
+        let downloads = limiter(5, Some((global.clone(), 3)), 0, 600);
+        let info = limiter(5, Some((global.clone(), 1)), 0, 600);
+
+        let download = ready(downloads.reserve());
+        let lookup = ready(info.reserve());
+        assert_eq!(global.stats()["in_use"], 4);
+        // Both pools have free slots, but the cap is used up.
+        assert_eq!(info.reserve().err(), Some(Rejection::GlobalBusy));
+        assert_eq!(downloads.reserve().err(), Some(Rejection::GlobalBusy));
+        assert_eq!(downloads.stats()["active"], 1, "the pool slot of a rejected request is given back");
+
+        drop(download);
+        assert_eq!(global.stats()["in_use"], 1);
+        let lookups: Vec<Permit> = (0..3).map(|_| ready(info.reserve())).collect();
+        assert_eq!(downloads.reserve().err(), Some(Rejection::GlobalBusy));
+
+        drop(lookups);
+        drop(lookup);
+        assert_eq!(global.stats()["in_use"], 0);
+        let _download = ready(downloads.reserve());
+        assert_eq!(global.stats()["in_use"], 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_for_the_global_cap_releases_the_pool_slot_on_timeout() {
+        let global = Arc::new(GlobalCap::new(3));
+        let downloads = limiter(2, Some((global.clone(), 3)), 1, 10);
+        let info = limiter(5, Some((global.clone(), 1)), 0, 600);
+
+        let lookup = ready(info.reserve());
+        // The download pool is free, so the request waits in the queue for the global cap only.
+        let queued = downloads.reserve().unwrap();
+        assert_eq!(queued.position(), Some(1));
+        assert_eq!(queued.acquire(None).await.err(), Some(Rejection::TimedOut));
+        assert_eq!(downloads.stats()["active"], 0);
+        assert_eq!(global.stats()["in_use"], 1);
+
+        let queued = downloads.reserve().unwrap();
+        let waiter = tokio::spawn(async move { queued.acquire(None).await.map(|_| ()) });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        drop(lookup);
+        assert_eq!(waiter.await.unwrap(), Ok(()));
+        assert_eq!(global.stats()["in_use"], 0);
+        assert_eq!(downloads.stats()["active"], 0);
+    }
+}
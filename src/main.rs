@@ -2,14 +2,32 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use actix_web::{web, App, HttpServer};
-use tokio::sync::{Mutex as AsyncMutex, Semaphore};
 use tokio::time;
 
+mod auth;
+mod batch;
+mod cache;
+mod chapters;
+mod coalesce;
 mod config;
+mod cookiecrypt;
+mod cookiejar;
+mod cookiepool;
 mod cookies;
+mod files;
 mod handlers;
+mod jobs;
+mod limiter;
+mod playlist;
+mod progress;
+mod ratelimit;
+mod spec;
 mod state;
+mod subtitles;
+mod urlguard;
 mod util;
+mod ytdlp;
+mod zip;
 
 use crate::state::AppState;
 
@@ -19,8 +37,7 @@ async fn main() -> std::io::Result<()> {
 
     let cfg_path = std::env::args()
         .skip_while(|a| a != "--config")
-        .skip(1)
-        .next()
+        .nth(1)
         .unwrap_or_else(|| "config.toml".to_string());
 
     let cfg = match config::AppConfig::load(&cfg_path) {
@@ -31,28 +48,91 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
+    if let Err(e) = cookies::secure_jars(&cfg) {
+        eprintln!("[COOKIES] {:#}", e);
+        std::process::exit(1);
+    }
+    if cfg.cookies_key.is_some() {
+        eprintln!("[COOKIES] Cookies files are encrypted at rest");
+    }
+
+    let cache = match &cfg.cache_dir {
+        Some(dir) => match cache::ContentCache::open(
+            dir,
+            cfg.cache_max_bytes,
+            Duration::from_secs(cfg.cache_ttl_secs),
+        ) {
+            Ok(c) => {
+                eprintln!(
+                    "[CACHE] Using {} ({} file(s), {} bytes)",
+                    dir.to_string_lossy(),
+                    c.len(),
+                    c.total_bytes()
+                );
+                Some(Arc::new(c))
+            }
+            Err(e) => {
+                eprintln!("[CACHE] Failed to open cache: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     println!("========================================");
     println!("  YouTube Download Service");
     println!("  http://{}", cfg.listen_addr);
     println!("========================================");
     println!();
 
-    let state = web::Data::new(AppState {
-        limiter: Arc::new(Semaphore::new(cfg.max_concurrent_downloads)),
-        cookie_lock: Arc::new(AsyncMutex::new(())),
-        config: Arc::new(cfg),
-    });
+    if cfg.api_keys.is_empty() {
+        eprintln!("[AUTH] No api_keys configured: every endpoint is open to anyone who can reach {}", cfg.listen_addr);
+    } else {
+        eprintln!("[AUTH] {} API key(s) configured", cfg.api_keys.len());
+    }
+
+    let state = web::Data::new(AppState::new(cfg, cache));
 
     // Keep cookies warm in the background.
     {
         let cookie_lock = state.cookie_lock.clone();
+        let cookie_pool = state.cookie_pool.clone();
         let cfg = state.config.clone();
         tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(300)); // check every 5 minutes
+            // Check every 5 minutes; a profile whose refreshes keep failing is retried less and less often.
+            let mut interval = time::interval(Duration::from_secs(300));
+            loop {
+                interval.tick().await;
+                for (profile, health) in cookie_pool.refresh_health() {
+                    if let Err(e) = cookies::ensure_cookies(cfg.as_ref(), profile, health, cookie_lock.as_ref()).await {
+                        eprintln!("[COOKIES] Background refresh of {} failed: {}", profile.name, e);
+                    }
+                }
+            }
+        });
+    }
+
+    // Drop finished jobs (and their files), expired cache entries and idle rate-limit clients once their time is up.
+    {
+        let jobs = state.jobs.clone();
+        let cache = state.cache.clone();
+        let rate_limiter = state.rate_limiter.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(60));
             loop {
                 interval.tick().await;
-                if let Err(e) = cookies::ensure_cookies(cfg.as_ref(), cookie_lock.as_ref()).await {
-                    eprintln!("[COOKIES] Background refresh failed: {}", e);
+                let removed = jobs.purge_expired();
+                if removed > 0 {
+                    eprintln!("[JOB] Cleaned up {} expired job(s)", removed);
+                }
+                if let Some(cache) = &cache {
+                    let removed = cache.purge_expired();
+                    if removed > 0 {
+                        eprintln!("[CACHE] Removed {} expired file(s)", removed);
+                    }
+                }
+                if let Some(rate_limiter) = &rate_limiter {
+                    rate_limiter.purge_idle();
                 }
             }
         });
@@ -61,14 +141,46 @@ async fn main() -> std::io::Result<()> {
     let bind_addr = state.config.listen_addr.clone();
     HttpServer::new(move || {
         App::new()
-            .wrap(actix_web::middleware::Logger::default())
+            // Outermost first: log, then authenticate, then count against the client's rate limits.
+            .wrap(actix_web::middleware::from_fn(ratelimit::enforce))
+            .wrap(actix_web::middleware::from_fn(auth::require_key))
+            .wrap(
+                actix_web::middleware::Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T key=%{key}xi"#)
+                    .custom_request_replace("key", auth::log_key_name),
+            )
             .app_data(state.clone())
-            .service(web::resource("/").route(web::get().to(handlers::index)))
-            .service(web::resource("/download").route(web::post().to(handlers::stream_direct)))
-            .service(web::resource("/thumbnail").route(web::post().to(handlers::thumbnail)))
-            .service(web::resource("/info").route(web::post().to(handlers::info)))
+            .configure(routes)
     })
     .bind(bind_addr.as_str())?
     .run()
     .await
 }
+
+/// Every endpoint of the service.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/").route(web::get().to(handlers::index)))
+        .service(web::resource("/stats").route(web::get().to(handlers::stats)))
+        .service(web::resource("/health/cookies").route(web::get().to(handlers::cookies_health)))
+        .service(
+            web::resource("/admin/cookies")
+                .route(web::get().to(handlers::cookies_summary))
+                .route(web::post().to(handlers::upload_cookies)),
+        )
+        .service(web::resource("/download").route(web::post().to(handlers::stream_direct)))
+        .service(web::resource("/audio").route(web::post().to(handlers::audio)))
+        .service(web::resource("/thumbnail").route(web::post().to(handlers::thumbnail)))
+        .service(web::resource("/info").route(web::post().to(handlers::info)))
+        .service(web::resource("/batch").route(web::post().to(handlers::batch)))
+        .service(web::resource("/playlist").route(web::post().to(handlers::playlist)))
+        .service(web::resource("/subtitles").route(web::post().to(handlers::subtitles)))
+        .service(
+            web::resource("/download/progress/{id}").route(web::get().to(handlers::download_progress)),
+        )
+        .service(web::resource("/jobs").route(web::post().to(handlers::create_job)))
+        .service(
+            web::resource("/jobs/{id}")
+                .route(web::get().to(handlers::job_status))
+                .route(web::delete().to(handlers::cancel_job)),
+        )
+        .service(web::resource("/jobs/{id}/file").route(web::get().to(handlers::job_file)));
+}
@@ -0,0 +1,196 @@
+use chrono::NaiveDate;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::config::AppConfig;
+use crate::ytdlp::build_ytdlp_playlist_command;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Deserialize)]
+pub struct PlaylistRequest {
+    pub url: String,
+    // 1-based page number (default 1) and entries per page (default 50, max 500).
+    pub page: Option<usize>,
+    pub page_size: Option<usize>,
+    // Only look at the first N entries of the playlist (capped by playlist_max_entries).
+    pub limit: Option<usize>,
+    // Keep entries uploaded in this range (inclusive), "YYYY-MM-DD" or "YYYYMMDD".
+    pub date_after: Option<String>,
+    pub date_before: Option<String>,
+}
+
+/// Validated `/playlist` query.
+pub struct PlaylistQuery {
+    pub page: usize,
+    pub page_size: usize,
+    pub limit: usize,
+    pub date_after: Option<NaiveDate>,
+    pub date_before: Option<NaiveDate>,
+}
+
+fn parse_date(s: &str, name: &str) -> Result<NaiveDate, String> {
+    let s = s.trim();
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y%m%d"))
+        .map_err(|_| format!("Invalid {} (expected YYYY-MM-DD or YYYYMMDD)", name))
+}
+
+impl PlaylistRequest {
+    pub fn validate(&self, cfg: &AppConfig) -> Result<PlaylistQuery, String> {
+        let page = self.page.unwrap_or(1);
+        if page == 0 {
+            return Err("Invalid page (expected >= 1)".to_string());
+        }
+        let page_size = self.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        if page_size == 0 || page_size > MAX_PAGE_SIZE {
+            return Err(format!("Invalid page_size (expected 1-{})", MAX_PAGE_SIZE));
+        }
+        let limit = self.limit.unwrap_or(cfg.playlist_max_entries);
+        if limit == 0 {
+            return Err("Invalid limit (expected >= 1)".to_string());
+        }
+        let date_after = self.date_after.as_deref().map(|d| parse_date(d, "date_after")).transpose()?;
+        let date_before = self.date_before.as_deref().map(|d| parse_date(d, "date_before")).transpose()?;
+        if let (Some(a), Some(b)) = (date_after, date_before) {
+            if a > b {
+                return Err("Invalid date range (date_after is after date_before)".to_string());
+            }
+        }
+
+        Ok(PlaylistQuery {
+            page,
+            page_size,
+            limit: limit.min(cfg.playlist_max_entries),
+            date_after,
+            date_before,
+        })
+    }
+}
+
+impl PlaylistQuery {
+    fn filters_by_date(&self) -> bool {
+        self.date_after.is_some() || self.date_before.is_some()
+    }
+
+    // 1-based, inclusive range of playlist items yt-dlp should extract. Without a date filter only the requested
+    // page (plus one entry, to know whether there is a next page) is extracted; with one, everything up to `limit`.
+    fn items(&self) -> Option<(usize, usize)> {
+        if self.filters_by_date() {
+            return Some((1, self.limit));
+        }
+        let first = (self.page - 1) * self.page_size + 1;
+        if first > self.limit {
+            return None;
+        }
+        Some((first, (first + self.page_size).min(self.limit)))
+    }
+
+    /// yt-dlp command for a flat (no per-video requests) extraction, or None when the page is past `limit`.
+    pub fn command(&self, cfg: &AppConfig, url: &str) -> Option<Command> {
+        let (first, last) = self.items()?;
+        let mut cmd = build_ytdlp_playlist_command(cfg);
+        cmd.arg("--flat-playlist")
+            .arg("-J")
+            .arg("-I")
+            .arg(format!("{}:{}", first, last))
+            .arg(url)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true);
+        Some(cmd)
+    }
+
+    fn in_range(&self, date: Option<NaiveDate>) -> bool {
+        // Entries without a date can't be placed in the range, so a date filter drops them.
+        let d = match date {
+            Some(d) => d,
+            None => return false,
+        };
+        self.date_after.is_none_or(|a| d >= a) && self.date_before.is_none_or(|b| d <= b)
+    }
+
+    /// Build the response page from yt-dlp's flat JSON (`None` = the page is past `limit`).
+    pub fn page(&self, info: Option<&serde_json::Value>) -> serde_json::Value {
+        let raw: Vec<&serde_json::Value> = info
+            .and_then(|v| v.get("entries"))
+            .and_then(|e| e.as_array())
+            .map(|a| a.iter().collect())
+            .unwrap_or_default();
+
+        let (entries, total, has_more) = if self.filters_by_date() {
+            let matching: Vec<serde_json::Value> = raw
+                .iter()
+                .map(|e| entry(e))
+                .filter(|(_, date)| self.in_range(*date))
+                .map(|(e, _)| e)
+                .collect();
+            let total = matching.len();
+            let start = ((self.page - 1) * self.page_size).min(total);
+            let end = (start + self.page_size).min(total);
+            (matching[start..end].to_vec(), Some(total), end < total)
+        } else {
+            let has_more = raw.len() > self.page_size;
+            let entries = raw.iter().take(self.page_size).map(|e| entry(e).0).collect();
+            let total = info
+                .and_then(|v| v.get("playlist_count"))
+                .and_then(|c| c.as_u64())
+                .map(|c| (c as usize).min(self.limit));
+            (entries, total, has_more)
+        };
+
+        let field = |k: &str| info.and_then(|v| v.get(k)).cloned().unwrap_or(serde_json::Value::Null);
+        serde_json::json!({
+            "id": field("id"),
+            "title": field("title"),
+            "type": field("_type"),
+            "channel": field("channel"),
+            "uploader": field("uploader"),
+            "page": self.page,
+            "page_size": self.page_size,
+            "total": total,
+            "has_more": has_more,
+            "entries": entries,
+        })
+    }
+}
+
+// Flat entries carry whatever the listing page had; upload dates are often missing, timestamps sometimes aren't.
+fn entry(e: &serde_json::Value) -> (serde_json::Value, Option<NaiveDate>) {
+    let date = e
+        .get("upload_date")
+        .and_then(|d| d.as_str())
+        .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok())
+        .or_else(|| {
+            e.get("timestamp")
+                .and_then(|t| t.as_i64())
+                .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+                .map(|t| t.date_naive())
+        });
+
+    let url = e
+        .get("url")
+        .and_then(|u| u.as_str())
+        .filter(|u| u.starts_with("http"))
+        .map(|u| u.to_string())
+        .or_else(|| {
+            e.get("id")
+                .and_then(|i| i.as_str())
+                .filter(|_| e.get("ie_key").and_then(|k| k.as_str()) == Some("Youtube"))
+                .map(|i| format!("https://www.youtube.com/watch?v={}", i))
+        });
+
+    let get = |k: &str| e.get(k).cloned().unwrap_or(serde_json::Value::Null);
+    (
+        serde_json::json!({
+            "id": get("id"),
+            "title": get("title"),
+            "url": url,
+            "duration": get("duration"),
+            "upload_date": date.map(|d| d.format("%Y-%m-%d").to_string()),
+            "channel": get("channel"),
+        }),
+        date,
+    )
+}
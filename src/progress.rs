@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+/// Prefix of the machine-readable progress lines we ask yt-dlp to print.
+const LINE_PREFIX: &str = "[progress] ";
+
+/// `--progress-template` values passed to yt-dlp. Fields yt-dlp doesn't know print as "NA".
+pub const DOWNLOAD_TEMPLATE: &str = "download:[progress] download %(progress.status)s %(progress.downloaded_bytes)s %(progress.total_bytes)s %(progress.total_bytes_estimate)s %(progress.speed)s %(progress.eta)s %(progress.fragment_index)s %(progress.fragment_count)s";
+pub const POSTPROCESS_TEMPLATE: &str =
+    "postprocess:[progress] postprocess %(progress.status)s %(progress.postprocessor)s";
+
+/// Structured download progress, streamed to clients on `GET /download/progress/{id}`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProgressEvent {
+    // queued | downloading | postprocessing | finished | error | cancelled
+    pub status: String,
+    pub percent: Option<f64>,
+    pub downloaded_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+    // Bytes per second.
+    pub speed: Option<f64>,
+    // Seconds.
+    pub eta: Option<u64>,
+    pub fragment_index: Option<u64>,
+    pub fragment_count: Option<u64>,
+    pub postprocessor: Option<String>,
+    pub error: Option<String>,
+    // 1-based position in the wait queue while status is "queued" behind max_concurrent_downloads.
+    pub queue_position: Option<usize>,
+}
+
+impl ProgressEvent {
+    pub fn status(status: &str) -> Self {
+        Self {
+            status: status.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self.status.as_str(), "finished" | "error" | "cancelled")
+    }
+}
+
+pub type ProgressSink = Arc<watch::Sender<ProgressEvent>>;
+
+pub fn channel() -> ProgressSink {
+    let (tx, _) = watch::channel(ProgressEvent::status("queued"));
+    Arc::new(tx)
+}
+
+fn field<T: std::str::FromStr>(s: Option<&str>) -> Option<T> {
+    s.filter(|v| *v != "NA" && *v != "None").and_then(|v| v.parse().ok())
+}
+
+/// Parse one line printed through our `--progress-template`. Returns None for any other output.
+pub fn parse_line(line: &str) -> Option<ProgressEvent> {
+    let rest = line.trim().strip_prefix(LINE_PREFIX)?;
+    let mut parts = rest.split_whitespace();
+
+    match parts.next()? {
+        "download" => {
+            // yt-dlp's own status is downloading|finished|error; a "finished" stream may still be followed by
+            // another (e.g. audio after video in mode=best), so only the job itself reports overall completion.
+            let raw_status = parts.next()?;
+            let downloaded_bytes: Option<u64> = field::<f64>(parts.next()).map(|v| v as u64);
+            let total_bytes: Option<u64> = field::<f64>(parts.next()).map(|v| v as u64);
+            let total_estimate: Option<u64> = field::<f64>(parts.next()).map(|v| v as u64);
+            let speed = field::<f64>(parts.next());
+            let eta = field::<f64>(parts.next()).map(|v| v as u64);
+            let fragment_index = field(parts.next());
+            let fragment_count = field(parts.next());
+
+            let total = total_bytes.or(total_estimate);
+            let percent = match (raw_status, downloaded_bytes, total) {
+                ("finished", _, _) => Some(100.0),
+                (_, Some(d), Some(t)) if t > 0 => Some((d as f64 * 1000.0 / t as f64).round() / 10.0),
+                _ => None,
+            };
+
+            Some(ProgressEvent {
+                status: if raw_status == "error" { "error" } else { "downloading" }.to_string(),
+                percent,
+                downloaded_bytes,
+                total_bytes: total,
+                speed,
+                eta,
+                fragment_index,
+                fragment_count,
+                ..Default::default()
+            })
+        }
+        "postprocess" => {
+            let _raw_status = parts.next()?;
+            Some(ProgressEvent {
+                status: "postprocessing".to_string(),
+                postprocessor: parts.next().map(|s| s.to_string()),
+                ..Default::default()
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Progress channels of in-flight `/download` requests, keyed by the client-chosen `progress_id`.
+#[derive(Default)]
+pub struct ProgressRegistry {
+    channels: Mutex<HashMap<String, watch::Receiver<ProgressEvent>>>,
+}
+
+impl ProgressRegistry {
+    /// Register a channel under `id`. Returns None if the id is already in use.
+    pub fn register(self: &Arc<Self>, id: &str, rx: watch::Receiver<ProgressEvent>) -> Option<Registration> {
+        let mut channels = self.channels.lock().unwrap();
+        if channels.contains_key(id) {
+            return None;
+        }
+        channels.insert(id.to_string(), rx);
+        Some(Registration {
+            registry: self.clone(),
+            id: id.to_string(),
+        })
+    }
+
+    pub fn subscribe(&self, id: &str) -> Option<watch::Receiver<ProgressEvent>> {
+        self.channels.lock().unwrap().get(id).cloned()
+    }
+}
+
+/// Removes the registry entry when dropped.
+pub struct Registration {
+    registry: Arc<ProgressRegistry>,
+    id: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.channels.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_download_lines() {
+        // (line, status, percent, downloaded, total, speed, eta, fragment index/count)
+        type Expected = (
+            &'static str,
+            Option<f64>,
+            Option<u64>,
+            Option<u64>,
+            Option<f64>,
+            Option<u64>,
+            Option<(u64, u64)>,
+        );
+        let cases: [(&str, Expected); 7] = [
+            (
+                "[progress] download downloading 1048576 10485760 NA 524288.25 18 NA NA",
+                ("downloading", Some(10.0), Some(1048576), Some(10485760), Some(524288.25), Some(18), None),
+            ),
+            // Start of a download: speed and ETA aren't known yet.
+            (
+                "[progress] download downloading 1024 3000000 NA NA NA NA NA",
+                ("downloading", Some(0.0), Some(1024), Some(3000000), None, None, None),
+            ),
+            // Unknown size: no percentage.
+            (
+                "[progress] download downloading 65536 NA NA 30000.0 None NA NA",
+                ("downloading", None, Some(65536), None, Some(30000.0), None, None),
+            ),
+            // HLS/DASH fragments: only an estimated total, plus fragment counters.
+            (
+                "[progress] download downloading 2097152.0 NA 41943040.0 1048576.0 38 5 100",
+                ("downloading", Some(5.0), Some(2097152), Some(41943040), Some(1048576.0), Some(38), Some((5, 100))),
+            ),
+            (
+                "  [progress] download finished 10485760 10485760 NA NA NA NA NA\r",
+                ("downloading", Some(100.0), Some(10485760), Some(10485760), None, None, None),
+            ),
+            (
+                "[progress] download finished NA NA NA NA NA 100 100",
+                ("downloading", Some(100.0), None, None, None, None, Some((100, 100))),
+            ),
+            (
+                "[progress] download error 5000 10000 NA NA NA NA NA",
+                ("error", Some(50.0), Some(5000), Some(10000), None, None, None),
+            ),
+        ];
+        for (line, (status, percent, downloaded, total, speed, eta, fragments)) in cases {
+            let e = parse_line(line).unwrap_or_else(|| panic!("not parsed: {}", line));
+            assert_eq!(e.status, status, "{}", line);
+            assert_eq!(e.percent, percent, "{}", line);
+            assert_eq!(e.downloaded_bytes, downloaded, "{}", line);
+            assert_eq!(e.total_bytes, total, "{}", line);
+            assert_eq!(e.speed, speed, "{}", line);
+            assert_eq!(e.eta, eta, "{}", line);
+            assert_eq!(e.fragment_index.zip(e.fragment_count), fragments, "{}", line);
+        }
+    }
+
+    #[test]
+    fn parses_postprocess_lines_and_ignores_other_output() {
+        let e = parse_line("[progress] postprocess started FFmpegMerger").unwrap();
+        assert_eq!(e.status, "postprocessing");
+        assert_eq!(e.postprocessor.as_deref(), Some("FFmpegMerger"));
+        assert!(!e.is_terminal());
+
+        // yt-dlp's own log lines, printed alongside ours.
+        for line in [
+            "[youtube] Extracting URL: https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "[info] dQw4w9WgXcQ: Downloading 1 format(s): 18",
+            "[download] Destination: /tmp/job/media.mp4",
+            "[download]  12.3% of   10.00MiB at  512.00KiB/s ETA 00:18",
+            "[download]   5.0% of ~  40.00MiB at    1.00MiB/s ETA Unknown (frag 5/100)",
+            "[download] 100% of   10.00MiB in 00:00:03 at 3.10MiB/s",
+            "[Merger] Merging formats into \"/tmp/job/media.mp4\"",
+            "[progress]",
+            "[progress] download",
+            "[progress] unknown 1 2 3",
+            "",
+        ] {
+            assert!(parse_line(line).is_none(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn registry_frees_ids_on_drop() {
+        let registry = Arc::new(ProgressRegistry::default());
+        let sink = channel();
+        let registration = registry.register("abc", sink.subscribe()).unwrap();
+        assert!(registry.register("abc", sink.subscribe()).is_none());
+        sink.send_replace(ProgressEvent::status("finished"));
+        assert!(registry.subscribe("abc").unwrap().borrow().is_terminal());
+        drop(registration);
+        assert!(registry.subscribe("abc").is_none());
+        assert!(registry.register("abc", sink.subscribe()).is_some());
+    }
+}
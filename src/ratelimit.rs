@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::auth::{presented_key, routed_path, same_key, Authenticated};
+use crate::config::{ApiKey, ClientLimits, RateLimitConfig};
+use crate::state::AppState;
+
+/// Clients idle for this long are forgotten (their bucket is full again and their day is over anyway).
+const IDLE_EXPIRY: Duration = Duration::from_secs(48 * 3600);
+
+struct ClientState {
+    tokens: f64,
+    refilled_at: Instant,
+    day: NaiveDate,
+    requests: u64,
+    // Response bytes sent today; bodies still streaming keep adding to it.
+    bytes: Arc<AtomicU64>,
+    seen_at: Instant,
+}
+
+/// Why a request was refused, and when the client may try again.
+#[derive(Debug)]
+pub struct Limited {
+    pub reason: String,
+    pub retry_after: u64,
+}
+
+/// Per-client token buckets and daily request/byte quotas. Requests authenticated with an [[api_keys]] entry are
+/// tracked by the key's name; without [[api_keys]], a key listed under [[rate_limit.clients]] identifies its
+/// client. Everyone else is tracked by IP address.
+pub struct RateLimiter {
+    default: ClientLimits,
+    // (api_key, client name, limits) of [[rate_limit.clients]] entries with their own key; only consulted when no
+    // [[api_keys]] are configured, since otherwise the key has to authenticate first.
+    keyed: Vec<(String, String, ClientLimits)>,
+    // Limits for [[api_keys]] entries, by key name.
+    named: HashMap<String, ClientLimits>,
+    auth_enabled: bool,
+    clients: Mutex<HashMap<String, ClientState>>,
+}
+
+fn secs_until_utc_midnight(now: DateTime<Utc>) -> u64 {
+    let tomorrow = (now.date_naive() + chrono::Days::new(1)).and_hms_opt(0, 0, 0).unwrap().and_utc();
+    (tomorrow - now).num_seconds().max(1) as u64
+}
+
+impl RateLimiter {
+    pub fn new(cfg: &RateLimitConfig, api_keys: &[ApiKey]) -> Self {
+        // A client is found by the name of the [[api_keys]] entry it is for: its own name, or the name of the
+        // entry whose key it lists.
+        let named = cfg.clients.iter().filter_map(|c| match &c.api_key {
+            None => Some((c.name.clone(), c.limits.clone())),
+            Some(key) => api_keys
+                .iter()
+                .find(|k| &k.key == key)
+                .map(|k| (k.name.clone(), c.limits.clone())),
+        });
+        Self {
+            default: cfg.default.clone(),
+            keyed: cfg
+                .clients
+                .iter()
+                .filter_map(|c| Some((c.api_key.clone()?, c.name.clone(), c.limits.clone())))
+                .collect(),
+            named: named.collect(),
+            auth_enabled: !api_keys.is_empty(),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Who `req` counts as (for limits and logs), and which limits apply. A key only picks a client once it is
+    /// known to be genuine: authenticated by `auth::require_key`, or — without [[api_keys]] — matching a
+    /// [[rate_limit.clients]] key.
+    fn identify(&self, req: &HttpRequest) -> (String, &ClientLimits) {
+        if let Some(Authenticated(name)) = req.extensions().get::<Authenticated>().cloned() {
+            let limits = self.named.get(&name).unwrap_or(&self.default);
+            return (format!("key:{}", name), limits);
+        }
+        if !self.auth_enabled {
+            let client = presented_key(req).and_then(|p| self.keyed.iter().find(|(key, ..)| same_key(key, p)));
+            if let Some((_, name, limits)) = client {
+                return (format!("key:{}", name), limits);
+            }
+        }
+        let ip = req
+            .peer_addr()
+            .map(|a| a.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        (format!("ip:{}", ip), &self.default)
+    }
+
+    /// Count one request from `client`. Returns the client's daily byte counter, to be fed with the response size.
+    fn check(&self, client: &str, limits: &ClientLimits) -> Result<Arc<AtomicU64>, Limited> {
+        self.check_at(client, limits, Instant::now(), Utc::now())
+    }
+
+    /// `check` at the given time (`now` for the bucket, `utc` for the day).
+    fn check_at(
+        &self,
+        client: &str,
+        limits: &ClientLimits,
+        now: Instant,
+        utc: DateTime<Utc>,
+    ) -> Result<Arc<AtomicU64>, Limited> {
+        let today = utc.date_naive();
+        let mut clients = self.clients.lock().unwrap();
+        let state = clients.entry(client.to_string()).or_insert_with(|| ClientState {
+            tokens: limits.burst.or(limits.requests_per_minute).unwrap_or(0) as f64,
+            refilled_at: now,
+            day: today,
+            requests: 0,
+            bytes: Arc::new(AtomicU64::new(0)),
+            seen_at: now,
+        });
+        state.seen_at = now;
+        if state.day != today {
+            state.day = today;
+            state.requests = 0;
+            state.bytes = Arc::new(AtomicU64::new(0));
+        }
+
+        // Quotas first, so requests refused for the day don't also drain the bucket.
+        if let Some(max) = limits.daily_requests {
+            if state.requests >= max {
+                return Err(Limited {
+                    reason: format!("Daily request quota exceeded ({} requests)", max),
+                    retry_after: secs_until_utc_midnight(utc),
+                });
+            }
+        }
+        if let Some(max) = limits.daily_bytes {
+            if state.bytes.load(Ordering::Relaxed) >= max {
+                return Err(Limited {
+                    reason: format!("Daily download quota exceeded ({} bytes)", max),
+                    retry_after: secs_until_utc_midnight(utc),
+                });
+            }
+        }
+
+        if let Some(rpm) = limits.requests_per_minute {
+            let rate = rpm as f64 / 60.0;
+            let burst = limits.burst.unwrap_or(rpm) as f64;
+            let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate).min(burst);
+            state.refilled_at = now;
+            if state.tokens < 1.0 {
+                return Err(Limited {
+                    reason: format!("Rate limit exceeded ({} requests per minute)", rpm),
+                    retry_after: ((1.0 - state.tokens) / rate).ceil().max(1.0) as u64,
+                });
+            }
+            state.tokens -= 1.0;
+        }
+
+        state.requests += 1;
+        Ok(state.bytes.clone())
+    }
+
+    /// Forget clients that haven't been seen for a while; returns how many were removed.
+    pub fn purge_idle(&self) -> usize {
+        let mut clients = self.clients.lock().unwrap();
+        let before = clients.len();
+        clients.retain(|_, c| c.seen_at.elapsed() < IDLE_EXPIRY);
+        before - clients.len()
+    }
+}
+
+/// Response body wrapper adding every chunk sent to the client's daily byte count.
+struct Counted {
+    inner: BoxBody,
+    bytes: Arc<AtomicU64>,
+}
+
+impl MessageBody for Counted {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+/// Middleware enforcing `[rate_limit]`: refused requests get 429 with `Retry-After`. The health check is exempt.
+pub async fn enforce(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let limiter = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|s| s.rate_limiter.clone());
+    let limiter = match limiter {
+        Some(l) if routed_path(req.request()) != "/" => l,
+        _ => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+
+    let (client, limits) = limiter.identify(req.request());
+    let bytes = match limiter.check(&client, limits) {
+        Ok(b) => b,
+        Err(limited) => {
+            eprintln!(
+                "[RATE] {} refused: {} {} ({})",
+                client,
+                req.method(),
+                routed_path(req.request()),
+                limited.reason
+            );
+            let resp = HttpResponse::TooManyRequests()
+                .append_header((header::RETRY_AFTER, limited.retry_after.to_string()))
+                .json(serde_json::json!({
+                    "error": limited.reason,
+                    "retry_after": limited.retry_after
+                }));
+            return Ok(req.into_response(resp));
+        }
+    };
+
+    let resp = next.call(req).await?;
+    Ok(resp.map_body(|_, body| BoxBody::new(Counted { inner: BoxBody::new(body), bytes })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn limits(rpm: Option<u32>, burst: Option<u32>, requests: Option<u64>, bytes: Option<u64>) -> ClientLimits {
+        ClientLimits {
+            requests_per_minute: rpm,
+            burst,
+            daily_requests: requests,
+            daily_bytes: bytes,
+        }
+    }
+
+    fn limiter(default: ClientLimits) -> RateLimiter {
+        let cfg = RateLimitConfig {
+            default,
+            clients: Vec::new(),
+        };
+        RateLimiter::new(&cfg, &[])
+    }
+
+    fn at(h: u32, m: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 17, h, m, s).unwrap()
+    }
+
+    /// How many requests in a row `client` gets through at one instant.
+    fn drain(rl: &RateLimiter, limits: &ClientLimits, now: Instant, utc: DateTime<Utc>) -> usize {
+        (0..1000).take_while(|_| rl.check_at("c", limits, now, utc).is_ok()).count()
+    }
+
+    #[test]
+    fn bucket_holds_burst_and_refills_at_rate() {
+        let t0 = Instant::now();
+        let noon = at(12, 0, 0);
+        let secs = |s: f64| t0 + Duration::from_secs_f64(s);
+
+        // burst defaults to requests_per_minute.
+        let l = limits(Some(60), None, None, None);
+        let rl = limiter(l.clone());
+        assert_eq!(drain(&rl, &l, t0, noon), 60);
+        let limited = rl.check_at("c", &l, secs(0.5), noon).unwrap_err();
+        assert_eq!(limited.retry_after, 1);
+        assert!(limited.reason.contains("60 requests per minute"));
+        assert!(rl.check_at("c", &l, secs(1.0), noon).is_ok());
+        // Idle time refills up to the burst, no further.
+        assert_eq!(drain(&rl, &l, secs(3600.0), noon), 60);
+
+        // 6 per minute = one token every 10s, at most 2 saved up.
+        let l = limits(Some(6), Some(2), None, None);
+        let rl = limiter(l.clone());
+        assert_eq!(drain(&rl, &l, t0, noon), 2);
+        assert_eq!(rl.check_at("c", &l, secs(4.0), noon).unwrap_err().retry_after, 6);
+        assert_eq!(drain(&rl, &l, secs(10.0), noon), 1);
+        assert_eq!(drain(&rl, &l, secs(100.0), noon), 2);
+    }
+
+    #[test]
+    fn daily_requests_reset_at_utc_midnight() {
+        let l = limits(None, None, Some(2), None);
+        let rl = limiter(l.clone());
+        let t0 = Instant::now();
+        assert_eq!(drain(&rl, &l, t0, at(23, 59, 30)), 2);
+        let limited = rl.check_at("c", &l, t0, at(23, 59, 30)).unwrap_err();
+        assert_eq!(limited.retry_after, 30);
+        assert!(limited.reason.contains("Daily request quota"));
+        // Other clients have their own quota.
+        assert!(rl.check_at("other", &l, t0, at(23, 59, 30)).is_ok());
+
+        let next_day = Utc.with_ymd_and_hms(2026, 10, 18, 0, 0, 1).unwrap();
+        assert_eq!(drain(&rl, &l, t0, next_day), 2);
+    }
+
+    #[test]
+    fn daily_bytes_count_what_was_sent() {
+        let l = limits(None, None, None, Some(1000));
+        let rl = limiter(l.clone());
+        let t0 = Instant::now();
+        let bytes = rl.check_at("c", &l, t0, at(8, 0, 0)).unwrap();
+        bytes.fetch_add(600, Ordering::Relaxed);
+        let bytes = rl.check_at("c", &l, t0, at(9, 0, 0)).unwrap();
+        bytes.fetch_add(400, Ordering::Relaxed);
+        let limited = rl.check_at("c", &l, t0, at(10, 0, 0)).unwrap_err();
+        assert!(limited.reason.contains("1000 bytes"));
+        assert_eq!(limited.retry_after, 14 * 3600);
+
+        let next_day = Utc.with_ymd_and_hms(2026, 10, 18, 0, 0, 0).unwrap();
+        let bytes = rl.check_at("c", &l, t0, next_day).unwrap();
+        assert_eq!(bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn exhausted_quota_does_not_drain_bucket() {
+        let l = limits(Some(1), Some(2), Some(1), None);
+        let rl = limiter(l.clone());
+        let t0 = Instant::now();
+        assert!(rl.check_at("c", &l, t0, at(23, 0, 0)).is_ok());
+        for _ in 0..5 {
+            assert!(rl.check_at("c", &l, t0, at(23, 0, 0)).unwrap_err().reason.contains("Daily"));
+        }
+        // No time has passed for the bucket: its second token is still there the next day.
+        let next_day = Utc.with_ymd_and_hms(2026, 10, 18, 0, 0, 0).unwrap();
+        assert!(rl.check_at("c", &l, t0, next_day).is_ok());
+        assert!(rl.check_at("c", &l, t0, next_day).unwrap_err().reason.contains("Daily"));
+    }
+
+    #[test]
+    fn keys_pick_a_client_only_once_genuine() {
+        let generous = limits(Some(1000), None, None, None);
+        let cfg = |key: &str| RateLimitConfig {
+            default: limits(Some(1), None, None, None),
+            clients: vec![crate::config::RateLimitClient {
+                name: "team-a".to_string(),
+                api_key: Some(key.to_string()),
+                limits: generous.clone(),
+            }],
+        };
+        let request = |key: Option<&str>, authenticated: Option<&str>| {
+            let mut req = TestRequest::get().peer_addr("10.1.2.3:5000".parse().unwrap());
+            if let Some(key) = key {
+                req = req.insert_header(("X-API-Key", key));
+            }
+            let req = req.to_http_request();
+            if let Some(name) = authenticated {
+                req.extensions_mut().insert(Authenticated(name.to_string()));
+            }
+            req
+        };
+
+        // Without [[api_keys]], the rate limit key itself identifies the client.
+        let rl = RateLimiter::new(&cfg("k-team"), &[]);
+        let (who, l) = rl.identify(&request(Some("k-team"), None));
+        assert_eq!((who.as_str(), l.requests_per_minute), ("key:team-a", Some(1000)));
+        assert_eq!(rl.identify(&request(Some("k-guess"), None)).0, "ip:10.1.2.3");
+
+        // With [[api_keys]], only an authenticated key counts.
+        let keys = [ApiKey {
+            name: "a".to_string(),
+            key: "k-team".to_string(),
+            scopes: vec!["download".to_string()],
+        }];
+        let rl = RateLimiter::new(&cfg("k-team"), &keys);
+        let (who, l) = rl.identify(&request(Some("k-team"), None));
+        assert_eq!((who.as_str(), l.requests_per_minute), ("ip:10.1.2.3", Some(1)));
+        let (who, l) = rl.identify(&request(Some("k-team"), Some("a")));
+        assert_eq!((who.as_str(), l.requests_per_minute), ("key:a", Some(1000)));
+        let (who, l) = rl.identify(&request(None, Some("other")));
+        assert_eq!((who.as_str(), l.requests_per_minute), ("key:other", Some(1)));
+    }
+}
+
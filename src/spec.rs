@@ -0,0 +1,1009 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::config::AppConfig;
+use crate::subtitles;
+use crate::ytdlp::find_ffmpeg;
+
+/// Download options shared by `/download`, `/jobs` and `/audio` (flattened into their request bodies).
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DownloadOptions {
+    // "progressive" (default): best single-file mp4 if available (more likely to truly stream as it downloads)
+    // "best": bestvideo+bestaudio with merge (may only start streaming after merge)
+    // "audio": audio only, see audio_format/audio_bitrate
+    pub mode: Option<String>,
+    // mode=audio: m4a (default) | mp3 | opus | flac
+    pub audio_format: Option<String>,
+    // mode=audio: target bitrate in kbit/s (32-320); best available quality when unset. Not used for flac.
+    pub audio_bitrate: Option<u32>,
+
+    // Video modes only. Hard caps on the selected format.
+    pub max_height: Option<u32>,
+    pub max_fps: Option<u32>,
+    // Codec preferences (yt-dlp `-S`): used when available, not required.
+    pub vcodec: Option<String>,
+    pub acodec: Option<String>,
+    // mp4 (default) | webm | mkv
+    pub container: Option<String>,
+    // Raw yt-dlp `-f` expression; replaces the mode's default selection. Cannot be combined with max_height/max_fps.
+    pub format: Option<String>,
+
+    // Embed into the output file (yt-dlp `--embed-*` postprocessors; all need ffmpeg).
+    // Subtitles are video modes only; sub_langs defaults to every manual subtitle track.
+    pub embed_subs: Option<bool>,
+    pub sub_langs: Option<Vec<String>>,
+    pub embed_chapters: Option<bool>,
+    pub embed_thumbnail: Option<bool>,
+    pub embed_metadata: Option<bool>,
+
+    // Only download a clip: start/end, or several sections that are joined in time order. Times are seconds or
+    // "[HH:]MM:SS[.ms]" strings; a missing start means the beginning, a missing end the end of the video.
+    pub start: Option<TimeValue>,
+    pub end: Option<TimeValue>,
+    pub sections: Option<Vec<SectionRequest>>,
+    // Re-encode around the cut points so clips start/end exactly there instead of at the nearest keyframe.
+    pub force_keyframes: Option<bool>,
+
+    // Split the download by chapter and return a ZIP of the chapter files plus a manifest.
+    pub split_chapters: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum TimeValue {
+    Seconds(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SectionRequest {
+    pub start: Option<TimeValue>,
+    pub end: Option<TimeValue>,
+}
+
+const MAX_SECTIONS: usize = 20;
+
+impl TimeValue {
+    // Seconds; None for anything that isn't a finite, non-negative time.
+    fn seconds(&self) -> Option<f64> {
+        let secs = match self {
+            TimeValue::Seconds(s) => *s,
+            TimeValue::Text(t) => {
+                let parts: Vec<&str> = t.trim().split(':').collect();
+                if parts.is_empty() || parts.len() > 3 {
+                    return None;
+                }
+                let (last, rest) = parts.split_last()?;
+                let mut secs: f64 = last.parse().ok()?;
+                if !rest.is_empty() && !(0.0..60.0).contains(&secs) {
+                    return None;
+                }
+                for (i, p) in rest.iter().rev().enumerate() {
+                    let n: u64 = p.parse().ok()?;
+                    // Minutes only wrap when hours are given too.
+                    if i == 0 && rest.len() == 2 && n >= 60 {
+                        return None;
+                    }
+                    secs += n as f64 * 60f64.powi(i as i32 + 1);
+                }
+                secs
+            }
+        };
+        (secs.is_finite() && secs >= 0.0).then_some(secs)
+    }
+}
+
+/// A time range of the video, in seconds. `end` None means until the end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Section {
+    pub start: f64,
+    pub end: Option<f64>,
+}
+
+// Times in labels and yt-dlp arguments: whole seconds without decimals, otherwise millisecond precision.
+fn fmt_secs(s: f64) -> String {
+    if s.fract() == 0.0 {
+        format!("{}", s as u64)
+    } else {
+        format!("{:.3}", s).trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+}
+
+impl Section {
+    fn label(&self) -> String {
+        match self.end {
+            Some(e) => format!("{}-{}", fmt_secs(self.start), fmt_secs(e)),
+            None => format!("{}-end", fmt_secs(self.start)),
+        }
+    }
+
+    // `--download-sections` value.
+    fn arg(&self) -> String {
+        match self.end {
+            Some(e) => format!("*{}-{}", fmt_secs(self.start), fmt_secs(e)),
+            None => format!("*{}-inf", fmt_secs(self.start)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClipSpec {
+    // Sorted by start, non-overlapping. Empty means the whole video.
+    pub sections: Vec<Section>,
+    pub force_keyframes: bool,
+}
+
+impl ClipSpec {
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    /// Human-readable range for file names, e.g. `10-40` or `10-40_95-end` (seconds).
+    pub fn label(&self) -> Option<String> {
+        if self.sections.is_empty() {
+            return None;
+        }
+        Some(self.sections.iter().map(|s| s.label()).collect::<Vec<_>>().join("_"))
+    }
+
+    /// `--match-filter` that makes yt-dlp skip (rather than download) a video ending before the last section
+    /// starts; None when every section starts at 0.
+    pub fn match_filter(&self) -> Option<String> {
+        let last = self.sections.last()?;
+        (last.start > 0.0).then(|| format!("duration >? {}", fmt_secs(last.start)))
+    }
+}
+
+const CONTAINERS: &[&str] = &["mp4", "webm", "mkv"];
+
+// Accepted spellings, mapped to the names yt-dlp's format sorting understands.
+const VCODECS: &[(&str, &str)] = &[
+    ("av01", "av01"),
+    ("av1", "av01"),
+    ("vp9", "vp9"),
+    ("h265", "h265"),
+    ("hevc", "h265"),
+    ("h264", "h264"),
+    ("avc", "h264"),
+    ("vp8", "vp8"),
+];
+const ACODECS: &[(&str, &str)] = &[
+    ("opus", "opus"),
+    ("aac", "aac"),
+    ("mp4a", "aac"),
+    ("vorbis", "vorbis"),
+    ("mp3", "mp3"),
+    ("flac", "flac"),
+    ("ac3", "ac3"),
+    ("eac3", "eac3"),
+];
+
+fn lookup(table: &[(&str, &'static str)], value: &str) -> Option<&'static str> {
+    let value = value.to_ascii_lowercase();
+    table.iter().find(|(k, _)| *k == value).map(|(_, v)| *v)
+}
+
+// Characters that show up in `-f` expressions (filters, fallbacks, merges). The value is passed as a single
+// argument (never through a shell); this is mostly to reject obvious garbage early with a clear error.
+fn valid_format_expression(f: &str) -> bool {
+    !f.is_empty()
+        && f.len() <= 256
+        && f.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "[]()+/,=<>!*^$?.:_-~'\"".contains(c))
+}
+
+// FNV-1a; stable across builds, unlike std's hasher, which matters for cache keys that outlive the process.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    M4a,
+    Mp3,
+    Opus,
+    Flac,
+}
+
+impl AudioCodec {
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "m4a" | "aac" => Some(Self::M4a),
+            "mp3" => Some(Self::Mp3),
+            "opus" => Some(Self::Opus),
+            "flac" => Some(Self::Flac),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::M4a => "m4a",
+            Self::Mp3 => "mp3",
+            Self::Opus => "opus",
+            Self::Flac => "flac",
+        }
+    }
+
+    // Source formats that can be kept as-is, preferred so yt-dlp only remuxes (or does nothing) when possible.
+    fn preferred_source(self) -> &'static str {
+        match self {
+            Self::M4a => "bestaudio[ext=m4a]/bestaudio/best",
+            Self::Opus => "bestaudio[acodec=opus]/bestaudio/best",
+            Self::Mp3 | Self::Flac => "bestaudio/best",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioSpec {
+    pub codec: AudioCodec,
+    pub bitrate: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VideoSpec {
+    pub max_height: Option<u32>,
+    pub max_fps: Option<u32>,
+    pub vcodec: Option<&'static str>,
+    pub acodec: Option<&'static str>,
+    pub container: Option<String>,
+    pub format: Option<String>,
+}
+
+impl VideoSpec {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // `[height<=N][fps<=N]`, appended to every alternative of the format expression.
+    fn filters(&self) -> String {
+        let mut f = String::new();
+        if let Some(h) = self.max_height {
+            f.push_str(&format!("[height<={}]", h));
+        }
+        if let Some(fps) = self.max_fps {
+            f.push_str(&format!("[fps<={}]", fps));
+        }
+        f
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EmbedSpec {
+    // Some(langs) to embed subtitles; an empty list means all manual tracks.
+    pub subs: Option<Vec<String>>,
+    pub chapters: bool,
+    pub thumbnail: bool,
+    pub metadata: bool,
+}
+
+impl EmbedSpec {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn flag_names(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.subs.is_some() {
+            names.push("embed_subs");
+        }
+        if self.chapters {
+            names.push("embed_chapters");
+        }
+        if self.thumbnail {
+            names.push("embed_thumbnail");
+        }
+        if self.metadata {
+            names.push("embed_metadata");
+        }
+        names
+    }
+}
+
+/// Validated download options. Decides the yt-dlp format arguments and the variant string that identifies
+/// "the same download" for coalescing and the content cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadSpec {
+    pub mode: String,
+    pub audio: Option<AudioSpec>,
+    pub video: VideoSpec,
+    pub embed: EmbedSpec,
+    pub clip: ClipSpec,
+    pub split_chapters: bool,
+}
+
+impl DownloadOptions {
+    pub fn validate(&self) -> Result<DownloadSpec, String> {
+        let mode = self.mode.clone().unwrap_or_else(|| "progressive".to_string());
+        if mode != "progressive" && mode != "best" && mode != "audio" {
+            return Err("Invalid mode (expected: progressive|best|audio)".to_string());
+        }
+
+        if mode != "audio" {
+            if self.audio_format.is_some() || self.audio_bitrate.is_some() {
+                return Err("audio_format/audio_bitrate are only supported with mode=audio".to_string());
+            }
+            let video = self.validate_video()?;
+            let embed = self.validate_embed(false)?;
+            if embed.thumbnail && video.container.as_deref() == Some("webm") {
+                return Err("embed_thumbnail is not supported with container=webm".to_string());
+            }
+            return Ok(DownloadSpec {
+                mode,
+                audio: None,
+                video,
+                embed,
+                clip: self.validate_clip()?,
+                split_chapters: self.validate_split()?,
+            });
+        }
+
+        if self.max_height.is_some()
+            || self.max_fps.is_some()
+            || self.vcodec.is_some()
+            || self.acodec.is_some()
+            || self.container.is_some()
+            || self.format.is_some()
+        {
+            return Err("max_height/max_fps/vcodec/acodec/container/format are not supported with mode=audio".to_string());
+        }
+
+        let codec = match self.audio_format.as_deref() {
+            Some(f) => AudioCodec::parse(f)
+                .ok_or_else(|| "Invalid audio_format (expected: m4a|mp3|opus|flac)".to_string())?,
+            None => AudioCodec::M4a,
+        };
+        if let Some(b) = self.audio_bitrate {
+            if codec == AudioCodec::Flac {
+                return Err("audio_bitrate is not supported with audio_format=flac".to_string());
+            }
+            if !(32..=320).contains(&b) {
+                return Err("Invalid audio_bitrate (expected: 32-320 kbit/s)".to_string());
+            }
+        }
+
+        Ok(DownloadSpec {
+            mode,
+            audio: Some(AudioSpec {
+                codec,
+                bitrate: self.audio_bitrate,
+            }),
+            video: VideoSpec::default(),
+            embed: self.validate_embed(true)?,
+            clip: self.validate_clip()?,
+            split_chapters: self.validate_split()?,
+        })
+    }
+
+    fn validate_split(&self) -> Result<bool, String> {
+        let split = self.split_chapters.unwrap_or(false);
+        if split && (self.start.is_some() || self.end.is_some() || self.sections.is_some()) {
+            return Err("split_chapters cannot be combined with start/end/sections".to_string());
+        }
+        Ok(split)
+    }
+
+    fn validate_clip(&self) -> Result<ClipSpec, String> {
+        let parse = |v: &Option<TimeValue>, name: &str| -> Result<Option<f64>, String> {
+            match v {
+                Some(t) => t
+                    .seconds()
+                    .map(Some)
+                    .ok_or_else(|| format!("Invalid {} (expected seconds or [HH:]MM:SS[.ms])", name)),
+                None => Ok(None),
+            }
+        };
+
+        let mut sections = Vec::new();
+        match &self.sections {
+            Some(list) => {
+                if self.start.is_some() || self.end.is_some() {
+                    return Err("Use either start/end or sections, not both".to_string());
+                }
+                if list.is_empty() || list.len() > MAX_SECTIONS {
+                    return Err(format!("Invalid sections (expected 1-{} entries)", MAX_SECTIONS));
+                }
+                for s in list {
+                    let start = parse(&s.start, "sections[].start")?.unwrap_or(0.0);
+                    let end = parse(&s.end, "sections[].end")?;
+                    sections.push(Section { start, end });
+                }
+            }
+            None => {
+                if self.start.is_some() || self.end.is_some() {
+                    let start = parse(&self.start, "start")?.unwrap_or(0.0);
+                    let end = parse(&self.end, "end")?;
+                    sections.push(Section { start, end });
+                }
+            }
+        }
+
+        for s in &sections {
+            if let Some(e) = s.end {
+                if e <= s.start {
+                    return Err("Invalid time range (end must be after start)".to_string());
+                }
+            }
+        }
+        sections.sort_by(|a, b| a.start.total_cmp(&b.start));
+        for pair in sections.windows(2) {
+            match pair[0].end {
+                Some(e) if e <= pair[1].start => {}
+                _ => return Err("Invalid sections (ranges must not overlap)".to_string()),
+            }
+        }
+
+        let force_keyframes = self.force_keyframes.unwrap_or(false);
+        if force_keyframes && sections.is_empty() {
+            return Err("force_keyframes requires start/end or sections".to_string());
+        }
+        Ok(ClipSpec {
+            sections,
+            force_keyframes,
+        })
+    }
+
+    fn validate_embed(&self, audio: bool) -> Result<EmbedSpec, String> {
+        let embed_subs = self.embed_subs.unwrap_or(false);
+        if !embed_subs && self.sub_langs.is_some() {
+            return Err("sub_langs requires embed_subs=true".to_string());
+        }
+        if embed_subs && audio {
+            return Err("embed_subs is not supported with mode=audio".to_string());
+        }
+        let subs = if embed_subs {
+            let langs = self.sub_langs.clone().unwrap_or_default();
+            if langs.len() > 16 || !langs.iter().all(|l| subtitles::valid_lang(l)) {
+                return Err("Invalid sub_langs (expected up to 16 language codes like en or pt-BR)".to_string());
+            }
+            Some(langs)
+        } else {
+            None
+        };
+
+        Ok(EmbedSpec {
+            subs,
+            chapters: self.embed_chapters.unwrap_or(false),
+            thumbnail: self.embed_thumbnail.unwrap_or(false),
+            metadata: self.embed_metadata.unwrap_or(false),
+        })
+    }
+
+    fn validate_video(&self) -> Result<VideoSpec, String> {
+        if let Some(h) = self.max_height {
+            if !(144..=4320).contains(&h) {
+                return Err("Invalid max_height (expected: 144-4320)".to_string());
+            }
+        }
+        if let Some(fps) = self.max_fps {
+            if !(1..=240).contains(&fps) {
+                return Err("Invalid max_fps (expected: 1-240)".to_string());
+            }
+        }
+        let vcodec = match self.vcodec.as_deref() {
+            Some(v) => Some(lookup(VCODECS, v).ok_or_else(|| {
+                "Invalid vcodec (expected: av01|vp9|h265|h264|vp8)".to_string()
+            })?),
+            None => None,
+        };
+        let acodec = match self.acodec.as_deref() {
+            Some(a) => Some(lookup(ACODECS, a).ok_or_else(|| {
+                "Invalid acodec (expected: opus|aac|vorbis|mp3|flac|ac3|eac3)".to_string()
+            })?),
+            None => None,
+        };
+        let container = match self.container.as_deref() {
+            Some(c) => {
+                let c = c.to_ascii_lowercase();
+                if !CONTAINERS.contains(&c.as_str()) {
+                    return Err("Invalid container (expected: mp4|webm|mkv)".to_string());
+                }
+                Some(c)
+            }
+            None => None,
+        };
+        if let Some(f) = self.format.as_deref() {
+            if !valid_format_expression(f) {
+                return Err("Invalid format (expected a yt-dlp format expression, max 256 chars)".to_string());
+            }
+            if self.max_height.is_some() || self.max_fps.is_some() {
+                return Err("format cannot be combined with max_height/max_fps; put the limits in the expression".to_string());
+            }
+        }
+
+        Ok(VideoSpec {
+            max_height: self.max_height,
+            max_fps: self.max_fps,
+            vcodec,
+            acodec,
+            container,
+            format: self.format.clone(),
+        })
+    }
+}
+
+impl DownloadSpec {
+    /// Short identifier of what this spec produces, e.g. `best`, `best-h720-mkv` or `audio-mp3-192k-meta`.
+    pub fn variant(&self) -> String {
+        let mut parts = match &self.audio {
+            Some(a) => match a.bitrate {
+                Some(b) => vec![format!("audio-{}-{}k", a.codec.as_str(), b)],
+                None => vec![format!("audio-{}", a.codec.as_str())],
+            },
+            None => self.video_variant_parts(),
+        };
+
+        let e = &self.embed;
+        if let Some(langs) = &e.subs {
+            if langs.is_empty() {
+                parts.push("subs".to_string());
+            } else {
+                parts.push(format!("subs.{}", langs.join(".")));
+            }
+        }
+        if e.chapters {
+            parts.push("chap".to_string());
+        }
+        if e.thumbnail {
+            parts.push("thumb".to_string());
+        }
+        if e.metadata {
+            parts.push("meta".to_string());
+        }
+        if self.split_chapters {
+            parts.push("chapters".to_string());
+        }
+        if let Some(label) = self.clip.label() {
+            // Variants end up in cache file names; keep long section lists short.
+            if label.len() > 64 {
+                parts.push(format!("clip.{:016x}", fnv1a(&label)));
+            } else {
+                parts.push(format!("clip.{}", label));
+            }
+            if self.clip.force_keyframes {
+                parts.push("kf".to_string());
+            }
+        }
+        parts.join("-")
+    }
+
+    fn video_variant_parts(&self) -> Vec<String> {
+        let v = &self.video;
+        let mut parts = vec![self.mode.clone()];
+        if let Some(h) = v.max_height {
+            parts.push(format!("h{}", h));
+        }
+        if let Some(fps) = v.max_fps {
+            parts.push(format!("fps{}", fps));
+        }
+        if let Some(c) = v.vcodec {
+            parts.push(format!("v{}", c));
+        }
+        if let Some(c) = v.acodec {
+            parts.push(format!("a{}", c));
+        }
+        if let Some(c) = &v.container {
+            parts.push(c.clone());
+        }
+        if let Some(f) = &v.format {
+            parts.push(format!("f{:016x}", fnv1a(f)));
+        }
+        parts
+    }
+
+    /// Whether the media can be written to stdout as it downloads (no merge, remux or embedding step).
+    pub fn supports_passthrough(&self) -> bool {
+        self.mode == "progressive"
+            && self.embed.is_empty()
+            && self.clip.is_empty()
+            && !self.split_chapters
+            && self.video.container.is_none()
+            && !self.video.format.as_deref().is_some_and(|f| f.contains('+'))
+    }
+
+    /// Add the format selection (and any postprocessing) arguments to `cmd`. Fails when ffmpeg is needed but
+    /// cannot be found.
+    pub fn apply(&self, cfg: &AppConfig, cmd: &mut Command) -> Result<(), String> {
+        if !self.embed.is_empty() {
+            let ffmpeg = find_ffmpeg(cfg).ok_or_else(|| {
+                format!(
+                    "ffmpeg is required for {}. Install ffmpeg or set ffmpeg_bin in config.toml",
+                    self.embed.flag_names().join("/")
+                )
+            })?;
+            cmd.arg("--ffmpeg-location").arg(ffmpeg);
+        }
+        if self.split_chapters {
+            let ffmpeg = find_ffmpeg(cfg).ok_or_else(|| {
+                "ffmpeg is required for split_chapters. Install ffmpeg or set ffmpeg_bin in config.toml".to_string()
+            })?;
+            cmd.arg("--ffmpeg-location").arg(ffmpeg);
+        }
+        if !self.clip.is_empty() {
+            // yt-dlp cuts ranges with ffmpeg as the downloader.
+            let ffmpeg = find_ffmpeg(cfg).ok_or_else(|| {
+                "ffmpeg is required for start/end/sections. Install ffmpeg or set ffmpeg_bin in config.toml".to_string()
+            })?;
+            cmd.arg("--ffmpeg-location").arg(ffmpeg);
+            for s in &self.clip.sections {
+                cmd.arg("--download-sections").arg(s.arg());
+            }
+            if let Some(filter) = self.clip.match_filter() {
+                cmd.arg("--match-filter").arg(filter);
+            }
+            if self.clip.force_keyframes {
+                cmd.arg("--force-keyframes-at-cuts");
+            }
+        }
+        self.apply_format(cfg, cmd)?;
+
+        let e = &self.embed;
+        if let Some(langs) = &e.subs {
+            let langs = if langs.is_empty() {
+                "all,-live_chat".to_string()
+            } else {
+                langs.join(",")
+            };
+            cmd.arg("--write-subs")
+                .arg("--sub-langs")
+                .arg(langs)
+                .arg("--embed-subs");
+        }
+        if e.chapters {
+            cmd.arg("--embed-chapters");
+        }
+        if e.thumbnail {
+            cmd.arg("--embed-thumbnail");
+        }
+        if e.metadata {
+            cmd.arg("--embed-metadata");
+        }
+        Ok(())
+    }
+
+    fn apply_format(&self, cfg: &AppConfig, cmd: &mut Command) -> Result<(), String> {
+        if let Some(a) = &self.audio {
+            // m4a at source quality is a plain download; every other combination is a conversion by ffmpeg.
+            if a.codec == AudioCodec::M4a && a.bitrate.is_none() && find_ffmpeg(cfg).is_none() {
+                cmd.arg("-f").arg("bestaudio[ext=m4a]");
+                return Ok(());
+            }
+            let ffmpeg = find_ffmpeg(cfg).ok_or_else(|| {
+                format!(
+                    "ffmpeg is required for audio_format={}. Install ffmpeg or set ffmpeg_bin in config.toml",
+                    a.codec.as_str()
+                )
+            })?;
+            cmd.arg("--ffmpeg-location").arg(ffmpeg);
+            cmd.arg("-f")
+                .arg(a.codec.preferred_source())
+                .arg("-x")
+                .arg("--audio-format")
+                .arg(a.codec.as_str());
+            if let Some(b) = a.bitrate {
+                cmd.arg("--audio-quality").arg(format!("{}K", b));
+            }
+            return Ok(());
+        }
+
+        let v = &self.video;
+        if v.is_default() {
+            if self.mode == "best" {
+                let ffmpeg = find_ffmpeg(cfg).ok_or_else(|| {
+                    "ffmpeg is required for mode=best. Install ffmpeg or set ffmpeg_bin in config.toml".to_string()
+                })?;
+                cmd.arg("--ffmpeg-location").arg(ffmpeg);
+                cmd.arg("-f")
+                    .arg("bestvideo[ext=mp4]+bestaudio[ext=m4a]/best[ext=mp4]/best")
+                    .arg("--merge-output-format")
+                    .arg("mp4");
+            } else {
+                cmd.arg("-f").arg("best[ext=mp4]/best");
+            }
+            return Ok(());
+        }
+
+        let merges = match &v.format {
+            Some(f) => f.contains('+'),
+            None => self.mode == "best",
+        };
+        let ffmpeg = find_ffmpeg(cfg);
+        if merges && ffmpeg.is_none() {
+            return Err(
+                "ffmpeg is required to merge video and audio (mode=best or a format with '+'). Install ffmpeg or set ffmpeg_bin in config.toml"
+                    .to_string(),
+            );
+        }
+        if let Some(ffmpeg) = &ffmpeg {
+            cmd.arg("--ffmpeg-location").arg(ffmpeg);
+        }
+
+        let container = v.container.as_deref().unwrap_or("mp4");
+        let selection = match &v.format {
+            Some(f) => f.clone(),
+            None => {
+                let f = v.filters();
+                match (self.mode.as_str(), container) {
+                    ("best", "mp4") => format!("bestvideo{f}[ext=mp4]+bestaudio[ext=m4a]/best{f}[ext=mp4]/best{f}"),
+                    ("best", "webm") => format!("bestvideo{f}[ext=webm]+bestaudio[ext=webm]/bestvideo{f}+bestaudio/best{f}"),
+                    ("best", _) => format!("bestvideo{f}+bestaudio/best{f}"),
+                    (_, c) => format!("best{f}[ext={c}]/best{f}"),
+                }
+            }
+        };
+        cmd.arg("-f").arg(selection);
+
+        let mut sort = Vec::new();
+        if let Some(c) = v.vcodec {
+            sort.push(format!("vcodec:{}", c));
+        }
+        if let Some(c) = v.acodec {
+            sort.push(format!("acodec:{}", c));
+        }
+        if !sort.is_empty() {
+            cmd.arg("-S").arg(sort.join(","));
+        }
+
+        if merges {
+            cmd.arg("--merge-output-format").arg(container);
+        } else if v.container.is_some() && ffmpeg.is_some() {
+            // A single-file format in another container is remuxed (no re-encode) so the client gets what it asked
+            // for; without ffmpeg the response reports the container it actually got.
+            cmd.arg("--remux-video").arg(container);
+        }
+        Ok(())
+    }
+}
+
+/// Name of the file (inside the job directory) yt-dlp writes the chosen format to, see `FORMAT_TEMPLATE`.
+pub const FORMAT_FILE: &str = "format.txt";
+macro_rules! format_fields {
+    () => {
+        "%(format_id)s\t%(format)s\t%(ext)s\t%(vcodec)s\t%(acodec)s\t%(height)s\t%(fps)s"
+    };
+}
+/// `--print-to-file` template describing the format yt-dlp ended up downloading.
+pub const FORMAT_TEMPLATE: &str = concat!("after_move:", format_fields!());
+/// The same line printed before the download starts, for passthrough runs that never move a file into place.
+pub const FORMAT_TEMPLATE_BEFORE_DL: &str = concat!("before_dl:", format_fields!());
+
+// yt-dlp prints "NA" for unknown fields and "none" for a missing video/audio stream.
+fn known(v: Option<&str>) -> Option<&str> {
+    v.map(str::trim).filter(|v| !v.is_empty() && *v != "NA" && *v != "none")
+}
+
+/// The format yt-dlp actually downloaded, reported in the `X-Format*` response headers and the job status.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChosenFormat {
+    pub format_id: String,
+    pub format: String,
+    pub ext: Option<String>,
+    pub vcodec: Option<String>,
+    pub acodec: Option<String>,
+    pub height: Option<u32>,
+    pub fps: Option<f64>,
+}
+
+impl ChosenFormat {
+    /// Read the line written through `FORMAT_TEMPLATE` into `dir`, if yt-dlp got that far.
+    pub fn read(dir: &Path) -> Option<Self> {
+        let raw = std::fs::read_to_string(dir.join(FORMAT_FILE)).ok()?;
+        let line = raw.lines().find(|l| !l.trim().is_empty())?;
+        let mut parts = line.split('\t');
+
+        Some(Self {
+            format_id: known(parts.next())?.to_string(),
+            format: known(parts.next()).unwrap_or_default().to_string(),
+            ext: known(parts.next()).map(str::to_string),
+            vcodec: known(parts.next()).map(str::to_string),
+            acodec: known(parts.next()).map(str::to_string),
+            height: known(parts.next()).and_then(|v| v.parse().ok()),
+            fps: known(parts.next()).and_then(|v| v.parse().ok()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(t: &str) -> TimeValue {
+        TimeValue::Text(t.to_string())
+    }
+
+    fn clip(body: serde_json::Value) -> Result<ClipSpec, String> {
+        serde_json::from_value::<DownloadOptions>(body).unwrap().validate_clip()
+    }
+
+    #[test]
+    fn parses_time_values() {
+        let cases = [
+            (TimeValue::Seconds(90.0), Some(90.0)),
+            (TimeValue::Seconds(12.5), Some(12.5)),
+            (TimeValue::Seconds(-1.0), None),
+            (TimeValue::Seconds(f64::INFINITY), None),
+            (text("90"), Some(90.0)),
+            (text(" 1:30 "), Some(90.0)),
+            (text("01:02:03.5"), Some(3723.5)),
+            (text("1:00:00"), Some(3600.0)),
+            (text("75:00"), Some(4500.0)),
+            (text("0:59.25"), Some(59.25)),
+            (text("1:60"), None),
+            (text("1:60:00"), None),
+            (text("1:2:3:4"), None),
+            (text("-5"), None),
+            (text("1:-5"), None),
+            (text("a:30"), None),
+            (text(""), None),
+            (text("1:30:"), None),
+        ];
+        for (value, want) in cases {
+            assert_eq!(value.seconds(), want, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn formats_seconds_without_trailing_zeros() {
+        let cases = [
+            (0.0, "0"),
+            (1.0, "1"),
+            (90.0, "90"),
+            (1.5, "1.5"),
+            (1.25, "1.25"),
+            (1.0001, "1"),
+            (1.9999, "2"),
+            (3723.125, "3723.125"),
+        ];
+        for (secs, want) in cases {
+            assert_eq!(fmt_secs(secs), want, "{}", secs);
+        }
+    }
+
+    #[test]
+    fn validates_clip_ranges() {
+        let errors = [
+            (serde_json::json!({"start": 40, "end": 40}), "end must be after start"),
+            (serde_json::json!({"start": 40, "end": "0:10"}), "end must be after start"),
+            (serde_json::json!({"start": -1}), "Invalid start"),
+            (serde_json::json!({"end": "-0:10"}), "Invalid end"),
+            (serde_json::json!({"start": "1:75"}), "Invalid start"),
+            (serde_json::json!({"start": 1, "sections": [{"start": 2}]}), "not both"),
+            (serde_json::json!({"sections": []}), "1-20 entries"),
+            (serde_json::json!({"sections": [{"start": 0, "end": 30}, {"start": 20, "end": 50}]}), "overlap"),
+            (serde_json::json!({"sections": [{"start": 60}, {"start": 0, "end": 90}]}), "overlap"),
+            (serde_json::json!({"force_keyframes": true}), "requires start/end"),
+        ];
+        for (body, want) in errors {
+            let err = clip(body.clone()).unwrap_err();
+            assert!(err.contains(want), "{}: {}", body, err);
+        }
+
+        let spec = clip(serde_json::json!({"sections": [{"start": "1:35"}, {"end": 40.5}]})).unwrap();
+        assert_eq!(
+            spec.sections,
+            [Section { start: 0.0, end: Some(40.5) }, Section { start: 95.0, end: None }]
+        );
+        assert!(clip(serde_json::json!({})).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sections_render_labels_arguments_and_duration_filter() {
+        let spec = clip(serde_json::json!({"start": 10, "end": "0:40"})).unwrap();
+        assert_eq!(spec.label().as_deref(), Some("10-40"));
+        assert_eq!(spec.sections[0].arg(), "*10-40");
+        assert_eq!(spec.match_filter().as_deref(), Some("duration >? 10"));
+
+        let spec = clip(serde_json::json!({"sections": [{"start": 95.25}, {"start": 1.0001, "end": 40.5}]})).unwrap();
+        assert_eq!(spec.label().as_deref(), Some("1-40.5_95.25-end"));
+        let args: Vec<_> = spec.sections.iter().map(Section::arg).collect();
+        assert_eq!(args, ["*1-40.5", "*95.25-inf"]);
+        assert_eq!(spec.match_filter().as_deref(), Some("duration >? 95.25"));
+
+        // A clip from the beginning can't start after the end of the video.
+        let spec = clip(serde_json::json!({"end": 30})).unwrap();
+        assert_eq!(spec.label().as_deref(), Some("0-30"));
+        assert_eq!(spec.match_filter(), None);
+        assert_eq!(ClipSpec::default().label(), None);
+    }
+
+    fn format_args(options: serde_json::Value) -> (String, Option<String>) {
+        let cfg = AppConfig::from_toml("ffmpeg_bin = \"/usr/bin/ffmpeg\"").unwrap();
+        let spec = serde_json::from_value::<DownloadOptions>(options).unwrap().validate().unwrap();
+        let mut cmd = Command::new("yt-dlp");
+        spec.apply_format(&cfg, &mut cmd).unwrap();
+        let args: Vec<String> = cmd.as_std().get_args().map(|a| a.to_string_lossy().to_string()).collect();
+        let value = |flag: &str| args.iter().position(|a| a == flag).map(|i| args[i + 1].clone());
+        (value("-f").unwrap(), value("-S"))
+    }
+
+    #[test]
+    fn format_options_generate_selection_and_sort() {
+        let cases = [
+            (serde_json::json!({}), "best[ext=mp4]/best", None),
+            (serde_json::json!({"mode": "best"}), "bestvideo[ext=mp4]+bestaudio[ext=m4a]/best[ext=mp4]/best", None),
+            (
+                serde_json::json!({"mode": "best", "max_height": 720}),
+                "bestvideo[height<=720][ext=mp4]+bestaudio[ext=m4a]/best[height<=720][ext=mp4]/best[height<=720]",
+                None,
+            ),
+            (
+                serde_json::json!({"mode": "best", "max_height": 720, "max_fps": 30, "vcodec": "h264"}),
+                "bestvideo[height<=720][fps<=30][ext=mp4]+bestaudio[ext=m4a]\
+                 /best[height<=720][fps<=30][ext=mp4]/best[height<=720][fps<=30]",
+                Some("vcodec:h264"),
+            ),
+            // Codecs are only sort preferences; the container still decides the selection.
+            (
+                serde_json::json!({"mode": "best", "vcodec": "vp9"}),
+                "bestvideo[ext=mp4]+bestaudio[ext=m4a]/best[ext=mp4]/best",
+                Some("vcodec:vp9"),
+            ),
+            (
+                serde_json::json!({"mode": "best", "vcodec": "vp9", "container": "webm"}),
+                "bestvideo[ext=webm]+bestaudio[ext=webm]/bestvideo+bestaudio/best",
+                Some("vcodec:vp9"),
+            ),
+            (
+                serde_json::json!({"mode": "best", "container": "mkv", "acodec": "aac"}),
+                "bestvideo+bestaudio/best",
+                Some("acodec:aac"),
+            ),
+            (serde_json::json!({"max_height": 480}), "best[height<=480][ext=mp4]/best[height<=480]", None),
+            (
+                serde_json::json!({"format": "bestvideo[height<=360]+bestaudio[ext=m4a]"}),
+                "bestvideo[height<=360]+bestaudio[ext=m4a]",
+                None,
+            ),
+            (
+                serde_json::json!({"mode": "audio", "audio_format": "opus"}),
+                "bestaudio[acodec=opus]/bestaudio/best",
+                None,
+            ),
+        ];
+        for (options, selection, sort) in cases {
+            let (got_selection, got_sort) = format_args(options.clone());
+            assert_eq!(got_selection, selection, "{}", options);
+            assert_eq!(got_sort.as_deref(), sort, "{}", options);
+        }
+    }
+
+    #[test]
+    fn codec_preferences_become_sort_keys() {
+        let cases = [
+            (serde_json::json!({"mode": "best"}), None),
+            (serde_json::json!({"vcodec": "AVC"}), Some("vcodec:h264")),
+            (serde_json::json!({"vcodec": "av1", "acodec": "mp4a"}), Some("vcodec:av01,acodec:aac")),
+            (serde_json::json!({"mode": "best", "acodec": "opus"}), Some("acodec:opus")),
+        ];
+        for (options, want) in cases {
+            assert_eq!(format_args(options.clone()).1.as_deref(), want, "{}", options);
+        }
+    }
+
+    #[test]
+    fn reads_the_chosen_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let lines = [
+            "298\t298 - 1280x720 (720p60)\tmp4\tavc1.4d4020\tnone\t720\t60\n",
+            "137+140\t137 - 1920x1080 (1080p)+140 - audio only (medium)\tmp4\tavc1.640028\tmp4a.40.2\t1080\t30.0\n",
+            "140\t140 - audio only (medium)\tm4a\tnone\tmp4a.40.2\tNA\tNA\n",
+        ];
+        let expected = [
+            ("298", Some("avc1.4d4020"), None, Some(720), Some(60.0)),
+            ("137+140", Some("avc1.640028"), Some("mp4a.40.2"), Some(1080), Some(30.0)),
+            ("140", None, Some("mp4a.40.2"), None, None),
+        ];
+        for (line, (id, vcodec, acodec, height, fps)) in lines.iter().zip(expected) {
+            std::fs::write(dir.path().join(FORMAT_FILE), line).unwrap();
+            let f = ChosenFormat::read(dir.path()).unwrap();
+            assert_eq!(f.format_id, id);
+            assert_eq!((f.vcodec.as_deref(), f.acodec.as_deref()), (vcodec, acodec), "{}", line);
+            assert_eq!((f.height, f.fps), (height, fps), "{}", line);
+        }
+        std::fs::write(dir.path().join(FORMAT_FILE), "NA\tNA\tNA\tNA\tNA\tNA\tNA\n").unwrap();
+        assert!(ChosenFormat::read(dir.path()).is_none());
+    }
+}
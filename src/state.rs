@@ -1,11 +1,65 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::sync::{Mutex as AsyncMutex, Semaphore};
+use tokio::sync::Mutex as AsyncMutex;
 
+use crate::cache::ContentCache;
+use crate::coalesce::InFlight;
 use crate::config::AppConfig;
+use crate::cookiepool::CookiePool;
+use crate::handlers::{InfoResult, ThumbnailResult};
+use crate::jobs::JobRegistry;
+use crate::limiter::{GlobalCap, Limiter};
+use crate::progress::ProgressRegistry;
+use crate::ratelimit::RateLimiter;
 
 pub struct AppState {
-    pub limiter: Arc<Semaphore>,
+    // Concurrency pools: downloads (/download, /audio, /jobs, /batch), metadata lookups (/info, /subtitles,
+    // /playlist) and thumbnails, plus the optional cap shared by all three.
+    pub limiter: Arc<Limiter>,
+    pub info_limiter: Arc<Limiter>,
+    pub thumbnail_limiter: Arc<Limiter>,
+    pub global_cap: Option<Arc<GlobalCap>>,
     pub cookie_lock: Arc<AsyncMutex<()>>,
+    // Cookie profiles yt-dlp runs rotate through, and which of them are benched.
+    pub cookie_pool: Arc<CookiePool>,
     pub config: Arc<AppConfig>,
+    pub jobs: Arc<JobRegistry>,
+    pub progress: Arc<ProgressRegistry>,
+    pub cache: Option<Arc<ContentCache>>,
+    // In-flight /info and /thumbnail lookups, keyed by url, so identical concurrent requests share one run.
+    pub info_inflight: Arc<InFlight<InfoResult>>,
+    pub thumbnail_inflight: Arc<InFlight<ThumbnailResult>>,
+    // Per-client rate limits and quotas, when [rate_limit] is configured.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl AppState {
+    pub fn new(cfg: AppConfig, cache: Option<Arc<ContentCache>>) -> Self {
+        let global_cap = cfg.global_max_weight.map(|max| Arc::new(GlobalCap::new(max)));
+        let pool = |what: &'static str, max: usize, weight: u32| {
+            Arc::new(Limiter::new(
+                what,
+                max,
+                global_cap.clone().map(|g| (g, weight)),
+                cfg.queue_max_len,
+                Duration::from_secs(cfg.queue_max_wait_secs),
+            ))
+        };
+        Self {
+            limiter: pool("downloads", cfg.max_concurrent_downloads, cfg.download_weight),
+            info_limiter: pool("info requests", cfg.max_concurrent_info, cfg.info_weight),
+            thumbnail_limiter: pool("thumbnail requests", cfg.max_concurrent_thumbnails, cfg.thumbnail_weight),
+            global_cap: global_cap.clone(),
+            cookie_lock: Arc::new(AsyncMutex::new(())),
+            cookie_pool: Arc::new(CookiePool::new(&cfg)),
+            rate_limiter: cfg.rate_limit.as_ref().map(|rl| Arc::new(RateLimiter::new(rl, &cfg.api_keys))),
+            config: Arc::new(cfg),
+            jobs: Arc::new(JobRegistry::default()),
+            progress: Arc::new(ProgressRegistry::default()),
+            cache,
+            info_inflight: Arc::new(InFlight::default()),
+            thumbnail_inflight: Arc::new(InFlight::default()),
+        }
+    }
 }
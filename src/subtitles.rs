@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+
+use crate::config::AppConfig;
+use crate::ytdlp::{build_ytdlp_base_command, find_ffmpeg};
+
+/// Base name of subtitle files in the temp dir; yt-dlp appends `.<lang>.<ext>`.
+const OUTPUT_STEM: &str = "subs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubtitleFormat {
+    Vtt,
+    Srt,
+    Json3,
+}
+
+impl SubtitleFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "vtt" => Some(Self::Vtt),
+            "srt" => Some(Self::Srt),
+            "json3" => Some(Self::Json3),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Vtt => "vtt",
+            Self::Srt => "srt",
+            Self::Json3 => "json3",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Vtt => "text/vtt; charset=utf-8",
+            Self::Srt => "application/x-subrip; charset=utf-8",
+            Self::Json3 => "application/json",
+        }
+    }
+}
+
+/// Which tracks to consider: manual subtitles, auto-generated captions, or manual with auto as the fallback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+    Manual,
+    Auto,
+    Any,
+}
+
+/// Languages are passed to `--sub-langs`, which takes regexes; only accept plain language codes like `en`,
+/// `pt-BR` or `zh-Hans`.
+pub fn valid_lang(lang: &str) -> bool {
+    !lang.is_empty()
+        && lang.len() <= 32
+        && lang.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// yt-dlp command writing the `lang` subtitles of `url` into `out_dir` without downloading the video.
+/// srt is converted from whatever the site offers and needs ffmpeg; vtt and json3 are fetched as-is.
+pub fn build_command(
+    cfg: &AppConfig,
+    url: &str,
+    lang: &str,
+    format: SubtitleFormat,
+    kind: TrackKind,
+    out_dir: &Path,
+) -> Result<Command, String> {
+    let mut cmd = build_ytdlp_base_command(cfg);
+    cmd.arg("--skip-download")
+        .arg("-o")
+        .arg(out_dir.join(format!("{}.%(ext)s", OUTPUT_STEM)));
+
+    // With both flags yt-dlp prefers the manual track of a language over its automatic captions.
+    if kind != TrackKind::Auto {
+        cmd.arg("--write-subs");
+    }
+    if kind != TrackKind::Manual {
+        cmd.arg("--write-auto-subs");
+    }
+    cmd.arg("--sub-langs").arg(lang);
+
+    match format {
+        SubtitleFormat::Srt => {
+            let ffmpeg = find_ffmpeg(cfg).ok_or_else(|| {
+                "ffmpeg is required for format=srt. Install ffmpeg or set ffmpeg_bin in config.toml".to_string()
+            })?;
+            cmd.arg("--ffmpeg-location").arg(ffmpeg);
+            cmd.arg("--sub-format")
+                .arg("srt/vtt/best")
+                .arg("--convert-subs")
+                .arg("srt");
+        }
+        f => {
+            cmd.arg("--sub-format").arg(f.as_str());
+        }
+    }
+
+    cmd.arg(url)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    Ok(cmd)
+}
+
+/// The subtitle file yt-dlp wrote into `dir`, if the requested track existed in the requested format.
+pub fn find_file(dir: &Path, format: SubtitleFormat) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| {
+            let name = p.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            name.starts_with(OUTPUT_STEM)
+                && p.extension().and_then(|e| e.to_str()) == Some(format.as_str())
+        })
+}
+
+// `{"en": [{"ext": "vtt", "name": "English", ...}, ...], ...}` -> `[{"lang", "name", "formats"}]`, sorted by lang.
+fn tracks(map: Option<&serde_json::Value>) -> Vec<serde_json::Value> {
+    let map = match map.and_then(|m| m.as_object()) {
+        Some(m) => m,
+        None => return Vec::new(),
+    };
+    let mut out: Vec<(String, serde_json::Value)> = map
+        .iter()
+        .map(|(lang, entries)| {
+            let entries = entries.as_array().map(|a| a.as_slice()).unwrap_or(&[]);
+            let name = entries
+                .iter()
+                .find_map(|e| e.get("name").and_then(|n| n.as_str()))
+                .map(|s| s.to_string());
+            let formats: Vec<&str> = entries
+                .iter()
+                .filter_map(|e| e.get("ext").and_then(|x| x.as_str()))
+                .collect();
+            (
+                lang.clone(),
+                serde_json::json!({ "lang": lang, "name": name, "formats": formats }),
+            )
+        })
+        .collect();
+    out.sort_by(|a, b| a.0.cmp(&b.0));
+    out.into_iter().map(|(_, v)| v).collect()
+}
+
+/// Available subtitle languages, taken from the `/info` (yt-dlp `-J`) JSON.
+pub fn list(info: &serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "id": info.get("id"),
+        "title": info.get("title"),
+        "subtitles": tracks(info.get("subtitles")),
+        "automatic_captions": tracks(info.get("automatic_captions")),
+    })
+}
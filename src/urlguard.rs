@@ -0,0 +1,283 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use actix_web::HttpResponse;
+use url::{Host, Url};
+
+use crate::config::AppConfig;
+
+/// Why a URL was refused. `code` is a stable machine-readable identifier returned alongside the message.
+#[derive(Debug, Clone)]
+pub struct UrlRejection {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl UrlRejection {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::BadRequest().json(serde_json::json!({
+            "error": self.message,
+            "code": self.code
+        }))
+    }
+}
+
+fn forbidden_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // 100.64.0.0/10 (carrier-grade NAT), 198.18.0.0/15 (benchmarking), 240.0.0.0/4 (reserved).
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (b == 18 || b == 19))
+        || a >= 240
+}
+
+fn forbidden_v6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return forbidden_v4(v4);
+    }
+    let s = ip.segments();
+    // 64:ff9b::/96 (NAT64) embeds an IPv4 address.
+    if s[0] == 0x64 && s[1] == 0xff9b && s[2..6] == [0, 0, 0, 0] {
+        return forbidden_v4(Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8));
+    }
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 (unique local), fe80::/10 (link-local), fec0::/10 (deprecated site-local), 2001:db8::/32.
+        || (s[0] & 0xfe00) == 0xfc00
+        || (s[0] & 0xffc0) == 0xfe80
+        || (s[0] & 0xffc0) == 0xfec0
+        || (s[0] == 0x2001 && s[1] == 0x0db8)
+}
+
+/// Loopback, private, link-local and other addresses that aren't on the public internet.
+pub fn is_forbidden_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => forbidden_v4(v4),
+        IpAddr::V6(v6) => forbidden_v6(v6),
+    }
+}
+
+fn domain_allowed(host: &str, allowed: &[String]) -> bool {
+    allowed.iter().any(|d| {
+        let d = d.trim_start_matches('.');
+        host == d || host.strip_suffix(d).is_some_and(|rest| rest.ends_with('.'))
+    })
+}
+
+/// Check a URL before handing it to yt-dlp: http(s) only, host in `allowed_domains` (when configured), and —
+/// unless `allow_private_addresses` — not pointing at a private, loopback or link-local address, either literally
+/// or through DNS. A name that doesn't resolve locally is refused too, since what it points at can't be checked.
+/// This is best effort: yt-dlp resolves the host again and follows redirects, neither of which is checked here.
+pub async fn check(cfg: &AppConfig, raw: &str) -> Result<(), UrlRejection> {
+    let url = Url::parse(raw.trim()).map_err(|e| UrlRejection::new("invalid_url", format!("Invalid url: {}", e)))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(UrlRejection::new(
+            "unsupported_scheme",
+            format!("Unsupported url scheme: {} (expected http or https)", url.scheme()),
+        ));
+    }
+    let host = url
+        .host()
+        .ok_or_else(|| UrlRejection::new("invalid_url", "Invalid url: missing host"))?;
+
+    let domain = match &host {
+        Host::Domain(d) => Some(d.trim_end_matches('.').to_ascii_lowercase()),
+        _ => None,
+    };
+    if !cfg.allowed_domains.is_empty() {
+        let allowed = domain.as_deref().is_some_and(|d| domain_allowed(d, &cfg.allowed_domains));
+        if !allowed {
+            return Err(UrlRejection::new(
+                "domain_not_allowed",
+                format!("Domain not allowed: {}", url.host_str().unwrap_or("")),
+            ));
+        }
+    }
+
+    if cfg.allow_private_addresses {
+        return Ok(());
+    }
+    let private = |ip: IpAddr| {
+        UrlRejection::new(
+            "private_address",
+            format!("Url points to a private or local address: {}", ip),
+        )
+    };
+    match host {
+        Host::Ipv4(ip) if forbidden_v4(ip) => Err(private(IpAddr::V4(ip))),
+        Host::Ipv6(ip) if forbidden_v6(ip) => Err(private(IpAddr::V6(ip))),
+        Host::Ipv4(_) | Host::Ipv6(_) => Ok(()),
+        Host::Domain(_) => {
+            let domain = domain.unwrap_or_default();
+            if domain == "localhost" || domain.ends_with(".localhost") {
+                return Err(UrlRejection::new(
+                    "private_address",
+                    format!("Url points to a private or local address: {}", domain),
+                ));
+            }
+            let port = url.port_or_known_default().unwrap_or(443);
+            let unresolved = || {
+                UrlRejection::new(
+                    "unresolved_host",
+                    format!("Url host does not resolve: {}", domain),
+                )
+            };
+            let addrs: Vec<_> = tokio::net::lookup_host((domain.as_str(), port))
+                .await
+                .map_err(|_| unresolved())?
+                .collect();
+            if addrs.is_empty() {
+                return Err(unresolved());
+            }
+            match addrs.iter().find(|addr| is_forbidden_ip(addr.ip())) {
+                Some(addr) => Err(private(addr.ip())),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_ranges_are_forbidden() {
+        let cases = [
+            ("10.0.0.1", true),
+            ("10.255.255.255", true),
+            ("127.0.0.1", true),
+            ("127.8.9.10", true),
+            ("169.254.169.254", true),
+            ("100.64.0.1", true),
+            ("100.127.255.254", true),
+            ("100.128.0.1", false),
+            ("100.63.255.255", false),
+            ("172.16.0.1", true),
+            ("192.168.1.1", true),
+            ("0.0.0.0", true),
+            ("8.8.8.8", false),
+            ("11.0.0.1", false),
+            ("::1", true),
+            ("::", true),
+            ("fc00::1", true),
+            ("fd12:3456::1", true),
+            ("fe80::1", true),
+            ("febf::1", true),
+            ("fec0::1", true),
+            ("::ffff:127.0.0.1", true),
+            ("::ffff:10.1.2.3", true),
+            ("::ffff:8.8.8.8", false),
+            ("64:ff9b::a9fe:a9fe", true),
+            ("2001:db8::1", true),
+            ("2001:4860:4860::8888", false),
+            ("fb00::1", false),
+        ];
+        for (ip, forbidden) in cases {
+            assert_eq!(is_forbidden_ip(ip.parse().unwrap()), forbidden, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn allowed_domains_match_whole_labels() {
+        let allowed = ["youtube.com".to_string(), ".youtu.be".to_string()];
+        let cases = [
+            ("youtube.com", true),
+            ("www.youtube.com", true),
+            ("m.www.youtube.com", true),
+            ("youtu.be", true),
+            ("evilyoutube.com", false),
+            ("youtube.com.evil.com", false),
+            ("notyoutu.be", false),
+            ("com", false),
+        ];
+        for (host, ok) in cases {
+            assert_eq!(domain_allowed(host, &allowed), ok, "{}", host);
+        }
+    }
+
+    fn code(cfg: &AppConfig, url: &str) -> Option<&'static str> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(check(cfg, url)).err().map(|r| r.code)
+    }
+
+    #[test]
+    fn rejects_local_and_unresolvable_hosts() {
+        let cfg = AppConfig::from_toml("allowed_domains = [\"youtube.com\", \"invalid\"]").unwrap();
+        let cases = [
+            ("ftp://youtube.com/x", Some("unsupported_scheme")),
+            ("not a url", Some("invalid_url")),
+            ("https://evilyoutube.com/watch?v=x", Some("domain_not_allowed")),
+            ("http://127.0.0.1/", Some("domain_not_allowed")),
+            ("https://nothing-here.invalid/", Some("unresolved_host")),
+        ];
+        for (url, want) in cases {
+            assert_eq!(code(&cfg, url), want, "{}", url);
+        }
+
+        let cfg = AppConfig::from_toml("").unwrap();
+        let cases = [
+            ("http://127.0.0.1:8080/", Some("private_address")),
+            ("http://[::1]/", Some("private_address")),
+            ("http://[::ffff:169.254.169.254]/", Some("private_address")),
+            ("http://localhost/", Some("private_address")),
+            ("http://a.localhost./", Some("private_address")),
+            ("https://nothing-here.invalid/", Some("unresolved_host")),
+            ("https://8.8.8.8/", None),
+        ];
+        for (url, want) in cases {
+            assert_eq!(code(&cfg, url), want, "{}", url);
+        }
+
+        let cfg = AppConfig::from_toml("allow_private_addresses = true").unwrap();
+        for url in ["http://127.0.0.1/", "http://localhost/", "https://nothing-here.invalid/"] {
+            assert_eq!(code(&cfg, url), None, "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn redirect_targets_are_not_checked() {
+        // The guard never requests the url, so it can't see where it redirects: a server answering with a redirect
+        // to the metadata service passes (loopback is allowed here only so the test can reach it) without a hit.
+        let hits = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = hits.clone();
+        let server = actix_web::HttpServer::new(move || {
+            let counter = counter.clone();
+            actix_web::App::new().default_service(actix_web::web::to(move || {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async {
+                    HttpResponse::Found()
+                        .append_header(("Location", "http://169.254.169.254/latest/meta-data/"))
+                        .finish()
+                }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let port = server.addrs()[0].port();
+        tokio::spawn(server.run());
+
+        let cfg = AppConfig::from_toml("allow_private_addresses = true").unwrap();
+        assert!(check(&cfg, &format!("http://127.0.0.1:{}/watch?v=x", port)).await.is_ok());
+        assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        // Likewise a public host whose redirect target is spelled out in the url.
+        let cfg = AppConfig::from_toml("").unwrap();
+        assert!(check(&cfg, "https://8.8.8.8/redirect?to=http://169.254.169.254/").await.is_ok());
+    }
+}
@@ -5,24 +5,239 @@ pub fn sanitize_filename_component(s: &str) -> String {
         .collect()
 }
 
-pub fn video_id_from_url(url: &str) -> Option<String> {
-    // Very small heuristic; avoids adding a URL parser dependency.
-    if let Some(idx) = url.find("v=") {
-        let rest = &url[idx + 2..];
-        let id = rest.split('&').next().unwrap_or(rest);
-        let id = sanitize_filename_component(id);
-        if !id.is_empty() {
-            return Some(id);
+/// A URL as understood by the service: a canonical form (so equivalent URLs share jobs and cache entries) plus
+/// the extractor that handles it and the media id, when known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaUrl {
+    pub canonical: String,
+    // "youtube" (a video), "youtube:playlist", or "generic" for everything else.
+    pub extractor: &'static str,
+    pub id: Option<String>,
+}
+
+const YOUTUBE_HOSTS: [&str; 5] = [
+    "youtube.com",
+    "m.youtube.com",
+    "music.youtube.com",
+    "youtube-nocookie.com",
+    "youtu.be",
+];
+
+fn valid_youtube_id(id: &str) -> bool {
+    id.len() == 11 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn valid_playlist_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// The video id of a YouTube URL: `youtu.be/ID`, `/watch?v=ID`, or `/shorts|live|embed|v|e/ID`.
+fn youtube_video_id(url: &url::Url, host: &str) -> Option<String> {
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+    let first = segments.next();
+    let id = if host == "youtu.be" {
+        first.map(|s| s.to_string())
+    } else {
+        match first {
+            Some("watch") => url.query_pairs().find(|(k, _)| k == "v").map(|(_, v)| v.into_owned()),
+            Some("shorts") | Some("live") | Some("embed") | Some("v") | Some("e") => {
+                segments.next().map(|s| s.to_string())
+            }
+            _ => None,
         }
+    };
+    id.filter(|id| valid_youtube_id(id))
+}
+
+/// Parse and normalize a URL. Returns None when it isn't an absolute http(s) URL.
+pub fn parse_media_url(raw: &str) -> Option<MediaUrl> {
+    let mut url = url::Url::parse(raw.trim()).ok()?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return None;
     }
-    if let Some(idx) = url.find("youtu.be/") {
-        let rest = &url[idx + "youtu.be/".len()..];
-        let id = rest.split('?').next().unwrap_or(rest);
-        let id = sanitize_filename_component(id);
-        if !id.is_empty() {
-            return Some(id);
+    url.set_fragment(None);
+    let host = url.host_str()?.trim_end_matches('.').to_ascii_lowercase();
+    let bare = host.strip_prefix("www.").unwrap_or(&host);
+
+    if YOUTUBE_HOSTS.contains(&bare) {
+        if let Some(id) = youtube_video_id(&url, bare) {
+            return Some(MediaUrl {
+                canonical: format!("https://www.youtube.com/watch?v={}", id),
+                extractor: "youtube",
+                id: Some(id),
+            });
+        }
+        if url.path() == "/playlist" {
+            let list = url.query_pairs().find(|(k, _)| k == "list").map(|(_, v)| v.into_owned());
+            if let Some(list) = list.filter(|l| valid_playlist_id(l)) {
+                return Some(MediaUrl {
+                    canonical: format!("https://www.youtube.com/playlist?list={}", list),
+                    extractor: "youtube:playlist",
+                    id: Some(list),
+                });
+            }
         }
     }
-    None
+
+    Some(MediaUrl {
+        canonical: url.to_string(),
+        extractor: "generic",
+        id: None,
+    })
+}
+
+/// The canonical form of `url`, or `url` itself (trimmed) when it can't be parsed.
+pub fn canonical_url(url: &str) -> String {
+    parse_media_url(url)
+        .map(|m| m.canonical)
+        .unwrap_or_else(|| url.trim().to_string())
 }
 
+/// Media id of a URL, safe to use in file names.
+pub fn video_id_from_url(url: &str) -> Option<String> {
+    parse_media_url(url)?.id.map(|id| sanitize_filename_component(&id))
+}
+
+/// Content-Type for a downloaded media file, based on its extension.
+pub fn content_type_for(path: &std::path::Path) -> &'static str {
+    content_type_for_ext(path.extension().and_then(|e| e.to_str()).unwrap_or_default())
+}
+
+/// Content-Type for a media file extension (without the dot).
+pub fn content_type_for_ext(ext: &str) -> &'static str {
+    match ext.to_ascii_lowercase().as_str() {
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "m4a" => "audio/mp4",
+        "mp3" => "audio/mpeg",
+        "opus" | "ogg" => "audio/ogg",
+        "flac" => "audio/flac",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_youtube_video_urls() {
+        let cases = [
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://youtube.com/watch?v=dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("http://www.youtube.com/watch?v=dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://m.youtube.com/watch?v=dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://music.youtube.com/watch?v=dQw4w9WgXcQ&feature=share", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/watch?feature=share&v=dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123&index=2", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=42s", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ#t=42", "dQw4w9WgXcQ"),
+            ("https://WWW.YouTube.COM/watch?v=dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com./watch?v=dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("  https://www.youtube.com/watch?v=dQw4w9WgXcQ  ", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/shorts/dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://youtube.com/shorts/dQw4w9WgXcQ?feature=share", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/live/dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/live/dQw4w9WgXcQ?si=abc", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/embed/dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ?start=10", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/v/dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/e/dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://youtu.be/dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://youtu.be/dQw4w9WgXcQ?t=10", "dQw4w9WgXcQ"),
+            ("https://www.youtu.be/dQw4w9WgXcQ", "dQw4w9WgXcQ"),
+            ("https://www.youtube.com/watch?v=a-b_c-d_e-f", "a-b_c-d_e-f"),
+        ];
+        for (input, id) in cases {
+            let parsed = parse_media_url(input).unwrap_or_else(|| panic!("failed to parse {}", input));
+            assert_eq!(parsed.extractor, "youtube", "{}", input);
+            assert_eq!(parsed.id.as_deref(), Some(id), "{}", input);
+            assert_eq!(parsed.canonical, format!("https://www.youtube.com/watch?v={}", id), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parses_youtube_playlists() {
+        let cases = [
+            ("https://www.youtube.com/playlist?list=PLabc_123-x", "PLabc_123-x"),
+            ("https://m.youtube.com/playlist?list=PLabc&si=xyz", "PLabc"),
+            ("https://music.youtube.com/playlist?list=OLAK5uy_abc", "OLAK5uy_abc"),
+        ];
+        for (input, id) in cases {
+            let parsed = parse_media_url(input).unwrap();
+            assert_eq!(parsed.extractor, "youtube:playlist", "{}", input);
+            assert_eq!(parsed.id.as_deref(), Some(id), "{}", input);
+            assert_eq!(parsed.canonical, format!("https://www.youtube.com/playlist?list={}", id), "{}", input);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_generic() {
+        let cases = [
+            // Not a video id: wrong length or characters, or a page that isn't a single video.
+            ("https://www.youtube.com/watch?v=short", "https://www.youtube.com/watch?v=short"),
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ1", "https://www.youtube.com/watch?v=dQw4w9WgXcQ1"),
+            ("https://www.youtube.com/@channel/videos", "https://www.youtube.com/@channel/videos"),
+            ("https://www.youtube.com/shorts/", "https://www.youtube.com/shorts/"),
+            ("https://youtu.be/", "https://youtu.be/"),
+            ("https://www.youtube.com/playlist", "https://www.youtube.com/playlist"),
+            // Look-alike hosts are other sites.
+            ("https://notyoutube.com/watch?v=dQw4w9WgXcQ", "https://notyoutube.com/watch?v=dQw4w9WgXcQ"),
+            ("https://youtube.com.evil.test/watch?v=dQw4w9WgXcQ", "https://youtube.com.evil.test/watch?v=dQw4w9WgXcQ"),
+            // A `v=` elsewhere in the URL doesn't make it a YouTube video.
+            ("https://example.com/page?nav=dQw4w9WgXcQ", "https://example.com/page?nav=dQw4w9WgXcQ"),
+            ("https://example.com/watch?v=dQw4w9WgXcQ", "https://example.com/watch?v=dQw4w9WgXcQ"),
+            // Normalized: lowercase host, default port and fragment dropped.
+            ("HTTPS://Example.COM:443/a/b?x=1#frag", "https://example.com/a/b?x=1"),
+            ("http://example.com", "http://example.com/"),
+        ];
+        for (input, canonical) in cases {
+            let parsed = parse_media_url(input).unwrap_or_else(|| panic!("failed to parse {}", input));
+            assert_eq!(parsed.extractor, "generic", "{}", input);
+            assert_eq!(parsed.id, None, "{}", input);
+            assert_eq!(parsed.canonical, canonical, "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_non_http_urls() {
+        for input in ["", "dQw4w9WgXcQ", "youtube.com/watch?v=dQw4w9WgXcQ", "ftp://youtube.com/x", "file:///etc/passwd"] {
+            assert_eq!(parse_media_url(input), None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn canonical_url_keeps_unparsable_input() {
+        assert_eq!(canonical_url("https://youtu.be/dQw4w9WgXcQ"), "https://www.youtube.com/watch?v=dQw4w9WgXcQ");
+        assert_eq!(canonical_url(" not a url "), "not a url");
+    }
+
+    #[test]
+    fn video_id_is_filename_safe() {
+        assert_eq!(video_id_from_url("https://youtu.be/dQw4w9WgXcQ").as_deref(), Some("dQw4w9WgXcQ"));
+        assert_eq!(video_id_from_url("https://example.com/watch?v=dQw4w9WgXcQ"), None);
+        assert_eq!(
+            video_id_from_url("https://www.youtube.com/playlist?list=PL_a-b").as_deref(),
+            Some("PL_a-b")
+        );
+    }
+
+    #[test]
+    fn content_type_follows_extension() {
+        let cases = [
+            ("mp4", "video/mp4"),
+            ("WEBM", "video/webm"),
+            ("m4a", "audio/mp4"),
+            ("opus", "audio/ogg"),
+            ("", "application/octet-stream"),
+            ("txt", "application/octet-stream"),
+        ];
+        for (ext, want) in cases {
+            assert_eq!(content_type_for_ext(ext), want, "{}", ext);
+        }
+        assert_eq!(content_type_for(std::path::Path::new("/tmp/media.webm")), "video/webm");
+        assert_eq!(content_type_for(std::path::Path::new("/tmp/media")), "application/octet-stream");
+    }
+}
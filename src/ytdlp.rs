@@ -0,0 +1,389 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+use crate::config::AppConfig;
+use crate::chapters;
+use crate::progress::{self, ProgressEvent, ProgressSink};
+use crate::spec::{self, DownloadSpec};
+
+pub async fn collect_stderr(
+    stderr: tokio::process::ChildStderr,
+    buf: std::sync::Arc<tokio::sync::Mutex<VecDeque<String>>>,
+    progress: Option<ProgressSink>,
+) {
+    let mut r = BufReader::new(stderr);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match r.read_line(&mut line).await {
+            Ok(0) => break,
+            Ok(_) => {
+                let l = line.trim_end().to_string();
+                // Progress lines end up on stderr when stdout carries the media itself.
+                if let Some(ev) = progress::parse_line(&l) {
+                    if let Some(p) = &progress {
+                        p.send_replace(ev);
+                    }
+                    continue;
+                }
+                if !l.is_empty() {
+                    eprintln!("{}", l);
+                    let mut g = buf.lock().await;
+                    if g.len() >= 50 {
+                        g.pop_front();
+                    }
+                    g.push_back(l);
+                }
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+/// Forward progress lines printed on yt-dlp's stdout; everything else there is informational noise.
+pub async fn collect_progress(stdout: tokio::process::ChildStdout, progress: ProgressSink) {
+    let mut r = BufReader::new(stdout);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match r.read_line(&mut line).await {
+            Ok(0) => break,
+            Ok(_) => {
+                if let Some(ev) = progress::parse_line(&line) {
+                    progress.send_replace(ev);
+                }
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+pub async fn render_tail(buf: &tokio::sync::Mutex<VecDeque<String>>) -> String {
+    let g = buf.lock().await;
+    if g.is_empty() {
+        return "no stderr output captured".to_string();
+    }
+    g.iter().cloned().collect::<Vec<_>>().join("\n")
+}
+
+pub fn find_ffmpeg(cfg: &AppConfig) -> Option<String> {
+    if let Some(p) = &cfg.ffmpeg_bin {
+        return Some(p.to_string_lossy().to_string());
+    }
+    // Common macOS/Homebrew locations.
+    for p in ["/opt/homebrew/bin/ffmpeg", "/usr/local/bin/ffmpeg", "/usr/bin/ffmpeg"] {
+        if std::path::Path::new(p).exists() {
+            return Some(p.to_string());
+        }
+    }
+    None
+}
+
+/// Single-video yt-dlp command with the service-wide options (proxy, progress output). Cookies depend on the
+/// profile picked for each run and are added just before it starts (`cookiepool::Rotation::apply`).
+pub fn build_ytdlp_base_command(cfg: &AppConfig) -> Command {
+    let mut cmd = build_ytdlp_common_command(cfg);
+    cmd.arg("--no-playlist");
+    cmd
+}
+
+/// Like `build_ytdlp_base_command`, but playlist/channel URLs are expanded instead of reduced to one video.
+pub fn build_ytdlp_playlist_command(cfg: &AppConfig) -> Command {
+    let mut cmd = build_ytdlp_common_command(cfg);
+    cmd.arg("--yes-playlist");
+    cmd
+}
+
+fn build_ytdlp_common_command(cfg: &AppConfig) -> Command {
+    let mut cmd = Command::new(&cfg.ytdlp_bin);
+    cmd.env("PATH", &cfg.ytdlp_path);
+
+    if !cfg.inherit_proxy_env {
+        // Avoid being accidentally bound to a dead local proxy (common in shell env).
+        cmd.env_remove("http_proxy")
+            .env_remove("https_proxy")
+            .env_remove("HTTP_PROXY")
+            .env_remove("HTTPS_PROXY")
+            .env_remove("no_proxy")
+            .env_remove("NO_PROXY");
+    }
+
+    if let Some(p) = &cfg.ytdlp_proxy {
+        cmd.arg("--proxy").arg(p);
+    }
+
+    if !cfg.allowed_extractors.is_empty() {
+        cmd.arg("--use-extractors").arg(cfg.allowed_extractors.join(","));
+    }
+
+    cmd.arg("--js-runtimes")
+        .arg("node")
+        .arg("--no-cache-dir")
+        .arg("--no-part")
+        .arg("--newline")
+        .arg("--progress-template")
+        .arg(progress::DOWNLOAD_TEMPLATE)
+        .arg("--progress-template")
+        .arg(progress::POSTPROCESS_TEMPLATE);
+
+    cmd
+}
+
+/// Base name of the file yt-dlp writes into a job's directory; the extension depends on the chosen format.
+pub const OUTPUT_STEM: &str = "media";
+/// Base name of the per-section files of a multi-section clip, joined into `OUTPUT_STEM` afterwards.
+const SECTION_STEM: &str = "section";
+
+pub fn build_ytdlp_command(
+    cfg: &AppConfig,
+    spec: &DownloadSpec,
+    url: &str,
+    out_dir: &Path,
+) -> Result<Command, String> {
+    let mut cmd = build_ytdlp_base_command(cfg);
+    // yt-dlp writes every section of a clip to its own file.
+    let template = if spec.clip.sections.len() > 1 {
+        format!("{}.%(section_start)s.%(ext)s", SECTION_STEM)
+    } else {
+        format!("{}.%(ext)s", OUTPUT_STEM)
+    };
+    cmd.arg("-o").arg(out_dir.join(template));
+    if spec.split_chapters {
+        chapters::apply_args(&mut cmd, out_dir);
+    }
+    cmd.arg("--print-to-file")
+        .arg(spec::FORMAT_TEMPLATE)
+        .arg(out_dir.join(spec::FORMAT_FILE));
+    spec.apply(cfg, &mut cmd)?;
+
+    cmd.arg(url)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+
+    Ok(cmd)
+}
+
+/// Like `build_ytdlp_command` for specs that `supports_passthrough`, but yt-dlp writes the media to stdout (`-o -`)
+/// so it can be forwarded to the client while it downloads. Log and progress lines move to stderr in this mode.
+/// The chosen format is written to `format_dir` before the first byte of media, see `ChosenFormat::read`.
+pub fn build_ytdlp_stream_command(
+    cfg: &AppConfig,
+    spec: &DownloadSpec,
+    url: &str,
+    format_dir: &Path,
+) -> Result<Command, String> {
+    let mut cmd = build_ytdlp_base_command(cfg);
+    cmd.arg("-o").arg("-");
+    cmd.arg("--print-to-file")
+        .arg(spec::FORMAT_TEMPLATE_BEFORE_DL)
+        .arg(format_dir.join(spec::FORMAT_FILE));
+    spec.apply(cfg, &mut cmd)?;
+    cmd.arg(url)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    Ok(cmd)
+}
+
+/// Why a yt-dlp run did not produce a usable output file.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub error: String,
+    pub stderr_tail: Option<String>,
+}
+
+/// The file yt-dlp produced in `dir` (named `OUTPUT_STEM.<ext>`). Intermediate files are normally deleted by
+/// yt-dlp; if any are left over, the largest file wins.
+pub fn find_output(dir: &Path) -> Option<PathBuf> {
+    let prefix = format!("{}.", OUTPUT_STEM);
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with(&prefix))
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            meta.is_file().then(|| (e.path(), meta.len()))
+        })
+        .max_by_key(|(_, size)| *size)
+        .map(|(path, _)| path)
+}
+
+/// Join the per-section files of a multi-section clip (in time order) into one `OUTPUT_STEM` file with ffmpeg's
+/// concat demuxer, without re-encoding. Does nothing when there are no section files.
+async fn join_sections(cfg: &AppConfig, dir: &Path) -> Result<(), String> {
+    let prefix = format!("{}.", SECTION_STEM);
+    let mut parts: Vec<(f64, PathBuf)> = std::fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read output dir: {}", e))?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            let rest = name.strip_prefix(&prefix)?;
+            // "<section_start>.<ext>"; section_start may itself contain a dot.
+            let (start, _ext) = rest.rsplit_once('.')?;
+            Some((start.parse().ok()?, e.path()))
+        })
+        .collect();
+    if parts.is_empty() {
+        return Ok(());
+    }
+    parts.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let ext = parts[0].1.extension().and_then(|e| e.to_str()).unwrap_or("mp4").to_string();
+    let out = dir.join(format!("{}.{}", OUTPUT_STEM, ext));
+    if parts.len() == 1 {
+        return tokio::fs::rename(&parts[0].1, &out)
+            .await
+            .map_err(|e| format!("Failed to move section file: {}", e));
+    }
+
+    let ffmpeg = find_ffmpeg(cfg).ok_or_else(|| "ffmpeg is required to join sections".to_string())?;
+    let list: String = parts
+        .iter()
+        .map(|(_, p)| format!("file '{}'\n", p.to_string_lossy().replace('\'', "'\\''")))
+        .collect();
+    let list_path = dir.join("sections.txt");
+    tokio::fs::write(&list_path, list)
+        .await
+        .map_err(|e| format!("Failed to write section list: {}", e))?;
+
+    let out_status = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-f", "concat", "-safe", "0", "-i"])
+        .arg(&list_path)
+        .args(["-c", "copy"])
+        .arg(&out)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to start ffmpeg: {}", e))?;
+    if !out_status.status.success() {
+        return Err(format!(
+            "ffmpeg failed to join sections (status={}): {}",
+            out_status.status,
+            String::from_utf8_lossy(&out_status.stderr).trim()
+        ));
+    }
+
+    for (_, p) in &parts {
+        let _ = tokio::fs::remove_file(p).await;
+    }
+    let _ = tokio::fs::remove_file(&list_path).await;
+    Ok(())
+}
+
+/// Spawn `cmd`, wait for it to exit and check that it left a non-empty output file in `out_dir` (joining the
+/// sections of a multi-section clip, or zipping the chapters for `split_chapters`, first). Returns the produced
+/// file and its size. Progress is reported to `progress` when given.
+pub async fn run_to_file(
+    cfg: &AppConfig,
+    spec: &DownloadSpec,
+    mut cmd: Command,
+    out_dir: &Path,
+    progress: Option<ProgressSink>,
+) -> Result<(PathBuf, u64), Failure> {
+    if progress.is_some() {
+        cmd.stdout(std::process::Stdio::piped());
+    }
+    let mut child = cmd.spawn().map_err(|e| Failure {
+        error: format!("Failed to start yt-dlp: {}", e),
+        stderr_tail: None,
+    })?;
+    if let Some(pid) = child.id() {
+        eprintln!("[YTDLP] started (pid={})", pid);
+    }
+
+    let tail_buf: std::sync::Arc<tokio::sync::Mutex<VecDeque<String>>> =
+        std::sync::Arc::new(tokio::sync::Mutex::new(VecDeque::new()));
+    let stderr = child.stderr.take().ok_or_else(|| Failure {
+        error: "Failed to capture yt-dlp stderr".to_string(),
+        stderr_tail: None,
+    })?;
+    let tail_buf_clone = tail_buf.clone();
+    let progress_clone = progress.clone();
+    let stderr_task = tokio::spawn(async move { collect_stderr(stderr, tail_buf_clone, progress_clone).await });
+    let stdout_task = match (child.stdout.take(), progress.clone()) {
+        (Some(stdout), Some(p)) => Some(tokio::spawn(async move { collect_progress(stdout, p).await })),
+        _ => None,
+    };
+
+    let status = match child.wait().await {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(Failure {
+                error: format!("Failed waiting for yt-dlp: {}", e),
+                stderr_tail: Some(render_tail(&tail_buf).await),
+            });
+        }
+    };
+    let _ = stderr_task.await;
+    if let Some(t) = stdout_task {
+        let _ = t.await;
+    }
+
+    if !status.success() {
+        return Err(Failure {
+            error: format!("yt-dlp exited with error (status={})", status),
+            stderr_tail: Some(render_tail(&tail_buf).await),
+        });
+    }
+
+    let finished = if spec.clip.sections.len() > 1 {
+        join_sections(cfg, out_dir).await
+    } else if spec.split_chapters {
+        if let Some(p) = &progress {
+            p.send_replace(ProgressEvent {
+                status: "postprocessing".to_string(),
+                postprocessor: Some("ZipChapters".to_string()),
+                ..Default::default()
+            });
+        }
+        chapters::package(out_dir).await
+    } else {
+        Ok(())
+    };
+    if let Err(e) = finished {
+        return Err(Failure {
+            error: e,
+            stderr_tail: Some(render_tail(&tail_buf).await),
+        });
+    }
+
+    let out_path = match find_output(out_dir) {
+        Some(p) => p,
+        None => {
+            let tail = render_tail(&tail_buf).await;
+            // Skipped by the clip's `--match-filter`, see `ClipSpec::match_filter`.
+            let error = if spec.clip.match_filter().is_some() && tail.contains("does not pass filter") {
+                "Invalid time range (starts after the end of the video)"
+            } else {
+                "Download succeeded but output file missing"
+            };
+            return Err(Failure {
+                error: error.to_string(),
+                stderr_tail: Some(tail),
+            });
+        }
+    };
+    let meta = match tokio::fs::metadata(&out_path).await {
+        Ok(m) => m,
+        Err(e) => {
+            return Err(Failure {
+                error: format!("Download succeeded but output file missing: {}", e),
+                stderr_tail: Some(render_tail(&tail_buf).await),
+            });
+        }
+    };
+    if meta.len() == 0 {
+        return Err(Failure {
+            error: "Download succeeded but output file is empty".to_string(),
+            stderr_tail: Some(render_tail(&tail_buf).await),
+        });
+    }
+
+    Ok((out_path, meta.len()))
+}
@@ -0,0 +1,337 @@
+use std::io::SeekFrom;
+use std::path::Path;
+
+use chrono::{Datelike, Timelike};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+// Minimal ZIP writer for bundling downloads: entries are stored (media doesn't compress), written sequentially to
+// any AsyncWrite (a file or a response body pipe), with ZIP64 records where sizes or offsets need them.
+
+const LOCAL_HEADER_SIG: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIG: u32 = 0x02014b50;
+const END_SIG: u32 = 0x06054b50;
+const ZIP64_END_SIG: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x07064b50;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+// General purpose flag bit 11: file names are UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+// Upper byte 3 = Unix, so the external attributes below are read as permissions.
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+const UNIX_FILE_MODE: u32 = 0o100644;
+
+const U32_MAX: u64 = 0xFFFF_FFFF;
+
+/// Why `add_open_file` failed. After a `Source` error the archive is still consistent and more entries can be
+/// added: the entry is left out of the central directory, so whatever was copied of it is never referenced.
+#[derive(Debug)]
+pub enum AddError {
+    /// Reading the file failed.
+    Source(std::io::Error),
+    /// Writing the archive failed.
+    Output(std::io::Error),
+}
+
+impl From<AddError> for std::io::Error {
+    fn from(e: AddError) -> Self {
+        match e {
+            AddError::Source(e) | AddError::Output(e) => e,
+        }
+    }
+}
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+pub struct ZipWriter<W: AsyncWrite + Unpin> {
+    out: W,
+    offset: u64,
+    entries: Vec<Entry>,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+fn dos_datetime() -> (u16, u16) {
+    let now = chrono::Local::now();
+    let time = ((now.hour() as u16) << 11) | ((now.minute() as u16) << 5) | ((now.second() as u16) / 2);
+    let year = (now.year().clamp(1980, 2107) - 1980) as u16;
+    let date = (year << 9) | ((now.month() as u16) << 5) | now.day() as u16;
+    (time, date)
+}
+
+impl<W: AsyncWrite + Unpin> ZipWriter<W> {
+    pub fn new(out: W) -> Self {
+        let (dos_time, dos_date) = dos_datetime();
+        Self {
+            out,
+            offset: 0,
+            entries: Vec::new(),
+            dos_time,
+            dos_date,
+        }
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.out.write_all(buf).await?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    async fn write_local_header(&mut self, name: &str, crc: u32, size: u64) -> std::io::Result<()> {
+        let zip64 = size >= U32_MAX;
+        let mut h = Vec::with_capacity(30 + name.len() + 20);
+        h.extend_from_slice(&LOCAL_HEADER_SIG.to_le_bytes());
+        h.extend_from_slice(&(if zip64 { VERSION_ZIP64 } else { VERSION_DEFAULT }).to_le_bytes());
+        h.extend_from_slice(&FLAG_UTF8.to_le_bytes());
+        h.extend_from_slice(&0u16.to_le_bytes()); // stored
+        h.extend_from_slice(&self.dos_time.to_le_bytes());
+        h.extend_from_slice(&self.dos_date.to_le_bytes());
+        h.extend_from_slice(&crc.to_le_bytes());
+        let size32 = if zip64 { U32_MAX as u32 } else { size as u32 };
+        h.extend_from_slice(&size32.to_le_bytes()); // compressed
+        h.extend_from_slice(&size32.to_le_bytes()); // uncompressed
+        h.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        h.extend_from_slice(&(if zip64 { 20u16 } else { 0 }).to_le_bytes());
+        h.extend_from_slice(name.as_bytes());
+        if zip64 {
+            h.extend_from_slice(&ZIP64_EXTRA_ID.to_le_bytes());
+            h.extend_from_slice(&16u16.to_le_bytes());
+            h.extend_from_slice(&size.to_le_bytes());
+            h.extend_from_slice(&size.to_le_bytes());
+        }
+        self.write(&h).await
+    }
+
+    /// Add an in-memory entry (e.g. a manifest).
+    pub async fn add_bytes(&mut self, name: &str, data: &[u8]) -> std::io::Result<()> {
+        let offset = self.offset;
+        let crc = crc32fast::hash(data);
+        self.write_local_header(name, crc, data.len() as u64).await?;
+        self.write(data).await?;
+        self.entries.push(Entry {
+            name: name.to_string(),
+            crc,
+            size: data.len() as u64,
+            offset,
+        });
+        Ok(())
+    }
+
+    /// Add a file from disk.
+    pub async fn add_file(&mut self, name: &str, path: &Path) -> std::io::Result<()> {
+        let file = tokio::fs::File::open(path).await?;
+        Ok(self.add_open_file(name, file).await?)
+    }
+
+    /// Add an opened file (which stays readable even if its path is deleted meanwhile). The file is read twice
+    /// (CRC first, then the data), so the header is complete up front and readers don't need data descriptors.
+    pub async fn add_open_file(&mut self, name: &str, mut file: tokio::fs::File) -> Result<(), AddError> {
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size = 0u64;
+        loop {
+            let n = file.read(&mut buffer).await.map_err(AddError::Source)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            size += n as u64;
+        }
+        let crc = hasher.finalize();
+        file.seek(SeekFrom::Start(0)).await.map_err(AddError::Source)?;
+
+        let offset = self.offset;
+        self.write_local_header(name, crc, size).await.map_err(AddError::Output)?;
+        let mut written = 0u64;
+        while written < size {
+            let want = (size - written).min(buffer.len() as u64) as usize;
+            let n = file.read(&mut buffer[..want]).await.map_err(AddError::Source)?;
+            if n == 0 {
+                return Err(AddError::Source(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("{} shrank while being archived", name),
+                )));
+            }
+            self.write(&buffer[..n]).await.map_err(AddError::Output)?;
+            written += n as u64;
+        }
+
+        self.entries.push(Entry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset,
+        });
+        Ok(())
+    }
+
+    /// Write the central directory and return the underlying writer (flushed).
+    pub async fn finish(mut self) -> std::io::Result<W> {
+        let cd_offset = self.offset;
+        let entries = std::mem::take(&mut self.entries);
+        for e in &entries {
+            let mut extra = Vec::new();
+            if e.size >= U32_MAX {
+                extra.extend_from_slice(&e.size.to_le_bytes());
+                extra.extend_from_slice(&e.size.to_le_bytes());
+            }
+            if e.offset >= U32_MAX {
+                extra.extend_from_slice(&e.offset.to_le_bytes());
+            }
+            let zip64 = !extra.is_empty();
+
+            let mut h = Vec::with_capacity(46 + e.name.len() + 28);
+            h.extend_from_slice(&CENTRAL_HEADER_SIG.to_le_bytes());
+            h.extend_from_slice(&VERSION_MADE_BY.to_le_bytes());
+            h.extend_from_slice(&(if zip64 { VERSION_ZIP64 } else { VERSION_DEFAULT }).to_le_bytes());
+            h.extend_from_slice(&FLAG_UTF8.to_le_bytes());
+            h.extend_from_slice(&0u16.to_le_bytes());
+            h.extend_from_slice(&self.dos_time.to_le_bytes());
+            h.extend_from_slice(&self.dos_date.to_le_bytes());
+            h.extend_from_slice(&e.crc.to_le_bytes());
+            let size32 = e.size.min(U32_MAX) as u32;
+            h.extend_from_slice(&size32.to_le_bytes());
+            h.extend_from_slice(&size32.to_le_bytes());
+            h.extend_from_slice(&(e.name.len() as u16).to_le_bytes());
+            h.extend_from_slice(&(if zip64 { extra.len() as u16 + 4 } else { 0 }).to_le_bytes());
+            h.extend_from_slice(&0u16.to_le_bytes()); // comment
+            h.extend_from_slice(&0u16.to_le_bytes()); // disk
+            h.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+            h.extend_from_slice(&(UNIX_FILE_MODE << 16).to_le_bytes());
+            h.extend_from_slice(&(e.offset.min(U32_MAX) as u32).to_le_bytes());
+            h.extend_from_slice(e.name.as_bytes());
+            if zip64 {
+                h.extend_from_slice(&ZIP64_EXTRA_ID.to_le_bytes());
+                h.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+                h.extend_from_slice(&extra);
+            }
+            self.write(&h).await?;
+        }
+        let cd_size = self.offset - cd_offset;
+        let count = entries.len() as u64;
+
+        let mut end = Vec::new();
+        if count >= 0xFFFF || cd_offset >= U32_MAX || cd_size >= U32_MAX {
+            let zip64_end_offset = self.offset;
+            end.extend_from_slice(&ZIP64_END_SIG.to_le_bytes());
+            end.extend_from_slice(&44u64.to_le_bytes());
+            end.extend_from_slice(&VERSION_MADE_BY.to_le_bytes());
+            end.extend_from_slice(&VERSION_ZIP64.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&count.to_le_bytes());
+            end.extend_from_slice(&count.to_le_bytes());
+            end.extend_from_slice(&cd_size.to_le_bytes());
+            end.extend_from_slice(&cd_offset.to_le_bytes());
+
+            end.extend_from_slice(&ZIP64_LOCATOR_SIG.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&zip64_end_offset.to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes());
+        }
+        end.extend_from_slice(&END_SIG.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        end.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+        end.extend_from_slice(&(count.min(0xFFFF) as u16).to_le_bytes());
+        end.extend_from_slice(&(cd_size.min(U32_MAX) as u32).to_le_bytes());
+        end.extend_from_slice(&(cd_offset.min(U32_MAX) as u32).to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.write(&end).await?;
+
+        self.out.flush().await?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::*;
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn archives_read_back_with_the_zip_crate() {
+        let dir = tempfile::tempdir().unwrap();
+        // Larger than the copy buffer, so add_file reads it in several chunks.
+        let media: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        std::fs::write(dir.path().join("media.mp4"), &media).unwrap();
+        std::fs::write(dir.path().join("empty"), b"").unwrap();
+        let manifest = br#"{"items": []}"#;
+
+        let mut zip = ZipWriter::new(Vec::new());
+        zip.add_file("001 - Intro.mp4", &dir.path().join("media.mp4")).await.unwrap();
+        zip.add_file("empty.txt", &dir.path().join("empty")).await.unwrap();
+        zip.add_bytes("002 - Ça marche 日本語.mp4", b"short").await.unwrap();
+        zip.add_bytes("manifest.json", manifest).await.unwrap();
+        let out = zip.finish().await.unwrap();
+
+        let expected: [(&str, &[u8]); 4] = [
+            ("001 - Intro.mp4", &media),
+            ("empty.txt", b""),
+            ("002 - Ça marche 日本語.mp4", b"short"),
+            ("manifest.json", manifest),
+        ];
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(out.clone())).unwrap();
+        assert_eq!(archive.len(), expected.len());
+        let mut next_header = 0;
+        for (i, (name, data)) in expected.iter().enumerate() {
+            let mut entry = archive.by_index(i).unwrap();
+            assert_eq!(entry.name(), *name);
+            assert_eq!(entry.size(), data.len() as u64);
+            assert_eq!(entry.compressed_size(), data.len() as u64);
+            assert_eq!(entry.crc32(), crc32fast::hash(data));
+            assert_eq!(entry.unix_mode(), Some(UNIX_FILE_MODE));
+            // Entries are laid out back to back: 30-byte local header, name, data.
+            assert_eq!(entry.header_start(), next_header);
+            assert_eq!(entry.data_start(), next_header + 30 + name.len() as u64);
+            next_header = entry.data_start() + data.len() as u64;
+            let mut read = Vec::new();
+            entry.read_to_end(&mut read).unwrap();
+            assert_eq!(read, *data, "{}", name);
+        }
+
+        // The end of central directory record points right behind the last entry and spans up to itself.
+        let eocd = out.len() - 22;
+        assert_eq!(u32_at(&out, eocd), END_SIG);
+        assert_eq!(u16::from_le_bytes([out[eocd + 10], out[eocd + 11]]), 4);
+        let cd_size = u32_at(&out, eocd + 12) as u64;
+        let cd_offset = u32_at(&out, eocd + 16) as u64;
+        assert_eq!(cd_offset, next_header);
+        assert_eq!(cd_offset + cd_size, eocd as u64);
+        assert_eq!(u32_at(&out, cd_offset as usize), CENTRAL_HEADER_SIG);
+    }
+
+    #[tokio::test]
+    async fn unreadable_files_are_left_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("media.mp4");
+        std::fs::write(&path, b"data").unwrap();
+        // Opened for writing only: every read fails.
+        let unreadable = tokio::fs::OpenOptions::new().write(true).open(&path).await.unwrap();
+
+        let mut zip = ZipWriter::new(Vec::new());
+        zip.add_bytes("first.txt", b"first").await.unwrap();
+        assert!(matches!(zip.add_open_file("broken.mp4", unreadable).await, Err(AddError::Source(_))));
+        zip.add_open_file("media.mp4", tokio::fs::File::open(&path).await.unwrap()).await.unwrap();
+        let out = zip.finish().await.unwrap();
+
+        let mut archive = ::zip::ZipArchive::new(Cursor::new(out)).unwrap();
+        let names: Vec<_> = archive.file_names().collect();
+        assert_eq!(names, ["first.txt", "media.mp4"]);
+        let mut data = String::new();
+        archive.by_name("media.mp4").unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "data");
+    }
+}